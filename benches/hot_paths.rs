@@ -0,0 +1,109 @@
+//! Benchmarks of the parse, diff and content hash hot paths over synthetic incidents of representative sizes:
+//!
+//! ```bash
+//! cargo bench --bench hot_paths
+//! ```
+//!
+//! Measured with criterion, which compares every run to the previous one in `target/criterion`. Hashes of
+//! raw snapshots are computed by Postgres (`md5(content::text)`) and therefore not covered here.
+
+#[allow(dead_code)]
+#[path = "../src/model.rs"]
+mod model;
+
+// Its tests aren't collected without the test harness
+#[allow(dead_code, unused_imports)]
+#[path = "../src/integrity.rs"]
+mod integrity;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use std::collections::HashSet;
+use std::hint::black_box;
+
+/// Incident list sizes to benchmark, the portal currently lists a few thousand incidents
+const SIZES: &[usize] = &[1_000, 10_000, 50_000];
+
+/// Lengths of `details_text` of the signed rows, most are short but some incidents carry long reports
+const TEXT_BYTES: &[usize] = &[500, 64 * 1024];
+
+fn incident_list(size: usize) -> String {
+    let incidents: Vec<_> = (1..=size)
+        .map(|id| serde_json::json!({
+            "incidentID": id,
+            "orgPublishDate": format!("20{:02}-{:02}-{:02}", 18 + id % 8, 1 + id % 12, 1 + id % 28),
+            "modifiedDate": format!("2024-{:02}-{:02} {:02}:{:02}:00", 1 + id % 12, 1 + id % 28, id % 24, id % 60),
+            "published": 1,
+            "country": if id % 3 == 0 { "AT" } else { "DE" },
+            "incidentText": format!("Unbefugter Zugriff auf Kundendaten bei Vorfall {}, betroffen sind Namen, Anschriften und E-Mail-Adressen.", id),
+        }))
+        .collect();
+    serde_json::Value::Array(incidents).to_string()
+}
+
+/// Row of `incidents` as read via `to_jsonb` for its HMAC
+fn stored_row(details_bytes: usize) -> serde_json::Value {
+    serde_json::json!({
+        "incident_id": 4711,
+        "org_publish_date": "2024-03-04",
+        "modified_date": "2024-03-05T09:12:44+00:00",
+        "published": 1,
+        "publish_date": "2024-03-05",
+        "affected_obj": "Beispiel Shop GmbH",
+        "affected_type": "Unternehmen",
+        "country": "DE",
+        "details_text": "Kundendaten betroffen. ".repeat(details_bytes / 23 + 1)[..details_bytes],
+        "tags": "Hacking, Zugangsdaten",
+        "href": "https://www.example.org/meldung/4711",
+        "references": ["https://www.example.org/meldung/4711"],
+        "incident_text": "Unbefugter Zugriff auf Kundendaten eines Onlineshops durch kompromittierte Zugangsdaten.",
+        "incident_text_plain": null,
+        "details_text_plain": null,
+        "incident_text_original_bytes": null,
+        "details_text_original_bytes": null,
+    })
+}
+
+fn parse_and_diff(c: &mut Criterion) {
+    let mut parse = c.benchmark_group("parse incident list");
+    parse.sample_size(20);
+    for &size in SIZES {
+        let body = incident_list(size);
+        parse.throughput(Throughput::Elements(size as u64));
+        parse.bench_with_input(BenchmarkId::from_parameter(size), &body, |b, body| {
+            b.iter(|| serde_json::from_str::<Vec<model::Incident>>(black_box(body)).expect("Failed to parse incident list"))
+        });
+    }
+    parse.finish();
+
+    let mut select = c.benchmark_group("select new incidents");
+    for &size in SIZES {
+        let body = incident_list(size);
+        // Typical steady state: all but the newest few incidents are stored already
+        let existing_ids: HashSet<i32> = (1..=(size - 10) as i32).collect();
+        select.throughput(Throughput::Elements(size as u64));
+        select.bench_with_input(BenchmarkId::from_parameter(size), &existing_ids, |b, existing_ids| {
+            b.iter_batched(
+                || serde_json::from_str::<Vec<model::Incident>>(&body).expect("Failed to parse incident list"),
+                |incidents| model::select_new_incidents(incidents, black_box(existing_ids)),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    select.finish();
+}
+
+fn content_hash(c: &mut Criterion) {
+    let row_hmac = integrity::RowHmac::new("benchmark-key".to_owned()).expect("Failed to create row HMAC");
+    let mut sign = c.benchmark_group("row hmac");
+    for &details_bytes in TEXT_BYTES {
+        let row = stored_row(details_bytes);
+        sign.throughput(Throughput::Bytes(row.to_string().len() as u64));
+        sign.bench_with_input(BenchmarkId::from_parameter(details_bytes), &row, |b, row| {
+            b.iter(|| row_hmac.sign(black_box(row)).expect("Failed to sign row"))
+        });
+    }
+    sign.finish();
+}
+
+criterion_group!(benches, parse_and_diff, content_hash);
+criterion_main!(benches);
@@ -0,0 +1,144 @@
+//! Serves canned portal responses from a fixtures directory, to exercise the full pipeline
+//! without the real portal:
+//!
+//! ```bash
+//! cargo run --example mock_portal -- --port 8080 --fixtures fixtures/portal
+//! dsgvo-downloader --base-url http://127.0.0.1:8080 ...
+//! ```
+//!
+//! The incident list is read from `<fixtures>/incidents.json`, the details of an incident from
+//! `<fixtures>/details/<id>.json`. Fixtures are re-read on every request so they can be edited
+//! while the server is running.
+
+use anyhow::{Context, Result};
+use clap::value_parser;
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+
+struct Response {
+    status: &'static str,
+    body: String,
+    etag: Option<String>,
+}
+
+impl Response {
+    fn status(status: &'static str) -> Self {
+        Self { status, body: String::new(), etag: None }
+    }
+}
+
+fn read_fixture(path: &Path) -> Response {
+    match std::fs::read_to_string(path) {
+        Ok(body) => {
+            let etag = format!("\"{}\"", &hex::encode(Sha256::digest(body.as_bytes()))[..16]);
+            Response { status: "200 OK", body, etag: Some(etag) }
+        }
+        Err(err) => {
+            eprintln!("Failed to read fixture {}: {}", path.display(), err);
+            Response::status("404 Not Found")
+        }
+    }
+}
+
+fn respond(fixtures: &Path, target: &str, if_none_match: Option<&str>) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let response = match path {
+        "/sicherheitsvorfall-datenbank/" if query == "cmd=getIncidents" => read_fixture(&fixtures.join("incidents.json")),
+        "/sicherheitsvorfall-datenbank/incidentDetails.php" => {
+            match query.strip_prefix("incident=").and_then(|id| id.parse::<i32>().ok()) {
+                Some(id) => read_fixture(&fixtures.join("details").join(format!("{}.json", id))),
+                None => Response::status("400 Bad Request"),
+            }
+        }
+        _ => Response::status("404 Not Found"),
+    };
+
+    match (&response.etag, if_none_match) {
+        (Some(etag), Some(if_none_match)) if etag == if_none_match => Response { etag: response.etag, ..Response::status("304 Not Modified") },
+        _ => response,
+    }
+}
+
+fn handle(stream: TcpStream, fixtures: &Path) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone().context("Failed to clone stream")?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).context("Failed to read request line")?;
+
+    let mut if_none_match = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).context("Failed to read header")? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("if-none-match") {
+                if_none_match = Some(value.trim().to_owned());
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => respond(fixtures, target, if_none_match.as_deref()),
+        _ => Response::status("405 Method Not Allowed"),
+    };
+    println!("{} -> {}", request_line.trim(), response.status);
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len(),
+    );
+    if let Some(etag) = &response.etag {
+        head.push_str(&format!("ETag: {}\r\n", etag));
+    }
+    let mut stream = stream;
+    stream.write_all(head.as_bytes()).context("Failed to write response")?;
+    stream.write_all(b"\r\n").context("Failed to write response")?;
+    stream.write_all(response.body.as_bytes()).context("Failed to write response")?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let matches = clap::builder::Command::new("mock_portal")
+        .about("Serves canned dsgvo-portal responses from a fixtures directory")
+        .arg(clap::Arg::new("port")
+            .long("port")
+            .default_value("8080")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(u16))
+            .help("Port to listen on")
+        )
+        .arg(clap::Arg::new("fixtures")
+            .long("fixtures")
+            .default_value("fixtures/portal")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(PathBuf))
+            .help("Directory with incidents.json and details/<id>.json")
+        )
+        .get_matches();
+
+    let port: u16 = *matches.get_one("port").context("missing required argument port")?;
+    let fixtures: &PathBuf = matches.get_one("fixtures").context("missing required argument fixtures")?;
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).with_context(|| format!("Failed to bind to port {}", port))?;
+    println!("Serving {} on http://127.0.0.1:{}", fixtures.display(), port);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        let fixtures = fixtures.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = handle(stream, &fixtures) {
+                eprintln!("{:#}", err);
+            }
+        });
+    }
+    Ok(())
+}
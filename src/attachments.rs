@@ -0,0 +1,222 @@
+//! Opt-in download of documents linked in the references of incident details, so the mirror
+//! contains the source documents and not only their URLs
+
+use crate::db::Database;
+use crate::http::{HttpClient, HttpRequest};
+use crate::PortalClient;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+use tracing::{debug, info, trace, warn};
+
+/// File extensions of links that are considered documents, other links are skipped
+const DOCUMENT_EXTENSIONS: &[&str] = &["pdf", "doc", "docx", "odt", "rtf", "txt"];
+
+/// Content types of documents that are stored, other responses are discarded
+const DOCUMENT_CONTENT_TYPES: &[&str] = &[
+    "application/pdf",
+    "application/msword",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "application/vnd.oasis.opendocument.text",
+    "application/rtf",
+    "text/plain",
+];
+
+pub struct AttachmentOptions {
+    /// Attachments larger than this are skipped
+    pub max_bytes: u64,
+    /// Store attachments as files named by their hash in this directory instead of as bytea
+    pub dir: Option<PathBuf>,
+    /// Disallowed path prefixes of `robots.txt` per origin
+    robots: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl AttachmentOptions {
+    pub fn new(max_bytes: u64, dir: Option<PathBuf>) -> Self {
+        Self { max_bytes, dir, robots: Mutex::new(HashMap::new()) }
+    }
+}
+
+/// Links in the references of an incident that look like documents
+pub fn document_links(references: &str) -> Vec<reqwest::Url> {
+    let links: Vec<String> = match crate::model::parse_references(references).and_then(serde_json::from_value) {
+        Ok(links) => links,
+        Err(err) => {
+            debug!("References aren't a list of links, skipping attachments: {}", err);
+            return Vec::new();
+        }
+    };
+    links
+        .iter()
+        .filter_map(|link| reqwest::Url::parse(link).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .filter(|url| {
+            let extension = url.path().rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+            extension.is_some_and(|extension| DOCUMENT_EXTENSIONS.contains(&extension.as_str()))
+        })
+        .collect()
+}
+
+/// Disallowed path prefixes for all user agents in a `robots.txt`
+fn parse_robots(content: &str) -> Vec<String> {
+    let mut disallowed = Vec::new();
+    let mut applies = false;
+    let mut in_agents = false;
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match field.trim().to_ascii_lowercase().as_str() {
+            "user-agent" => {
+                // Consecutive user-agent lines form one group
+                if !in_agents {
+                    applies = false;
+                }
+                in_agents = true;
+                applies |= value == "*";
+            }
+            "disallow" => {
+                in_agents = false;
+                if applies && !value.is_empty() {
+                    disallowed.push(value.to_owned());
+                }
+            }
+            _ => in_agents = false,
+        }
+    }
+    disallowed
+}
+
+/// Whether `robots.txt` of the link's origin allows fetching it, fetched once per origin
+async fn robots_allow<H: HttpClient>(client: &PortalClient<H>, options: &AttachmentOptions, url: &reqwest::Url) -> Result<bool> {
+    let origin = url.origin().ascii_serialization();
+    let mut robots = options.robots.lock().await;
+    if !robots.contains_key(&origin) {
+        let robots_url = format!("{}/robots.txt", origin);
+        client.pacer(&robots_url).wait().await;
+        trace!("Fetching {}", robots_url);
+        let response = client.get(HttpRequest::get(&robots_url)).await.with_context(|| format!("Failed to fetch {}", robots_url))?;
+        let status = response.status;
+        let disallowed = if status.is_success() {
+            parse_robots(&response.text().with_context(|| format!("Failed to read {}", robots_url))?)
+        } else if status.is_client_error() {
+            // No robots.txt, everything is allowed
+            Vec::new()
+        } else {
+            anyhow::bail!("Unexpected status code for {}: {}", robots_url, status);
+        };
+        debug!("{} disallows {:?}", robots_url, disallowed);
+        robots.insert(origin.clone(), disallowed);
+    }
+    Ok(!robots[&origin].iter().any(|prefix| url.path().starts_with(prefix.as_str())))
+}
+
+/// Download a document, `None` if it isn't a document or too large
+async fn download<H: HttpClient>(client: &PortalClient<H>, options: &AttachmentOptions, url: &reqwest::Url) -> Result<Option<(String, Vec<u8>)>> {
+    client.pacer(url.as_str()).wait().await;
+    let request = HttpRequest::get(url.as_str()).max_body_bytes(options.max_bytes);
+    let response = client.get(request).await.with_context(|| format!("Failed to fetch attachment {}", url))?;
+    if !response.status.is_success() {
+        anyhow::bail!("Unexpected status code: {}", response.status);
+    }
+
+    let content_type = response
+        .header(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.split(';').next().map(|value| value.trim().to_ascii_lowercase()))
+        .unwrap_or_default();
+    if !DOCUMENT_CONTENT_TYPES.contains(&content_type.as_str()) {
+        debug!("Skipping attachment {} with content type '{}'", url, content_type);
+        return Ok(None);
+    }
+    let Some(content) = response.body else {
+        debug!("Skipping attachment {} larger than {} bytes", url, options.max_bytes);
+        return Ok(None);
+    };
+    Ok(Some((content_type, content)))
+}
+
+/// A downloaded document to store in `incident_attachments`
+pub struct Attachment<'a> {
+    pub incident_id: i32,
+    pub url: &'a str,
+    pub content_type: &'a str,
+    pub size: i64,
+    pub sha256: &'a str,
+    /// The document, `None` if it is stored as a file at `path`
+    pub content: Option<Vec<u8>>,
+    pub path: Option<String>,
+}
+
+pub async fn is_stored(pool: &sqlx::PgPool, incident_id: i32, url: &str) -> Result<bool> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM incident_attachments WHERE incident_id = $1 AND url = $2)")
+        .bind(incident_id)
+        .bind(url)
+        .fetch_one(pool)
+        .await
+        .with_context(|| format!("Failed to check for attachment {}", url))
+}
+
+pub async fn insert(pool: &sqlx::PgPool, attachment: &Attachment<'_>) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO incident_attachments (incident_id, url, content_type, size, sha256, content, path)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (incident_id, url) DO NOTHING"#,
+    )
+        .bind(attachment.incident_id)
+        .bind(attachment.url)
+        .bind(attachment.content_type)
+        .bind(attachment.size)
+        .bind(attachment.sha256)
+        .bind(attachment.content.as_deref())
+        .bind(attachment.path.as_deref())
+        .execute(pool)
+        .await
+        .with_context(|| format!("Failed to store attachment {}", attachment.url))?;
+    Ok(())
+}
+
+async fn store<D: Database>(db: &D, options: &AttachmentOptions, incident_id: i32, url: &reqwest::Url, content_type: &str, content: Vec<u8>) -> Result<()> {
+    let sha256 = hex::encode(Sha256::digest(&content));
+    let size = i64::try_from(content.len()).context("Attachment is too large")?;
+    let (path, content) = match &options.dir {
+        Some(dir) => {
+            let path = dir.join(&sha256);
+            std::fs::write(&path, &content).with_context(|| format!("Failed to write attachment {}", path.display()))?;
+            (Some(path.display().to_string()), None)
+        }
+        None => (None, Some(content)),
+    };
+
+    db.store_attachment(&Attachment { incident_id, url: url.as_str(), content_type, size, sha256: &sha256, content, path }).await?;
+    info!(incident_id, "Stored attachment {} ({} bytes, sha256 {})", url, size, sha256);
+    Ok(())
+}
+
+/// Download and store the documents linked in the references of an incident that aren't stored yet.
+/// Failures are logged and don't fail the incident
+pub async fn fetch_attachments<H: HttpClient, D: Database>(client: &PortalClient<H>, db: &D, options: &AttachmentOptions, incident_id: i32, references: &[&str]) {
+    for url in references.iter().flat_map(|references| document_links(references)) {
+        let result = async {
+            if db.attachment_stored(incident_id, url.as_str()).await? {
+                trace!(incident_id, "Attachment {} is already stored", url);
+                return Ok(());
+            }
+            if !robots_allow(client, options, &url).await? {
+                debug!(incident_id, "robots.txt disallows attachment {}", url);
+                return Ok(());
+            }
+            if let Some((content_type, content)) = download(client, options, &url).await? {
+                store(db, options, incident_id, &url, &content_type, content).await?;
+            }
+            anyhow::Ok(())
+        }
+            .await;
+        if let Err(err) = result {
+            warn!(incident_id, "Failed to fetch attachment {}: {:#}", url, err);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Read-only verification of the stored incidents against the portal, reporting drift between
+//! the mirror and the current details without modifying anything
+
+use crate::http::HttpClient;
+use crate::model::{Incident, IncidentDetail};
+use crate::{PortalClient, RunOptions};
+use anyhow::{Context, Result};
+use chrono::{NaiveDate, NaiveDateTime};
+use tracing::{debug, info, warn};
+
+/// A stored detail as in `incident_details`
+type StoredDetail = (NaiveDate, String, String, String, String, String, serde_json::Value);
+
+async fn get_stored_incidents(pool: &sqlx::PgPool) -> Result<Vec<Incident>> {
+    let rows: Vec<(i32, NaiveDate, NaiveDateTime, i32, String, String)> = sqlx::query_as(
+        "SELECT incident_id, org_publish_date, modified_date::timestamp, published, country, incident_text FROM incidents ORDER BY incident_id",
+    )
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch stored incidents")?;
+    Ok(rows
+        .into_iter()
+        .map(|(incident_id, org_publish_date, modified_date, published, country, incident_text)| Incident {
+            incident_id,
+            org_publish_date,
+            modified_date,
+            published,
+            country,
+            incident_text,
+        })
+        .collect())
+}
+
+async fn get_stored_details(pool: &sqlx::PgPool, incident_id: i32) -> Result<Vec<StoredDetail>> {
+    sqlx::query_as(
+        r#"SELECT publish_date::date, affected_obj, affected_type, details_text, tags, href, "references"
+        FROM incident_details WHERE incident_id = $1 ORDER BY position"#,
+    )
+        .bind(incident_id)
+        .fetch_all(pool)
+        .await
+        .with_context(|| format!("Failed to fetch stored details of incident {}", incident_id))
+}
+
+/// Names of the fields that differ between the stored and the current details
+fn drifted_fields(stored: &[StoredDetail], current: &[IncidentDetail]) -> Vec<String> {
+    let mut fields = Vec::new();
+    if stored.len() != current.len() {
+        fields.push(format!("details ({} stored, {} current)", stored.len(), current.len()));
+    }
+    let multiple = stored.len().max(current.len()) > 1;
+    for (position, (stored, current)) in stored.iter().zip(current).enumerate() {
+        let (publish_date, affected_obj, affected_type, details_text, tags, href, references) = stored;
+        let current_references = current.references().ok();
+        let comparisons = [
+            ("publish_date", *publish_date == current.publish_date),
+            ("affected_obj", *affected_obj == current.affected_obj),
+            ("affected_type", *affected_type == current.affected_type),
+            ("details_text", *details_text == current.details_text),
+            ("tags", *tags == current.tags),
+            ("href", *href == current.href),
+            ("references", current_references.as_ref() == Some(references)),
+        ];
+        for (field, _) in comparisons.iter().filter(|(_, equal)| !equal) {
+            fields.push(if multiple { format!("{}[{}]", field, position) } else { field.to_string() });
+        }
+    }
+    fields
+}
+
+/// Re-fetch the details of the stored incidents, or a random `sample` of them, and print the ones
+/// that differ from the stored copy
+pub async fn audit_incidents<H: HttpClient>(client: &PortalClient<H>, pool: &sqlx::PgPool, options: &RunOptions, sample: Option<usize>) -> Result<()> {
+    let mut incidents = get_stored_incidents(pool).await?;
+    options.id_filter.apply(&mut incidents);
+    if let Some(count) = sample {
+        incidents = crate::sample_incidents(incidents, count, options.seed);
+    }
+    info!("Auditing {} stored incidents against the portal", incidents.len());
+
+    let (mut matching, mut drifted, mut failed) = (0, 0, 0);
+    for incident in &incidents {
+        let incident_id = incident.incident_id;
+        let result = async {
+            let stored = get_stored_details(pool, incident_id).await?;
+            let current = crate::fetch_incident_detail(client, pool, options, incident).await?;
+            anyhow::Ok(drifted_fields(&stored, &current))
+        }
+            .await;
+        match result {
+            Ok(fields) if fields.is_empty() => {
+                debug!(incident_id, "Stored incident matches the portal");
+                matching += 1;
+            }
+            Ok(fields) => {
+                println!("DRIFT {}: {}", incident_id, fields.join(", "));
+                drifted += 1;
+            }
+            Err(err) => {
+                warn!(incident_id, "Failed to audit incident: {:#}", err);
+                println!("ERROR {}: {:#}", incident_id, err);
+                failed += 1;
+            }
+        }
+        client.pacer(&options.endpoints.incident_detail(incident_id)).wait().await;
+    }
+
+    println!("Audited {} incidents: {} matching, {} drifted, {} failed", incidents.len(), matching, drifted, failed);
+    crate::log_delay_stats(&client.pacers);
+    Ok(())
+}
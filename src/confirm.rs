@@ -0,0 +1,29 @@
+//! Confirmation before destructive operations, shared by all subcommands that delete rows
+
+use anyhow::{Context, Result};
+use std::io::{BufRead, IsTerminal, Write};
+use tracing::info;
+
+/// Ask whether `count` `what` (e.g. "snapshots from incident_history") may be deleted. `yes`
+/// confirms without asking, e.g. via `--yes` in scripts. Without a terminal on stdin there is
+/// no one to ask, so the deletion is refused unless `yes` is given
+pub fn confirm_deletion(what: &str, count: usize, yes: bool) -> Result<bool> {
+    if count == 0 || yes {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("Refusing to delete {} {} without confirmation, pass --yes to confirm non-interactively", count, what);
+    }
+
+    let mut stderr = std::io::stderr().lock();
+    write!(stderr, "Delete {} {}? [y/N] ", count, what).context("Failed to write confirmation prompt")?;
+    stderr.flush().context("Failed to write confirmation prompt")?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer).context("Failed to read confirmation")?;
+
+    let confirmed = matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes");
+    if !confirmed {
+        info!("Not deleting {} {}", count, what);
+    }
+    Ok(confirmed)
+}
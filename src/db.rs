@@ -0,0 +1,303 @@
+//! Persistence of the pipeline, a trait so fetching and storing incidents can be exercised against an
+//! in-memory fake instead of Postgres. The implementation on `sqlx::PgPool` is the one used by the binary,
+//! the SQL lives next to the code that reads it
+
+use crate::attachments::Attachment;
+use crate::model::{Incident, IncidentDetail};
+use crate::sink::{RawResponse, StoreOptions};
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::future::Future;
+
+/// Last raw detail response of an incident stored in `detail_history`, with its validators
+#[derive(Clone)]
+pub struct StoredDetail {
+    pub content: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+pub trait Database: Send + Sync {
+    /// Incidents stored since a batch was begun, only visible to others once it is committed
+    type Batch: Send;
+
+    fn existing_incident_ids(&self) -> impl Future<Output = Result<HashSet<i32>>> + Send;
+
+    /// ETag and Last-Modified of the last stored snapshot of the incident list
+    fn last_snapshot_validators(&self) -> impl Future<Output = Result<(Option<String>, Option<String>)>> + Send;
+
+    fn store_raw_response(&self, response: &RawResponse<'_>, compress: bool) -> impl Future<Output = Result<()>> + Send;
+
+    fn last_stored_detail(&self, incident_id: i32) -> impl Future<Output = Result<Option<StoredDetail>>> + Send;
+
+    fn store_raw_detail(&self, incident_id: i32, content: &str, etag: Option<&str>, last_modified: Option<&str>) -> impl Future<Output = Result<()>> + Send;
+
+    fn store_incident(&self, incident: &Incident, details: &[IncidentDetail], options: &StoreOptions) -> impl Future<Output = Result<()>> + Send;
+
+    fn begin_batch(&self) -> impl Future<Output = Result<Self::Batch>> + Send;
+
+    /// Store an incident in a batch, a failure leaves the batch as it was before
+    fn store_incident_in_batch(&self, batch: &mut Self::Batch, incident: &Incident, details: &[IncidentDetail], options: &StoreOptions) -> impl Future<Output = Result<()>> + Send;
+
+    /// Undo the last successful [`Database::store_incident_in_batch`]
+    fn discard_last(&self, batch: &mut Self::Batch) -> impl Future<Output = Result<()>> + Send;
+
+    fn commit_batch(&self, batch: Self::Batch) -> impl Future<Output = Result<()>> + Send;
+
+    /// See [`crate::touch_unchanged_incident`]
+    fn touch_unchanged_incident(&self, incident: &Incident) -> impl Future<Output = Result<bool>> + Send;
+
+    fn record_failed_incident(&self, incident: &Incident, err: &anyhow::Error) -> impl Future<Output = Result<()>> + Send;
+
+    fn clear_failed_incidents(&self, incident_ids: &[i32]) -> impl Future<Output = Result<()>> + Send;
+
+    fn attachment_stored(&self, incident_id: i32, url: &str) -> impl Future<Output = Result<bool>> + Send;
+
+    fn store_attachment(&self, attachment: &Attachment<'_>) -> impl Future<Output = Result<()>> + Send;
+
+    /// Fail with a serialization failure as raised by the database, see `--simulate-db-errors`
+    #[cfg(feature = "simulate-errors")]
+    fn fail_serialization(&self, what: &str) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Transaction of a batch on Postgres, every incident is stored after the savepoint `incident` so
+/// a failed or discarded one doesn't abort the incidents stored before
+pub struct PgBatch {
+    transaction: sqlx::Transaction<'static, sqlx::Postgres>,
+    /// Whether the last incident is stored after the savepoint
+    savepoint: bool,
+}
+
+impl PgBatch {
+    async fn rollback_to_savepoint(&mut self) -> Result<()> {
+        sqlx::query("ROLLBACK TO SAVEPOINT incident").execute(&mut *self.transaction).await.context("Failed to roll back to savepoint")?;
+        sqlx::query("RELEASE SAVEPOINT incident").execute(&mut *self.transaction).await.context("Failed to release savepoint")?;
+        Ok(())
+    }
+}
+
+impl Database for sqlx::PgPool {
+    type Batch = PgBatch;
+
+    async fn existing_incident_ids(&self) -> Result<HashSet<i32>> {
+        crate::get_existing_incident_ids(self).await
+    }
+
+    async fn last_snapshot_validators(&self) -> Result<(Option<String>, Option<String>)> {
+        crate::get_last_snapshot_validators(self).await
+    }
+
+    async fn store_raw_response(&self, response: &RawResponse<'_>, compress: bool) -> Result<()> {
+        crate::store_raw_response(self, response.content, response.etag, response.last_modified, response.idempotency_key, compress).await
+    }
+
+    async fn last_stored_detail(&self, incident_id: i32) -> Result<Option<StoredDetail>> {
+        crate::get_last_stored_detail(self, incident_id).await
+    }
+
+    async fn store_raw_detail(&self, incident_id: i32, content: &str, etag: Option<&str>, last_modified: Option<&str>) -> Result<()> {
+        crate::store_raw_detail(self, incident_id, content, etag, last_modified).await
+    }
+
+    async fn store_incident(&self, incident: &Incident, details: &[IncidentDetail], options: &StoreOptions) -> Result<()> {
+        crate::store_incident(self, incident, details, options).await
+    }
+
+    async fn begin_batch(&self) -> Result<PgBatch> {
+        let transaction = self.begin().await.context("Failed to start transaction")?;
+        Ok(PgBatch { transaction, savepoint: false })
+    }
+
+    async fn store_incident_in_batch(&self, batch: &mut PgBatch, incident: &Incident, details: &[IncidentDetail], options: &StoreOptions) -> Result<()> {
+        if batch.savepoint {
+            sqlx::query("RELEASE SAVEPOINT incident").execute(&mut *batch.transaction).await.context("Failed to release savepoint")?;
+            batch.savepoint = false;
+        }
+        sqlx::query("SAVEPOINT incident").execute(&mut *batch.transaction).await.context("Failed to create savepoint")?;
+        if let Err(err) = crate::store_incident(&mut *batch.transaction, incident, details, options).await {
+            batch.rollback_to_savepoint().await?;
+            return Err(err);
+        }
+        batch.savepoint = true;
+        Ok(())
+    }
+
+    async fn discard_last(&self, batch: &mut PgBatch) -> Result<()> {
+        if batch.savepoint {
+            batch.rollback_to_savepoint().await?;
+            batch.savepoint = false;
+        }
+        Ok(())
+    }
+
+    async fn commit_batch(&self, batch: PgBatch) -> Result<()> {
+        batch.transaction.commit().await.context("Failed to commit stored incidents")
+    }
+
+    async fn touch_unchanged_incident(&self, incident: &Incident) -> Result<bool> {
+        crate::touch_unchanged_incident(self, incident).await
+    }
+
+    async fn record_failed_incident(&self, incident: &Incident, err: &anyhow::Error) -> Result<()> {
+        crate::record_failed_incident(self, incident, err).await
+    }
+
+    async fn clear_failed_incidents(&self, incident_ids: &[i32]) -> Result<()> {
+        crate::clear_failed_incidents(self, incident_ids).await
+    }
+
+    async fn attachment_stored(&self, incident_id: i32, url: &str) -> Result<bool> {
+        crate::attachments::is_stored(self, incident_id, url).await
+    }
+
+    async fn store_attachment(&self, attachment: &Attachment<'_>) -> Result<()> {
+        crate::attachments::insert(self, attachment).await
+    }
+
+    #[cfg(feature = "simulate-errors")]
+    async fn fail_serialization(&self, what: &str) -> Result<()> {
+        sqlx::query("DO $$ BEGIN RAISE EXCEPTION 'Simulated serialization failure' USING ERRCODE = 'serialization_failure'; END $$")
+            .execute(self)
+            .await
+            .with_context(|| format!("Failed {}", what))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// In-memory database, clones share their contents like clones of a pool
+    #[derive(Clone, Default)]
+    pub struct FakeDatabase {
+        state: Arc<Mutex<State>>,
+    }
+
+    #[derive(Default)]
+    struct State {
+        incidents: HashMap<i32, (Incident, Vec<IncidentDetail>)>,
+        /// Error and attempts of the failed incidents
+        failed: HashMap<i32, (String, i32)>,
+        raw_responses: Vec<String>,
+        raw_details: HashMap<i32, StoredDetail>,
+        /// Incidents marked as fetched by [`Database::touch_unchanged_incident`]
+        touched: Vec<i32>,
+        attachments: HashSet<(i32, String)>,
+    }
+
+    impl FakeDatabase {
+        pub fn stored_ids(&self) -> Vec<i32> {
+            let mut ids: Vec<i32> = self.state.lock().unwrap().incidents.keys().copied().collect();
+            ids.sort();
+            ids
+        }
+
+        /// Attempts of a failed incident, `None` if it isn't recorded as failed
+        pub fn failed_attempts(&self, incident_id: i32) -> Option<i32> {
+            self.state.lock().unwrap().failed.get(&incident_id).map(|(_, attempts)| *attempts)
+        }
+
+        pub fn raw_responses(&self) -> Vec<String> {
+            self.state.lock().unwrap().raw_responses.clone()
+        }
+
+        pub fn touched(&self) -> Vec<i32> {
+            self.state.lock().unwrap().touched.clone()
+        }
+    }
+
+    impl Database for FakeDatabase {
+        /// Incidents staged until the commit
+        type Batch = Vec<(Incident, Vec<IncidentDetail>)>;
+
+        async fn existing_incident_ids(&self) -> Result<HashSet<i32>> {
+            Ok(self.state.lock().unwrap().incidents.keys().copied().collect())
+        }
+
+        async fn last_snapshot_validators(&self) -> Result<(Option<String>, Option<String>)> {
+            Ok((None, None))
+        }
+
+        async fn store_raw_response(&self, response: &RawResponse<'_>, _compress: bool) -> Result<()> {
+            self.state.lock().unwrap().raw_responses.push(response.content.to_owned());
+            Ok(())
+        }
+
+        async fn last_stored_detail(&self, incident_id: i32) -> Result<Option<StoredDetail>> {
+            Ok(self.state.lock().unwrap().raw_details.get(&incident_id).cloned())
+        }
+
+        async fn store_raw_detail(&self, incident_id: i32, content: &str, etag: Option<&str>, last_modified: Option<&str>) -> Result<()> {
+            let detail = StoredDetail { content: content.to_owned(), etag: etag.map(str::to_owned), last_modified: last_modified.map(str::to_owned) };
+            self.state.lock().unwrap().raw_details.insert(incident_id, detail);
+            Ok(())
+        }
+
+        async fn store_incident(&self, incident: &Incident, details: &[IncidentDetail], _options: &StoreOptions) -> Result<()> {
+            self.state.lock().unwrap().incidents.insert(incident.incident_id, (incident.clone(), details.to_vec()));
+            Ok(())
+        }
+
+        async fn begin_batch(&self) -> Result<Self::Batch> {
+            Ok(Vec::new())
+        }
+
+        async fn store_incident_in_batch(&self, batch: &mut Self::Batch, incident: &Incident, details: &[IncidentDetail], _options: &StoreOptions) -> Result<()> {
+            batch.push((incident.clone(), details.to_vec()));
+            Ok(())
+        }
+
+        async fn discard_last(&self, batch: &mut Self::Batch) -> Result<()> {
+            batch.pop();
+            Ok(())
+        }
+
+        async fn commit_batch(&self, batch: Self::Batch) -> Result<()> {
+            let mut state = self.state.lock().unwrap();
+            state.incidents.extend(batch.into_iter().map(|(incident, details)| (incident.incident_id, (incident, details))));
+            Ok(())
+        }
+
+        async fn touch_unchanged_incident(&self, incident: &Incident) -> Result<bool> {
+            let mut state = self.state.lock().unwrap();
+            let unchanged = state.incidents.get(&incident.incident_id).is_some_and(|(stored, _)| stored.modified_date == incident.modified_date);
+            if unchanged {
+                state.touched.push(incident.incident_id);
+                state.failed.remove(&incident.incident_id);
+            }
+            Ok(unchanged)
+        }
+
+        async fn record_failed_incident(&self, incident: &Incident, err: &anyhow::Error) -> Result<()> {
+            let mut state = self.state.lock().unwrap();
+            let failed = state.failed.entry(incident.incident_id).or_insert_with(|| (String::new(), 0));
+            *failed = (format!("{:#}", err), failed.1 + 1);
+            Ok(())
+        }
+
+        async fn clear_failed_incidents(&self, incident_ids: &[i32]) -> Result<()> {
+            let mut state = self.state.lock().unwrap();
+            for incident_id in incident_ids {
+                state.failed.remove(incident_id);
+            }
+            Ok(())
+        }
+
+        async fn attachment_stored(&self, incident_id: i32, url: &str) -> Result<bool> {
+            Ok(self.state.lock().unwrap().attachments.contains(&(incident_id, url.to_owned())))
+        }
+
+        async fn store_attachment(&self, attachment: &Attachment<'_>) -> Result<()> {
+            self.state.lock().unwrap().attachments.insert((attachment.incident_id, attachment.url.to_owned()));
+            Ok(())
+        }
+
+        #[cfg(feature = "simulate-errors")]
+        async fn fail_serialization(&self, what: &str) -> Result<()> {
+            anyhow::bail!("Failed {}: simulated serialization failure", what)
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Optional local SQLite cache of raw incident detail responses, so retries and restarts within a
+//! short window don't fetch the same details from the portal again
+
+use anyhow::{Context, Result};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{debug, trace};
+
+pub struct DetailCache {
+    pool: SqlitePool,
+    ttl: Duration,
+}
+
+impl DetailCache {
+    /// Open or create the cache at `path`, entries older than `ttl` are ignored and pruned
+    pub async fn open(path: &Path, ttl: Duration) -> Result<Self> {
+        debug!("Using detail cache {} with a TTL of {:?}", path.display(), ttl);
+        let options = SqliteConnectOptions::new().filename(path).create_if_missing(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .with_context(|| format!("Failed to open detail cache {}", path.display()))?;
+
+        // Caches of earlier versions were keyed without the language, they are only a cache
+        sqlx::query("DROP TABLE IF EXISTS details")
+            .execute(&pool)
+            .await
+            .context("Failed to drop outdated detail cache table")?;
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS detail_responses (
+                incident_id INTEGER NOT NULL,
+                language TEXT NOT NULL,
+                modified_date TEXT NOT NULL,
+                body TEXT NOT NULL,
+                fetched_at INTEGER NOT NULL,
+                PRIMARY KEY (incident_id, language, modified_date)
+            )"#,
+        )
+            .execute(&pool)
+            .await
+            .context("Failed to create detail cache table")?;
+
+        let cache = Self { pool, ttl };
+        let pruned = sqlx::query("DELETE FROM detail_responses WHERE fetched_at < $1")
+            .bind(cache.cutoff())
+            .execute(&cache.pool)
+            .await
+            .context("Failed to prune detail cache")?;
+        debug!("Pruned {} expired entries from the detail cache", pruned.rows_affected());
+        Ok(cache)
+    }
+
+    /// Unix timestamp before which entries are expired
+    fn cutoff(&self) -> i64 {
+        Utc::now().timestamp() - i64::try_from(self.ttl.as_secs()).unwrap_or(i64::MAX)
+    }
+
+    /// Cached detail response of an incident in the given language and version, if not expired
+    pub async fn get(&self, incident_id: i32, language: &str, modified_date: NaiveDateTime) -> Result<Option<String>> {
+        let body: Option<String> = sqlx::query_scalar(
+            "SELECT body FROM detail_responses WHERE incident_id = $1 AND language = $2 AND modified_date = $3 AND fetched_at >= $4",
+        )
+            .bind(incident_id)
+            .bind(language)
+            .bind(modified_date.to_string())
+            .bind(self.cutoff())
+            .fetch_optional(&self.pool)
+            .await
+            .with_context(|| format!("Failed to read incident {} from the detail cache", incident_id))?;
+        trace!(incident_id, language, hit = body.is_some(), "Looked up detail cache");
+        Ok(body)
+    }
+
+    pub async fn put(&self, incident_id: i32, language: &str, modified_date: NaiveDateTime, body: &str) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO detail_responses (incident_id, language, modified_date, body, fetched_at) VALUES ($1, $2, $3, $4, $5)")
+            .bind(incident_id)
+            .bind(language)
+            .bind(modified_date.to_string())
+            .bind(body)
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await
+            .with_context(|| format!("Failed to write incident {} to the detail cache", incident_id))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn entries_are_keyed_by_language() {
+        let path = std::env::temp_dir().join(format!("dsgvo-detail-cache-{}.sqlite", std::process::id()));
+        let cache = DetailCache::open(&path, Duration::from_secs(60)).await.unwrap();
+        let modified_date = NaiveDateTime::parse_from_str("2024-03-01 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        cache.put(1, "de", modified_date, "deutsch").await.unwrap();
+
+        assert_eq!(cache.get(1, "de", modified_date).await.unwrap().as_deref(), Some("deutsch"));
+        assert_eq!(cache.get(1, "en", modified_date).await.unwrap(), None);
+        assert_eq!(cache.get(1, "de", modified_date + chrono::Duration::seconds(1)).await.unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
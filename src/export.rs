@@ -0,0 +1,215 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use tracing::{debug, info, trace};
+use sha2::Sha256;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+/// Fields that may contain personal data and are redacted by default
+pub const DEFAULT_REDACT_FIELDS: &[&str] = &["affected_obj"];
+
+pub struct ExportOptions {
+    /// File to write to, stdout if not given
+    pub output: Option<PathBuf>,
+    pub redaction: Option<Redaction>,
+    pub format: ExportFormat,
+    pub order_by: ExportOrder,
+    pub descending: bool,
+    /// Export at most this many incidents, all if not given
+    pub limit: Option<i64>,
+    /// Skip this many incidents in the given order
+    pub offset: Option<i64>,
+    /// Only export incidents stored after this
+    pub since: Option<DateTime<Utc>>,
+    /// Name of the watermark of an incremental export, which continues after the newest incident
+    /// of the last export with the same name and is advanced once the output is written
+    pub watermark: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    JsonLines,
+    /// Columnar file for analytics tools, only available with the `parquet` feature
+    Parquet,
+}
+
+/// Column the export is ordered by
+#[derive(Debug, Clone, Copy)]
+pub enum ExportOrder {
+    Id,
+    PublishDate,
+    Country,
+}
+
+impl ExportOrder {
+    /// `ORDER BY` clause, ties are broken by the incident id so pages with `--offset` are stable
+    fn order_by(self, descending: bool) -> String {
+        let direction = if descending { "DESC" } else { "ASC" };
+        match self {
+            ExportOrder::Id => format!("incident_id {}", direction),
+            // Incidents stored without details have no publish date
+            ExportOrder::PublishDate => format!("publish_date {} NULLS LAST, incident_id {}", direction, direction),
+            ExportOrder::Country => format!("country {}, incident_id {}", direction, direction),
+        }
+    }
+}
+
+/// Pseudonymize fields by replacing them with a salted HMAC, so the same value always maps to the same hash
+pub struct Redaction {
+    pub fields: Vec<String>,
+    pub salt: String,
+}
+
+impl Redaction {
+    fn redact(&self, incident: &mut serde_json::Value) -> Result<()> {
+        for field in &self.fields {
+            let Some(value) = incident.get_mut(field) else {
+                continue;
+            };
+            let plain = match &*value {
+                serde_json::Value::Null => continue,
+                serde_json::Value::String(text) => text.clone(),
+                other => other.to_string(),
+            };
+            let mut mac = Hmac::<Sha256>::new_from_slice(self.salt.as_bytes()).context("Failed to create HMAC")?;
+            mac.update(plain.as_bytes());
+            *value = serde_json::Value::String(hex::encode(mac.finalize().into_bytes()));
+        }
+        Ok(())
+    }
+}
+
+/// Parse `--since` as an RFC 3339 timestamp or a date, which means midnight UTC
+pub fn parse_since(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc())
+        .map_err(|_| format!("'{}' is neither an RFC 3339 timestamp nor a YYYY-MM-DD date", value))
+}
+
+async fn get_watermark(pool: &sqlx::PgPool, name: &str) -> Result<Option<DateTime<Utc>>> {
+    sqlx::query_scalar("SELECT watermark FROM export_watermarks WHERE name = $1")
+        .bind(name)
+        .fetch_optional(pool)
+        .await
+        .with_context(|| format!("Failed to get export watermark '{}', is the schema migrated?", name))
+}
+
+async fn set_watermark(pool: &sqlx::PgPool, name: &str, watermark: DateTime<Utc>) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO export_watermarks (name, watermark) VALUES ($1, $2)
+        ON CONFLICT (name) DO UPDATE SET watermark = excluded.watermark, exported_at = CURRENT_TIMESTAMP"#,
+    )
+        .bind(name)
+        .bind(watermark)
+        .execute(pool)
+        .await
+        .with_context(|| format!("Failed to store export watermark '{}'", name))?;
+    Ok(())
+}
+
+/// Latest watermark that can't skip incidents of transactions still open when the incidents are
+/// read. Their `fetched_at` is the start of their transaction, so they become visible after the
+/// export with a time older than the newest exported one. Read before the incidents, anything
+/// stored after it is exported again by the next incremental export
+async fn get_watermark_horizon(pool: &sqlx::PgPool) -> Result<DateTime<Utc>> {
+    sqlx::query_scalar(
+        r#"SELECT LEAST(clock_timestamp(), min(xact_start)) - interval '1 microsecond'
+        FROM pg_stat_activity WHERE pid <> pg_backend_pid() AND xact_start IS NOT NULL"#,
+    )
+        .fetch_one(pool)
+        .await
+        .context("Failed to get the start of the oldest open transaction")
+}
+
+/// Export the stored incidents as JSON lines or Parquet
+pub async fn export_incidents(pool: &sqlx::PgPool, options: &ExportOptions) -> Result<()> {
+    let since = match &options.watermark {
+        Some(name) => {
+            let watermark = get_watermark(pool, name).await?;
+            match watermark {
+                Some(watermark) => info!("Exporting incidents stored since the last export '{}' at {}", name, watermark),
+                None => info!("First incremental export '{}', exporting all incidents", name),
+            }
+            watermark
+        }
+        None => options.since,
+    };
+
+    let horizon = match &options.watermark {
+        Some(_) => Some(get_watermark_horizon(pool).await?),
+        None => None,
+    };
+
+    trace!("Fetching incidents for export ordered by {:?}", options.order_by);
+    // Incidents stored before `fetched_at` existed only appear in full exports
+    let query = format!(
+        "SELECT to_jsonb(incidents) - 'search_vector', fetched_at FROM incidents WHERE $3::timestamptz IS NULL OR fetched_at > $3 ORDER BY {} LIMIT $1 OFFSET $2",
+        options.order_by.order_by(options.descending),
+    );
+    let rows: Vec<(serde_json::Value, Option<DateTime<Utc>>)> = sqlx::query_as(&query)
+        .bind(options.limit)
+        .bind(options.offset)
+        .bind(since)
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch incidents for export")?;
+    let newest = rows.iter().filter_map(|(_, fetched_at)| *fetched_at).max().map(|newest| match horizon {
+        Some(horizon) if horizon < newest => {
+            debug!("Capping export watermark at {}, the start of the oldest open transaction", horizon);
+            horizon
+        }
+        _ => newest,
+    });
+    let incidents: Vec<serde_json::Value> = rows.into_iter().map(|(incident, _)| incident).collect();
+
+    if cfg!(not(feature = "parquet")) && options.format == ExportFormat::Parquet {
+        anyhow::bail!("Parquet export requires a build with the `parquet` feature");
+    }
+
+    let mut writer: BufWriter<Box<dyn Write + Send>> = match &options.output {
+        Some(path) => {
+            debug!("Exporting to {}", path.display());
+            BufWriter::new(Box::new(File::create(path).with_context(|| format!("Failed to create {}", path.display()))?))
+        }
+        None => BufWriter::new(Box::new(std::io::stdout())),
+    };
+
+    if let Some(redaction) = &options.redaction {
+        info!("Redacting fields {:?}, redaction is best-effort and does not catch personal data in other fields", redaction.fields);
+    }
+
+    let mut incidents = incidents;
+    if let Some(redaction) = &options.redaction {
+        for incident in &mut incidents {
+            redaction.redact(incident)?;
+        }
+    }
+
+    let count = incidents.len();
+    match options.format {
+        ExportFormat::JsonLines => {
+            for incident in &incidents {
+                serde_json::to_writer(&mut writer, incident).context("Failed to write incident")?;
+                writeln!(writer).context("Failed to write incident")?;
+            }
+        }
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => crate::parquet_export::write(&mut writer, &incidents)?,
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => unreachable!("checked above"),
+    }
+    writer.flush().context("Failed to flush export")?;
+
+    info!("Exported {} incidents", count);
+    // Only advanced after the output is written, so a failed export is repeated by the next one
+    if let (Some(name), Some(newest)) = (&options.watermark, newest) {
+        set_watermark(pool, name, newest).await?;
+        debug!("Advanced export watermark '{}' to {}", name, newest);
+    }
+    Ok(())
+}
@@ -0,0 +1,133 @@
+//! Runtime renames of portal JSON keys, so a renamed field on the portal doesn't require a new release
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use tracing::{debug, trace};
+
+/// Canonical field names of an incident in the list and the portal keys they are parsed from by default
+const INCIDENT_FIELDS: &[(&str, &str)] = &[
+    ("incident_id", "incidentID"),
+    ("org_publish_date", "orgPublishDate"),
+    ("modified_date", "modifiedDate"),
+    ("published", "published"),
+    ("country", "country"),
+    ("incident_text", "incidentText"),
+];
+
+/// Canonical field names of incident details and the portal keys they are parsed from by default
+const DETAIL_FIELDS: &[(&str, &str)] = &[
+    ("publish_date", "publishDate"),
+    ("affected_obj", "affectedObj"),
+    ("affected_type", "affectedType"),
+    ("details_text", "description_de"),
+    ("tags", "tags"),
+    ("href", "href"),
+    ("reference", "reference"),
+];
+
+/// Mapping of canonical field names to the keys the portal currently uses, e.g.
+/// `{"incident": {"incident_id": "incidentId"}, "detail": {"details_text": "description"}}`.
+/// Fields that aren't mapped use the built-in keys
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FieldMapping {
+    incident: HashMap<String, String>,
+    detail: HashMap<String, String>,
+}
+
+/// Resolve the mapping of `canonical -> portal key` into `portal key -> built-in key`
+fn resolve(mapping: &HashMap<String, String>, fields: &[(&str, &'static str)], kind: &str) -> Result<Vec<(String, &'static str)>> {
+    mapping
+        .iter()
+        .map(|(canonical, key)| {
+            let (_, builtin) = fields
+                .iter()
+                .find(|(name, _)| name == canonical)
+                .with_context(|| format!("Unknown {} field '{}' in field mapping", kind, canonical))?;
+            Ok((key.clone(), *builtin))
+        })
+        .collect()
+}
+
+fn rename_keys(value: &mut Value, renames: &[(String, &'static str)]) {
+    let Value::Object(object) = value else {
+        return;
+    };
+    for (key, builtin) in renames {
+        if let Some(field) = object.remove(key) {
+            trace!("Renaming field '{}' to '{}'", key, builtin);
+            object.insert((*builtin).to_owned(), field);
+        }
+    }
+}
+
+impl FieldMapping {
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read field mapping {}", path.display()))?;
+        let mapping: Self = serde_json::from_str(&content).with_context(|| format!("Failed to parse field mapping {}", path.display()))?;
+        mapping.validate()?;
+        debug!("Loaded field mapping: {:?}", mapping);
+        Ok(mapping)
+    }
+
+    /// Fail early on typos instead of on the first response
+    pub fn validate(&self) -> Result<()> {
+        resolve(&self.incident, INCIDENT_FIELDS, "incident")?;
+        resolve(&self.detail, DETAIL_FIELDS, "detail")?;
+        Ok(())
+    }
+
+    /// Use the mappings of `defaults` for fields this mapping doesn't map
+    pub fn with_defaults(mut self, defaults: &FieldMapping) -> Self {
+        for (canonical, key) in &defaults.incident {
+            self.incident.entry(canonical.clone()).or_insert_with(|| key.clone());
+        }
+        for (canonical, key) in &defaults.detail {
+            self.detail.entry(canonical.clone()).or_insert_with(|| key.clone());
+        }
+        self
+    }
+
+    /// Map a detail field to `key` unless the mapping file maps it already
+    pub fn map_detail_default(&mut self, canonical: &str, key: String) {
+        self.detail.entry(canonical.to_owned()).or_insert(key);
+    }
+
+    /// Key the portal currently uses for a canonical incident field, e.g. `incidentID` for `incident_id`
+    pub fn incident_key<'a>(&'a self, canonical: &str) -> &'a str {
+        match self.incident.get(canonical) {
+            Some(key) => key,
+            None => INCIDENT_FIELDS
+                .iter()
+                .find(|(name, _)| *name == canonical)
+                .map(|(_, key)| *key)
+                .unwrap_or_else(|| panic!("Unknown incident field '{}'", canonical)),
+        }
+    }
+
+    fn remap<'a>(body: &'a str, mapping: &HashMap<String, String>, fields: &[(&str, &'static str)], kind: &str) -> Result<Cow<'a, str>> {
+        if mapping.is_empty() {
+            return Ok(Cow::Borrowed(body));
+        }
+        let renames = resolve(mapping, fields, kind)?;
+        let mut value: Value = serde_json::from_str(body).with_context(|| format!("Failed to parse {} response for field mapping", kind))?;
+        match &mut value {
+            Value::Array(items) => items.iter_mut().for_each(|item| rename_keys(item, &renames)),
+            value => rename_keys(value, &renames),
+        }
+        Ok(Cow::Owned(value.to_string()))
+    }
+
+    /// Rename the keys of every incident in an incident list response to the built-in keys
+    pub fn remap_incidents<'a>(&self, body: &'a str) -> Result<Cow<'a, str>> {
+        Self::remap(body, &self.incident, INCIDENT_FIELDS, "incident")
+    }
+
+    /// Rename the keys of the details in an incident detail response to the built-in keys
+    pub fn remap_detail<'a>(&self, body: &'a str) -> Result<Cow<'a, str>> {
+        Self::remap(body, &self.detail, DETAIL_FIELDS, "detail")
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+use crate::field_mapping::FieldMapping;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::time::Duration;
+use tracing::{info, trace};
+
+/// Gzip a raw response for `content_gzip`, see `--compress-history`
+pub fn compress(content: &str) -> Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(content.as_bytes()).context("Failed to compress snapshot")?;
+    encoder.finish().context("Failed to compress snapshot")
+}
+
+pub fn decompress(content_gzip: &[u8]) -> Result<String> {
+    let mut content = String::new();
+    flate2::read::GzDecoder::new(content_gzip).read_to_string(&mut content).context("Failed to decompress snapshot")?;
+    Ok(content)
+}
+
+/// JSON text of a snapshot stored either as jsonb or compressed
+pub fn snapshot_content(content: Option<String>, content_gzip: Option<Vec<u8>>) -> Result<Option<String>> {
+    match (content, content_gzip) {
+        (Some(content), _) => Ok(Some(content)),
+        (None, Some(content_gzip)) => decompress(&content_gzip).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Which `incident_history` snapshots survive `compact-history`
+pub struct RetentionPolicy {
+    /// Always keep the latest snapshots, at least one so conditional requests keep working
+    pub keep_last: u64,
+    /// Keep every snapshot younger than this, older ones are thinned out to the latest one per day
+    pub keep_all_within: Duration,
+    /// Keep the very first snapshot
+    pub keep_first: bool,
+    /// Keep every snapshot whose content differs from the previous one
+    pub keep_changes: bool,
+    pub dry_run: bool,
+    /// Delete without asking for confirmation
+    pub yes: bool,
+}
+
+/// Prune old raw snapshots according to the retention policy
+pub async fn compact_history(pool: &sqlx::PgPool, policy: &RetentionPolicy) -> Result<()> {
+    trace!("Fetching snapshots for compaction");
+    // Compressed snapshots are compared by their gzip bytes, which are deterministic for the same
+    // response, so only a switch of `--compress-history` counts as a change on its own
+    let snapshots: Vec<(i32, DateTime<Utc>, String)> = sqlx::query_as(
+        "SELECT id, created_at, coalesce(md5(content::text), md5(raw_text), md5(content_gzip)) FROM incident_history ORDER BY id",
+    )
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch snapshots")?;
+
+    let cutoff = Utc::now() - chrono::Duration::from_std(policy.keep_all_within).context("Retention duration is too large")?;
+    let mut keep: HashSet<i32> = snapshots.iter().rev().take(usize::try_from(policy.keep_last).unwrap_or(usize::MAX)).map(|(id, _, _)| *id).collect();
+    if policy.keep_first {
+        keep.extend(snapshots.first().map(|(id, _, _)| *id));
+    }
+
+    let mut days: HashSet<NaiveDate> = HashSet::new();
+    // Newest first, so the first snapshot seen per day is the latest one of that day
+    for (index, (id, created_at, hash)) in snapshots.iter().enumerate().rev() {
+        if *created_at >= cutoff || days.insert(created_at.date_naive()) {
+            trace!("Keeping snapshot {} from {}", id, created_at);
+            keep.insert(*id);
+        }
+        let previous_hash = index.checked_sub(1).map(|previous| snapshots[previous].2.as_str());
+        if policy.keep_changes && previous_hash != Some(hash.as_str()) {
+            keep.insert(*id);
+        }
+    }
+
+    let delete: Vec<i32> = snapshots.iter().map(|(id, _, _)| *id).filter(|id| !keep.contains(id)).collect();
+    info!("Keeping {} of {} snapshots, pruning {}", snapshots.len() - delete.len(), snapshots.len(), delete.len());
+
+    if policy.dry_run {
+        info!("Dry run, would prune snapshots: {:?}", delete);
+        return Ok(());
+    }
+    if !crate::confirm::confirm_deletion("snapshots from incident_history", delete.len(), policy.yes)? {
+        return Ok(());
+    }
+
+    let deleted = sqlx::query("DELETE FROM incident_history WHERE id = ANY($1)")
+        .bind(&delete)
+        .execute(pool)
+        .await
+        .context("Failed to prune snapshots")?
+        .rows_affected();
+    info!("Pruned {} snapshots", deleted);
+    Ok(())
+}
+
+/// Rebuild `incident_history_latest` with the newest state of every incident found in any snapshot.
+/// Snapshots are stored as received, so their id and modified date are read from the keys given by `field_mapping`
+pub async fn flatten_history(pool: &sqlx::PgPool, field_mapping: &FieldMapping) -> Result<()> {
+    let mut tx = pool.begin().await.context("Failed to start transaction")?;
+    sqlx::query("DELETE FROM incident_history_latest")
+        .execute(&mut *tx)
+        .await
+        .context("Failed to clear incident_history_latest")?;
+
+    // Compressed snapshots can't be expanded in SQL, they are decompressed one by one into a
+    // temporary table the flattening reads alongside the jsonb ones
+    sqlx::query("CREATE TEMPORARY TABLE decompressed_history (id INTEGER, created_at TIMESTAMP WITH TIME ZONE, content JSONB) ON COMMIT DROP")
+        .execute(&mut *tx)
+        .await
+        .context("Failed to create table for decompressed snapshots")?;
+    let compressed: Vec<i32> = sqlx::query_scalar("SELECT id FROM incident_history WHERE content_gzip IS NOT NULL ORDER BY id")
+        .fetch_all(&mut *tx)
+        .await
+        .context("Failed to fetch compressed snapshots")?;
+    for id in compressed {
+        trace!("Decompressing snapshot {}", id);
+        let (created_at, content_gzip): (Option<DateTime<Utc>>, Vec<u8>) = sqlx::query_as("SELECT created_at, content_gzip FROM incident_history WHERE id = $1")
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to fetch snapshot {}", id))?;
+        let content = decompress(&content_gzip).with_context(|| format!("Snapshot {} is corrupt", id))?;
+        sqlx::query("INSERT INTO decompressed_history (id, created_at, content) VALUES ($1, $2, $3::jsonb)")
+            .bind(id)
+            .bind(created_at)
+            .bind(content)
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to decompress snapshot {}", id))?;
+    }
+
+    trace!("Flattening snapshots");
+    let inserted = sqlx::query(
+        r#"INSERT INTO incident_history_latest (incident_id, modified_date, incident, snapshot_id, snapshot_created_at)
+        SELECT DISTINCT ON (incident_id) incident_id, modified_date, incident, snapshot_id, snapshot_created_at
+        FROM (
+            SELECT (element->>$1)::integer AS incident_id,
+                   (element->>$2)::timestamp AS modified_date,
+                   element AS incident,
+                   history.id AS snapshot_id,
+                   history.created_at AS snapshot_created_at
+            FROM (
+                SELECT id, created_at, content FROM incident_history WHERE content IS NOT NULL
+                UNION ALL
+                SELECT id, created_at, content FROM decompressed_history
+            ) history
+            CROSS JOIN LATERAL jsonb_array_elements(history.content) AS element
+            WHERE jsonb_typeof(history.content) = 'array'
+              AND element ? $1
+              AND element ? $2
+        ) elements
+        ORDER BY incident_id, modified_date DESC, snapshot_id DESC"#,
+    )
+        .bind(field_mapping.incident_key("incident_id"))
+        .bind(field_mapping.incident_key("modified_date"))
+        .execute(&mut *tx)
+        .await
+        .context("Failed to flatten snapshots")?
+        .rows_affected();
+
+    tx.commit().await.context("Failed to commit flattened history")?;
+    info!("Flattened snapshots into {} incidents in incident_history_latest", inserted);
+    Ok(())
+}
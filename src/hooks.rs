@@ -0,0 +1,52 @@
+//! Extension point called after an incident was stored, e.g. for enrichment, indexing or notifications
+
+use crate::model::{Incident, IncidentDetail};
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use tracing::{debug, trace};
+
+pub trait IncidentHook: Send + Sync {
+    /// Called after `incident` and its `details` were stored successfully
+    fn on_stored<'a>(&'a self, incident: &'a Incident, details: &'a [IncidentDetail]) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Runs a shell command per stored incident, passing `{"incident": ..., "details": [...]}` as JSON on stdin
+pub struct CommandHook {
+    pub command: String,
+}
+
+impl IncidentHook for CommandHook {
+    fn on_stored<'a>(&'a self, incident: &'a Incident, details: &'a [IncidentDetail]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let input = serde_json::to_vec(&serde_json::json!({ "incident": incident, "details": details }))
+                .context("Failed to serialize incident for hook")?;
+            let command = self.command.clone();
+            let incident_id = incident.incident_id;
+            trace!(incident_id, "Running hook command: {}", command);
+
+            let status = tokio::task::spawn_blocking(move || -> Result<std::process::ExitStatus> {
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(&command)
+                    .env("INCIDENT_ID", incident_id.to_string())
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("Failed to run hook command '{}'", command))?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(&input).context("Failed to write incident to hook command")?;
+                }
+                child.wait().context("Failed to wait for hook command")
+            })
+                .await
+                .context("Hook command panicked")??;
+
+            if !status.success() {
+                anyhow::bail!("Hook command exited with {}", status);
+            }
+            debug!(incident_id, "Hook command succeeded");
+            Ok(())
+        })
+    }
+}
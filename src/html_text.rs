@@ -0,0 +1,89 @@
+//! Conversion of the HTML in portal texts to plain text, tolerant of malformed markup
+
+/// Tags whose content isn't text
+const SKIPPED_TAGS: &[&str] = &["script", "style"];
+
+/// Tags that start a new line
+const BLOCK_TAGS: &[&str] = &["br", "p", "div", "li", "ul", "ol", "tr", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote"];
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        "auml" => Some('ä'),
+        "ouml" => Some('ö'),
+        "uuml" => Some('ü'),
+        "Auml" => Some('Ä'),
+        "Ouml" => Some('Ö'),
+        "Uuml" => Some('Ü'),
+        "szlig" => Some('ß'),
+        "euro" => Some('€'),
+        _ => {
+            let number = entity.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// Name of a tag like `<br/>`, `</p>` or `<a href="...">`, lowercased
+fn tag_name(tag: &str) -> String {
+    tag.trim_start_matches('/')
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// Plain text of `html`: tags are removed, block elements become line breaks, entities are decoded
+/// and whitespace is collapsed. A `<` that doesn't start a tag is kept as text
+pub fn to_plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut skipping: Option<String> = None;
+    let mut rest = html;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            let starts_tag = rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!');
+            if let (true, Some(end)) = (starts_tag, rest.find('>')) {
+                let tag = &rest[1..end];
+                let name = tag_name(tag);
+                match &skipping {
+                    Some(skipped) if tag.starts_with('/') && name == *skipped => skipping = None,
+                    Some(_) => {}
+                    None if SKIPPED_TAGS.contains(&name.as_str()) && !tag.starts_with('/') && !tag.ends_with('/') => skipping = Some(name),
+                    None if BLOCK_TAGS.contains(&name.as_str()) => text.push('\n'),
+                    None => {}
+                }
+                rest = &rest[end + 1..];
+                continue;
+            }
+        }
+        if skipping.is_none() {
+            if c == '&' {
+                if let Some(end) = rest[1..].find(';').filter(|end| *end <= 10) {
+                    if let Some(decoded) = decode_entity(&rest[1..=end]) {
+                        text.push(decoded);
+                        rest = &rest[end + 2..];
+                        continue;
+                    }
+                }
+            }
+            text.push(c);
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,350 @@
+//! HTTP transport of the portal client, a trait so the fetch paths can be exercised against a fake
+//! portal instead of the network. [`Transport`] on top of `reqwest::Client` is the implementation used by the binary
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{StatusCode, Version};
+use std::future::Future;
+
+#[derive(Clone)]
+pub struct HttpRequest<'a> {
+    pub url: &'a str,
+    pub headers: Vec<(HeaderName, String)>,
+    /// Headers with secret values like credentials, marked sensitive and never logged
+    pub secret_headers: Vec<(HeaderName, String)>,
+    /// Stop reading bodies larger than this, see [`HttpResponse::body`]
+    pub max_body_bytes: Option<u64>,
+}
+
+impl<'a> HttpRequest<'a> {
+    pub fn get(url: &'a str) -> Self {
+        Self { url, headers: Vec::new(), secret_headers: Vec::new(), max_body_bytes: None }
+    }
+
+    pub fn header(mut self, name: HeaderName, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn secret_header(mut self, name: HeaderName, value: impl Into<String>) -> Self {
+        self.secret_headers.push((name, value.into()));
+        self
+    }
+
+    pub fn max_body_bytes(mut self, max_body_bytes: u64) -> Self {
+        self.max_body_bytes = Some(max_body_bytes);
+        self
+    }
+}
+
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub version: Version,
+    pub headers: HeaderMap,
+    /// `None` if the body exceeded `max_body_bytes` of the request
+    pub body: Option<Vec<u8>>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: HeaderName) -> Option<String> {
+        self.headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_owned)
+    }
+
+    pub fn text(self) -> Result<String> {
+        let body = self.body.context("Response body is too large")?;
+        String::from_utf8(body).context("Response body is not valid UTF-8")
+    }
+}
+
+pub trait HttpClient: Send + Sync {
+    fn get(&self, request: HttpRequest<'_>) -> impl Future<Output = Result<HttpResponse>> + Send;
+}
+
+impl HttpClient for reqwest::Client {
+    async fn get(&self, request: HttpRequest<'_>) -> Result<HttpResponse> {
+        let mut builder = reqwest::Client::get(self, request.url);
+        for (name, value) in request.headers {
+            builder = builder.header(name, value);
+        }
+        for (name, value) in request.secret_headers {
+            let mut value = HeaderValue::from_str(&value).with_context(|| format!("Invalid value of header {}", name))?;
+            value.set_sensitive(true);
+            builder = builder.header(name, value);
+        }
+        let mut response = builder.send().await.with_context(|| format!("Failed to send request to {}", request.url))?;
+
+        let status = response.status();
+        let version = response.version();
+        let headers = response.headers().clone();
+        let max = request.max_body_bytes.unwrap_or(u64::MAX);
+        if response.content_length().is_some_and(|length| length > max) {
+            return Ok(HttpResponse { status, version, headers, body: None });
+        }
+
+        // The announced length can't be trusted, so the limit is enforced while reading
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.with_context(|| format!("Failed to read response of {}", request.url))? {
+            body.extend_from_slice(&chunk);
+            if body.len() as u64 > max {
+                return Ok(HttpResponse { status, version, headers, body: None });
+            }
+        }
+        Ok(HttpResponse { status, version, headers, body: Some(body) })
+    }
+}
+
+/// Transport of the binary. With a pinned certificate requests to the portal host use a client
+/// trusting only that certificate, other hosts like those of attachments the regular one
+pub struct Transport {
+    client: reqwest::Client,
+    /// Host of the portal and its client, see `--pinned-cert`
+    pinned: Option<(String, reqwest::Client)>,
+}
+
+impl Transport {
+    pub fn new(client: reqwest::Client, pinned: Option<(String, reqwest::Client)>) -> Self {
+        Self { client, pinned }
+    }
+}
+
+/// Whether a connection failed because the certificate of the server didn't verify, told by the
+/// OpenSSL error in the source chain rather than by the message
+#[cfg(not(any(target_os = "windows", target_vendor = "apple")))]
+fn is_certificate_error(err: &anyhow::Error) -> bool {
+    // ERR_LIB_SSL and SSL_R_CERTIFICATE_VERIFY_FAILED
+    const LIB_SSL: std::ffi::c_int = 20;
+    const CERTIFICATE_VERIFY_FAILED: std::ffi::c_int = 134;
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<openssl::error::ErrorStack>())
+        .flat_map(openssl::error::ErrorStack::errors)
+        .any(|error| error.library_code() == LIB_SSL && error.reason_code() == CERTIFICATE_VERIFY_FAILED)
+}
+
+/// The TLS backends of these platforms don't expose why a handshake failed, pinning violations are
+/// reported as plain connection errors there
+#[cfg(any(target_os = "windows", target_vendor = "apple"))]
+fn is_certificate_error(_err: &anyhow::Error) -> bool {
+    false
+}
+
+impl HttpClient for Transport {
+    async fn get(&self, request: HttpRequest<'_>) -> Result<HttpResponse> {
+        let host = reqwest::Url::parse(request.url).ok().and_then(|url| url.host_str().map(str::to_owned));
+        match &self.pinned {
+            Some((pinned_host, client)) if host.as_deref() == Some(pinned_host.as_str()) => {
+                HttpClient::get(client, request).await.map_err(|err| {
+                    if is_certificate_error(&err) {
+                        err.context(format!("Pinning violation: the certificate of {} doesn't match --pinned-cert, the connection may be intercepted", pinned_host))
+                    } else {
+                        err
+                    }
+                })
+            }
+            _ => HttpClient::get(&self.client, request).await,
+        }
+    }
+}
+
+/// Parse a `Name: value` header given to `--auth-header`. The value is a secret, so it isn't part
+/// of the error
+pub fn parse_auth_header(header: &str) -> Result<(HeaderName, String), String> {
+    let (name, value) = header.split_once(':').ok_or("expected 'Name: value'")?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| format!("invalid header name '{}'", name.trim()))?;
+    let value = value.trim();
+    if value.is_empty() || HeaderValue::from_str(value).is_err() {
+        return Err(format!("invalid or empty value of header {}", name));
+    }
+    Ok((name, value.to_owned()))
+}
+
+/// Read `Name: value` headers from a file, one per line. Empty lines and lines starting with `#`
+/// are skipped
+pub fn read_auth_headers(path: &std::path::Path) -> Result<Vec<(HeaderName, String)>> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read auth headers {}", path.display()))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(index, line)| parse_auth_header(line).map_err(|err| anyhow::anyhow!("Line {} of {}: {}", index + 1, path.display(), err)))
+        .collect()
+}
+
+/// Redirect policy refusing to follow a redirect of a request to the authenticated `host` to
+/// another host, which would receive the auth headers. reqwest only strips its own list of
+/// sensitive headers on such redirects, not custom ones like `X-Api-Key`
+pub fn auth_redirect_policy(host: String) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        let from_host = attempt.previous().first().and_then(|url| url.host_str()) == Some(host.as_str());
+        if from_host && attempt.url().host_str() != Some(host.as_str()) {
+            let error = format!("Refusing to follow the redirect of an authenticated request to {} to another host", host);
+            attempt.error(error)
+        } else if attempt.previous().len() >= 10 {
+            attempt.error("too many redirects")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// IP family of outbound connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    /// Whatever the resolver returns, IPv6 and IPv4
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl std::fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IpFamily::Any => "IP",
+            IpFamily::Ipv4 => "IPv4",
+            IpFamily::Ipv6 => "IPv6",
+        })
+    }
+}
+
+impl IpFamily {
+    fn allows(self, address: &std::net::SocketAddr) -> bool {
+        match self {
+            IpFamily::Any => true,
+            IpFamily::Ipv4 => address.is_ipv4(),
+            IpFamily::Ipv6 => address.is_ipv6(),
+        }
+    }
+}
+
+/// Resolver that only returns addresses of one IP family, so connections can't fall back to the
+/// other one
+pub struct FamilyResolver {
+    pub family: IpFamily,
+}
+
+impl reqwest::dns::Resolve for FamilyResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let family = self.family;
+        Box::pin(async move {
+            let host = name.as_str().to_owned();
+            let addresses = tokio::task::spawn_blocking(move || std::net::ToSocketAddrs::to_socket_addrs(&(host.as_str(), 0)))
+                .await
+                .map_err(|err| Box::new(err) as Box<dyn std::error::Error + Send + Sync>)??;
+            let addresses: Vec<std::net::SocketAddr> = addresses.filter(|address| family.allows(address)).collect();
+            if addresses.is_empty() {
+                return Err(format!("{} has no {} address", name.as_str(), family).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Routing of all requests through the SOCKS5 proxy of a local Tor client
+#[derive(Debug, Clone, Copy)]
+pub struct TorSettings {
+    pub proxy: std::net::SocketAddr,
+    /// Use a separate Tor circuit for every request
+    pub isolate: bool,
+}
+
+impl TorSettings {
+    /// Fail with a clear error if nothing listens on the proxy address, instead of every request failing
+    pub fn check_reachable(&self) -> Result<()> {
+        std::net::TcpStream::connect_timeout(&self.proxy, std::time::Duration::from_secs(5))
+            .with_context(|| format!("Tor SOCKS proxy at {} isn't reachable, is Tor running?", self.proxy))?;
+        Ok(())
+    }
+
+    /// `socks5h` so host names are resolved by Tor and don't leak to the local resolver. With
+    /// isolation every request uses random SOCKS credentials, which Tor maps to separate circuits
+    pub fn proxy(&self) -> Result<reqwest::Proxy> {
+        if !self.isolate {
+            return reqwest::Proxy::all(format!("socks5h://{}", self.proxy)).context("Invalid Tor proxy address");
+        }
+        let proxy = self.proxy;
+        Ok(reqwest::Proxy::custom(move |_| reqwest::Url::parse(&format!("socks5h://{:016x}:x@{}", rand::random::<u64>(), proxy)).ok()))
+    }
+}
+
+/// Fake portal for tests, answering from canned responses by URL and recording the requests
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// A request received by [`FakePortal`]
+    pub struct Received {
+        pub url: String,
+        /// Including the secret headers
+        pub headers: Vec<(HeaderName, String)>,
+    }
+
+    #[derive(Default)]
+    pub struct FakePortal {
+        responses: HashMap<String, (StatusCode, String)>,
+        pub requests: Mutex<Vec<Received>>,
+    }
+
+    impl FakePortal {
+        pub fn respond(mut self, url: impl Into<String>, status: StatusCode, body: impl Into<String>) -> Self {
+            self.responses.insert(url.into(), (status, body.into()));
+            self
+        }
+
+        /// Value of a header of the request to `url`
+        pub fn request_header(&self, url: &str, name: &HeaderName) -> Option<String> {
+            let requests = self.requests.lock().unwrap();
+            let request = requests.iter().find(|request| request.url == url)?;
+            request.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.clone())
+        }
+    }
+
+    impl HttpClient for FakePortal {
+        async fn get(&self, request: HttpRequest<'_>) -> Result<HttpResponse> {
+            let headers = request.headers.iter().chain(&request.secret_headers).cloned().collect();
+            self.requests.lock().unwrap().push(Received { url: request.url.to_owned(), headers });
+            let (status, body) = self.responses.get(request.url).with_context(|| format!("Failed to send request to {}", request.url))?;
+            Ok(HttpResponse { status: *status, version: Version::HTTP_11, headers: HeaderMap::new(), body: Some(body.clone().into_bytes()) })
+        }
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "windows", target_vendor = "apple"))))]
+mod tests {
+    use super::*;
+    use openssl::pkey::PKey;
+    use openssl::ssl::{SslAcceptor, SslMethod};
+    use openssl::x509::X509;
+
+    /// Port of a server presenting `fixtures/tls/portal.pem` to one connection
+    fn tls_server() -> u16 {
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&PKey::private_key_from_pem(include_bytes!("../fixtures/tls/portal.key")).unwrap()).unwrap();
+        acceptor.set_certificate(&X509::from_pem(include_bytes!("../fixtures/tls/portal.pem")).unwrap()).unwrap();
+        let acceptor = acceptor.build();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            // Fails as the client rejects the certificate
+            let _ = acceptor.accept(stream);
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn certificate_not_matching_the_pin_is_a_pinning_violation() {
+        let interceptor = reqwest::Certificate::from_pem(include_bytes!("../fixtures/tls/interceptor.pem")).unwrap();
+        let pinned = reqwest::Client::builder().tls_built_in_root_certs(false).add_root_certificate(interceptor).build().unwrap();
+        let transport = Transport::new(reqwest::Client::new(), Some(("localhost".to_owned(), pinned)));
+
+        let url = format!("https://localhost:{}/", tls_server());
+        let err = transport.get(HttpRequest::get(&url)).await.err().unwrap();
+        assert!(format!("{:#}", err).starts_with("Pinning violation"), "{:#}", err);
+    }
+
+    #[test]
+    fn certificate_in_a_message_is_no_certificate_error() {
+        assert!(!is_certificate_error(&anyhow::anyhow!("Portal answered: client certificate required")));
+    }
+}
@@ -0,0 +1,149 @@
+//! Row-level HMACs of the stored incidents, so rows altered out of band can be detected with
+//! `verify-hash`. The key is never logged
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{info, trace, warn};
+
+/// Columns of `incidents` covered by the HMAC. A fixed list, so columns added later don't invalidate
+/// the stored HMACs. Derived columns and the fetch time that changes on every re-sync aren't signed
+const SIGNED_COLUMNS: &[&str] = &[
+    "incident_id", "org_publish_date", "modified_date", "published", "publish_date", "affected_obj", "affected_type",
+    "country", "details_text", "tags", "href", "references", "incident_text", "incident_text_plain", "details_text_plain",
+    "incident_text_original_bytes", "details_text_original_bytes",
+];
+
+/// Canonical serialization of a row as read via `to_jsonb`: the signed columns as JSON object with
+/// sorted keys
+fn canonical(row: &serde_json::Value) -> String {
+    let signed: serde_json::Map<String, serde_json::Value> = SIGNED_COLUMNS
+        .iter()
+        .map(|column| (column.to_string(), row.get(column).cloned().unwrap_or_default()))
+        .collect();
+    serde_json::Value::Object(signed).to_string()
+}
+
+pub struct RowHmac {
+    key: String,
+}
+
+impl std::fmt::Debug for RowHmac {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RowHmac { key: *** }")
+    }
+}
+
+impl RowHmac {
+    pub fn new(key: String) -> Result<Self> {
+        if key.is_empty() {
+            anyhow::bail!("Row HMAC key is empty");
+        }
+        Ok(Self { key })
+    }
+
+    fn mac(&self, row: &serde_json::Value) -> Result<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_bytes()).context("Failed to create HMAC")?;
+        mac.update(canonical(row).as_bytes());
+        Ok(mac)
+    }
+
+    /// HMAC-SHA256 of the canonical serialization of a row
+    pub fn sign(&self, row: &serde_json::Value) -> Result<String> {
+        Ok(hex::encode(self.mac(row)?.finalize().into_bytes()))
+    }
+
+    pub fn verify(&self, row: &serde_json::Value, hmac: &str) -> Result<bool> {
+        let mac = self.mac(row)?;
+        let Ok(expected) = hex::decode(hmac) else {
+            return Ok(false);
+        };
+        Ok(mac.verify_slice(&expected).is_ok())
+    }
+}
+
+/// Store the HMAC of the row of an incident as it is after the store
+pub async fn store_row_hmac(tx: &mut sqlx::PgConnection, row_hmac: &RowHmac, incident_id: i32, row: &serde_json::Value) -> Result<()> {
+    sqlx::query("UPDATE incidents SET row_hmac = $2 WHERE incident_id = $1")
+        .bind(incident_id)
+        .bind(row_hmac.sign(row)?)
+        .execute(tx)
+        .await
+        .with_context(|| format!("Failed to store row HMAC of incident {}", incident_id))?;
+    Ok(())
+}
+
+/// Recompute the HMACs of all stored incidents and print the ones that don't match as
+/// `MISMATCH <id>` and the ones without HMAC as `UNSIGNED <id>`. Fails if any row doesn't match
+pub async fn verify_hashes(pool: &sqlx::PgPool, row_hmac: &RowHmac) -> Result<()> {
+    trace!("Fetching incidents to verify");
+    let rows: Vec<(i32, Option<String>, serde_json::Value)> = sqlx::query_as(
+        "SELECT incident_id, row_hmac, to_jsonb(incidents) FROM incidents ORDER BY incident_id",
+    )
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch incidents to verify")?;
+
+    let (mut verified, mut mismatched, mut unsigned) = (0, 0, 0);
+    for (incident_id, hmac, row) in &rows {
+        match hmac {
+            Some(hmac) if row_hmac.verify(row, hmac)? => verified += 1,
+            Some(_) => {
+                println!("MISMATCH {}", incident_id);
+                mismatched += 1;
+            }
+            None => {
+                println!("UNSIGNED {}", incident_id);
+                unsigned += 1;
+            }
+        }
+    }
+
+    println!("Verified {} incidents: {} match, {} mismatch, {} unsigned", rows.len(), verified, mismatched, unsigned);
+    info!(verified, mismatched, unsigned, "Verified row HMACs");
+    if unsigned > 0 {
+        warn!("{} incidents have no row HMAC, they were stored without --row-hmac-key", unsigned);
+    }
+    if mismatched > 0 {
+        anyhow::bail!("{} incidents don't match their row HMAC, they were altered or signed with another key", mismatched);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn row() -> serde_json::Value {
+        json!({
+            "incident_id": 1,
+            "published": 1,
+            "details_text": "Unbefugter Zugriff",
+            "fetched_at": "2024-03-01T12:00:00+00:00",
+        })
+    }
+
+    #[test]
+    fn detects_altered_rows() {
+        let row_hmac = RowHmac::new("key".to_owned()).unwrap();
+        let mut row = row();
+        let hmac = row_hmac.sign(&row).unwrap();
+        assert!(row_hmac.verify(&row, &hmac).unwrap());
+
+        row["fetched_at"] = json!("2025-01-01T00:00:00+00:00");
+        assert!(row_hmac.verify(&row, &hmac).unwrap(), "fetched_at isn't signed");
+
+        row["details_text"] = json!("Geändert");
+        assert!(!row_hmac.verify(&row, &hmac).unwrap());
+    }
+
+    #[test]
+    fn rejects_other_keys_and_invalid_hmacs() {
+        let hmac = RowHmac::new("key".to_owned()).unwrap().sign(&row()).unwrap();
+        let other = RowHmac::new("other".to_owned()).unwrap();
+        assert!(!other.verify(&row(), &hmac).unwrap());
+        assert!(!other.verify(&row(), "not hex").unwrap());
+        assert!(RowHmac::new(String::new()).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Sink publishing stored incidents to a Kafka topic, see `--kafka-brokers`. Incidents are
+//! buffered until the sinks are flushed and kept while the brokers are unavailable, so an outage
+//! of Kafka doesn't fail the run
+
+use crate::model::{Incident, IncidentDetail};
+use crate::sink::{RawResponse, Sink};
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+use rskafka::client::ClientBuilder;
+use rskafka::record::Record;
+use rskafka::BackoffConfig;
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Incidents kept while the brokers are unavailable, older ones are dropped beyond this
+const MAX_PENDING: usize = 10_000;
+
+/// Time to keep retrying to reach the brokers per flush before giving up until the next one
+const CONNECT_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Time after a failure before publishing is attempted again, so an outage doesn't slow down every incident
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct State {
+    /// Connected on the first flush and again after a failure
+    client: Option<PartitionClient>,
+    /// Records not published yet
+    pending: Vec<Record>,
+    /// No attempt to publish is made before this after a failure
+    retry_at: Option<Instant>,
+}
+
+/// Every stored incident as a JSON message of `{"incident": ..., "details": [...]}` keyed by the
+/// incident id, published to partition 0 of the topic
+pub struct KafkaSink {
+    brokers: Vec<String>,
+    topic: String,
+    state: Mutex<State>,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: Vec<String>, topic: String) -> Self {
+        Self { brokers, topic, state: Mutex::new(State::default()) }
+    }
+
+    async fn connect(&self) -> Result<PartitionClient> {
+        let backoff = BackoffConfig { deadline: Some(CONNECT_DEADLINE), ..BackoffConfig::default() };
+        let client = ClientBuilder::new(self.brokers.clone())
+            .client_id("dsgvo-downloader")
+            .backoff_config(backoff)
+            .build()
+            .await
+            .with_context(|| format!("Failed to connect to Kafka brokers {}", self.brokers.join(",")))?;
+        client
+            .partition_client(self.topic.as_str(), 0, UnknownTopicHandling::Error)
+            .await
+            .with_context(|| format!("Failed to open Kafka topic {}", self.topic))
+    }
+
+    /// Publish the buffered incidents. A failure is only logged, the incidents stay buffered. After
+    /// a failure no attempt is made for [`RETRY_INTERVAL`] unless `force` is set
+    async fn publish(&self, force: bool) {
+        let mut state = self.state.lock().await;
+        if state.pending.is_empty() || (!force && state.retry_at.is_some_and(|retry_at| Instant::now() < retry_at)) {
+            return;
+        }
+        let result = async {
+            if state.client.is_none() {
+                state.client = Some(self.connect().await?);
+            }
+            let client = state.client.as_ref().context("Kafka client isn't connected")?;
+            client
+                .produce(state.pending.clone(), Compression::NoCompression)
+                .await
+                .with_context(|| format!("Failed to publish to Kafka topic {}", self.topic))
+        }
+            .await;
+        match result {
+            Ok(_) => {
+                debug!("Published {} incidents to Kafka topic {}", state.pending.len(), self.topic);
+                state.pending.clear();
+                state.retry_at = None;
+            }
+            Err(err) => {
+                warn!("Keeping {} incidents buffered for Kafka: {:#}", state.pending.len(), err);
+                state.client = None;
+                state.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+impl Sink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn existing_ids(&self) -> BoxFuture<'_, Result<Option<HashSet<i32>>>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn store_raw_response<'a>(&'a self, _response: &'a RawResponse<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn store_incident<'a>(&'a self, incident: &'a Incident, details: &'a [IncidentDetail]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let value = serde_json::to_vec(&serde_json::json!({ "incident": incident, "details": details })).context("Failed to serialize incident")?;
+            let record = Record {
+                key: Some(incident.incident_id.to_string().into_bytes()),
+                value: Some(value),
+                headers: BTreeMap::new(),
+                timestamp: chrono::Utc::now(),
+            };
+            let mut state = self.state.lock().await;
+            state.pending.push(record);
+            if state.pending.len() > MAX_PENDING {
+                let dropped = state.pending.len() - MAX_PENDING;
+                state.pending.drain(..dropped);
+                warn!("Kafka is unavailable, dropped {} buffered incidents", dropped);
+            }
+            Ok(())
+        })
+    }
+
+    fn discard<'a>(&'a self, incident: &'a Incident) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let key = incident.incident_id.to_string().into_bytes();
+            let mut state = self.state.lock().await;
+            if state.pending.last().is_some_and(|record| record.key.as_ref() == Some(&key)) {
+                state.pending.pop();
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            self.publish(false).await;
+            Ok(())
+        })
+    }
+
+    /// A last attempt regardless of a recent failure, the incidents are lost once the process exits
+    fn close(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            self.publish(true).await;
+            let dropped = self.state.lock().await.pending.len();
+            if dropped > 0 {
+                warn!("Dropping {} incidents that couldn't be published to Kafka topic {}", dropped, self.topic);
+            }
+            Ok(())
+        })
+    }
+}
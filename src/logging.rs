@@ -0,0 +1,82 @@
+//! Output of `tracing` events and `log` records of dependencies, filtered via `RUST_LOG`
+//! and formatted by `tracing_subscriber` either as text or as JSON lines including the fields of events and spans
+
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, Layer};
+
+/// Output format of the logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Whether free texts that may contain personal data are replaced in logs
+static REDACT: AtomicBool = AtomicBool::new(false);
+
+/// Replace free texts wrapped in [`Sensitive`] by a length/hash placeholder in all logs
+pub fn set_redact(redact: bool) {
+    REDACT.store(redact, Ordering::Relaxed);
+}
+
+/// Free text that may contain personal data, e.g. incident texts, formatted as a placeholder
+/// with its length and a hash prefix if `--redact-logs` is set
+pub struct Sensitive<'a>(pub &'a str);
+
+impl Sensitive<'_> {
+    fn placeholder(&self) -> Option<String> {
+        if !REDACT.load(Ordering::Relaxed) {
+            return None;
+        }
+        let hash = hex::encode(Sha256::digest(self.0.as_bytes()));
+        Some(format!("<redacted {} bytes, sha256 {}>", self.0.len(), &hash[..12]))
+    }
+}
+
+impl std::fmt::Display for Sensitive<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.placeholder() {
+            Some(placeholder) => f.write_str(&placeholder),
+            None => f.write_str(self.0),
+        }
+    }
+}
+
+impl std::fmt::Debug for Sensitive<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.placeholder() {
+            Some(placeholder) => f.write_str(&placeholder),
+            None => write!(f, "{:?}", self.0),
+        }
+    }
+}
+
+/// Colors are used when writing to a terminal, unless disabled via `--no-color` or the `NO_COLOR` env var
+pub fn use_color(no_color: bool) -> bool {
+    use std::io::IsTerminal;
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    !no_color && !no_color_env && std::io::stderr().is_terminal()
+}
+
+/// Write `tracing` events and `log` records of dependencies to stderr, including the fields of the spans they
+/// occurred in. `Sensitive` values are redacted by their own formatting, so this holds for both formats
+pub fn setup_logger(format: LogFormat, color: bool) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy()
+        .add_directive("dsgvo_downloader=trace".parse().expect("valid log directive"));
+
+    let layer = fmt::layer().with_writer(std::io::stderr).with_timer(fmt::time::ChronoUtc::rfc_3339());
+    let layer = match format {
+        LogFormat::Text => layer.with_ansi(color).boxed(),
+        LogFormat::Json => layer.json().with_current_span(false).with_span_list(true).boxed(),
+    };
+
+    // Also installs the bridge forwarding `log` records, e.g. of dependencies not using `tracing` yet
+    if tracing_subscriber::registry().with(filter).with(layer).try_init().is_err() {
+        eprintln!("Logger was already initialized");
+    }
+}
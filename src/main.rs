@@ -1,94 +1,283 @@
-use std::collections::HashSet;
+mod attachments;
+mod audit;
+mod confirm;
+mod db;
+mod detail_cache;
+mod export;
+mod field_mapping;
+mod history;
+mod hooks;
+mod html_text;
+mod http;
+mod integrity;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod logging;
+mod manifest;
+mod model;
+mod pacing;
+mod profile;
+mod partitioning;
+#[cfg(feature = "parquet")]
+mod parquet_export;
+mod refetch;
+mod report;
+mod retry;
+mod run_config;
+mod schema_validation;
+mod search;
+mod self_test;
+#[cfg(feature = "simulate-errors")]
+mod simulate;
+mod sink;
+mod statsd;
+mod telemetry;
+
+use std::collections::{HashMap, HashSet};
 use anyhow::{Context, Result};
-use chrono::{NaiveDate, NaiveDateTime, Utc};
-use log::{debug, info, trace, LevelFilter};
-use serde::{Deserialize, Deserializer, Serialize};
-use sqlx::postgres::PgPoolOptions;
+use chrono::Datelike;
+use tracing::{debug, error, info, trace, warn};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
 use std::time::Duration;
-use std::io::Write;
 use clap::value_parser;
+use futures_util::{stream, Stream, StreamExt};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use model::{Incident, IncidentDetail};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Incident {
-    #[serde(rename = "incidentID")]
-    incident_id: i32,
-    #[serde(rename = "orgPublishDate")]
-    org_publish_date: NaiveDate,
-    #[serde(deserialize_with = "parse_naive_datetime")]
-    #[serde(rename = "modifiedDate")]
-    modified_date: NaiveDateTime,
-    published: i32,
-    country: String,
-    #[serde(rename = "incidentText")]
-    incident_text: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct IncidentDetail {
-    #[serde(rename = "publishDate")]
-    publish_date: NaiveDate,
-    #[serde(rename = "affectedObj")]
-    affected_obj: String,
-    #[serde(rename = "affectedType")]
-    affected_type: String,
-    #[serde(rename = "description_de")]
-    details_text: String,
-    tags: String,
-    href: String,
-    reference: String,
-}
-
-fn parse_naive_datetime<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    NaiveDateTime::parse_from_str(&s, "%Y-%m-%d %H:%M:%S")
-        .map_err(|e| serde::de::Error::custom(format!("Failed to parse datetime '{}': {}", s, e)))
-}
-
-fn setup_logger() {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .format(|buf, record| {
-            let timestamp = Utc::now().to_rfc3339();
-            writeln!(
-                buf,
-                "{} [{}] {}: {}",
-                timestamp,
-                record.target(),
-                record.level(),
-                record.args()
-            )
-        })
-        .filter_module("dsgvo_downloader", LevelFilter::Trace)
-        .init();
+// Debug is implemented by hand so the free texts honour `--redact-logs`
+impl std::fmt::Debug for Incident {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Incident")
+            .field("incident_id", &self.incident_id)
+            .field("org_publish_date", &self.org_publish_date)
+            .field("modified_date", &self.modified_date)
+            .field("published", &self.published)
+            .field("country", &self.country)
+            .field("incident_text", &logging::Sensitive(&self.incident_text))
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for IncidentDetail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IncidentDetail")
+            .field("publish_date", &self.publish_date)
+            .field("affected_obj", &self.affected_obj)
+            .field("affected_type", &self.affected_type)
+            .field("details_text", &logging::Sensitive(&self.details_text))
+            .field("tags", &self.tags)
+            .field("href", &self.href)
+            .field("reference", &self.reference)
+            .finish()
+    }
+}
+
+/// Connection recycling of the database pool, so connections reaped by the server while idle are replaced transparently
+struct PoolSettings {
+    idle_timeout: Duration,
+    max_lifetime: Duration,
+    test_before_acquire: bool,
+    /// Postgres `statement_timeout` of every connection, so a stuck query fails instead of stalling the run
+    statement_timeout: Option<Duration>,
+    /// Postgres schema of the tables instead of `public`, set as `search_path` of every connection
+    schema: Option<String>,
+}
+
+/// Validate a `--db-schema` name. Only unquoted lowercase identifiers are accepted, so the name can
+/// be used in `search_path` as is and matches the catalog without quoting
+fn parse_schema_name(value: &str) -> Result<String, String> {
+    let valid = value.len() <= 63
+        && value.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && value.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid {
+        return Err("expected a lowercase identifier of at most 63 letters, digits and underscores, starting with a letter or underscore".to_owned());
+    }
+    Ok(value.to_owned())
+}
+
+/// Database url with the password masked, for logging
+fn mask_database_url(database_url: &str) -> String {
+    match reqwest::Url::parse(database_url) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("***"));
+            url.to_string()
+        }
+        Ok(url) => url.to_string(),
+        Err(_) => "<invalid url>".to_owned(),
+    }
+}
+
+/// Read a database password from a secrets file, as mounted by Docker or Kubernetes
+fn read_password_file(path: &std::path::Path) -> Result<String> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read database password file {}", path.display()))?;
+    Ok(content.trim_end_matches(['\r', '\n']).to_owned())
 }
 
-async fn setup_database(database_url: &str) -> Result<sqlx::PgPool> {
+async fn setup_database(database_url: &str, password_file: Option<&std::path::Path>, settings: &PoolSettings) -> Result<sqlx::PgPool> {
     trace!("Setting up database");
-    debug!("Using database url: {}", database_url);
+    debug!("Using database url: {}", mask_database_url(database_url));
+    debug!("Using idle timeout {:?}, max lifetime {:?}, test before acquire {}", settings.idle_timeout, settings.max_lifetime, settings.test_before_acquire);
+
+    let mut connect_options: PgConnectOptions = database_url.parse().context("Invalid database url")?;
+    if let Some(path) = password_file {
+        debug!("Using database password from {}", path.display());
+        connect_options = connect_options.password(&read_password_file(path)?);
+    }
+    if let Some(timeout) = settings.statement_timeout {
+        debug!("Using statement timeout {:?}", timeout);
+        connect_options = connect_options.options([("statement_timeout", format!("{}ms", timeout.as_millis()))]);
+    }
+    if let Some(schema) = &settings.schema {
+        debug!("Using schema {}", schema);
+        connect_options = connect_options.options([("search_path", schema.as_str())]);
+    }
 
-    PgPoolOptions::new()
+    let pool = PgPoolOptions::new()
         .max_connections(5)
-        .connect(&database_url)
+        .idle_timeout(settings.idle_timeout)
+        .max_lifetime(settings.max_lifetime)
+        .test_before_acquire(settings.test_before_acquire)
+        .connect_with(connect_options)
+        .await
+        .context("Failed to connect to database")?;
+
+    if let Some(schema) = &settings.schema {
+        let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM information_schema.schemata WHERE schema_name = $1)")
+            .bind(schema)
+            .fetch_one(&pool)
+            .await
+            .context("Failed to check for database schema")?;
+        if !exists {
+            anyhow::bail!("Database schema {} doesn't exist, create it with CREATE SCHEMA {}", schema, schema);
+        }
+    }
+    Ok(pool)
+}
+
+/// Embedded migrations, each one is applied once in order and has to be idempotent,
+/// so databases created from `schema.sql` before versioning existed can be migrated as well
+const MIGRATIONS: &[(i32, &str)] = &[
+    (1, include_str!("migrations/0001_initial.sql")),
+    (2, include_str!("migrations/0002_failed_incidents.sql")),
+    (3, include_str!("migrations/0003_incident_revisions.sql")),
+    (4, include_str!("migrations/0004_incident_history_validators.sql")),
+    (5, include_str!("migrations/0005_incident_search.sql")),
+    (6, include_str!("migrations/0006_incident_details.sql")),
+    (7, include_str!("migrations/0007_incident_history_latest.sql")),
+    (8, include_str!("migrations/0008_incident_is_published.sql")),
+    (9, include_str!("migrations/0009_incident_history_raw_text.sql")),
+    (10, include_str!("migrations/0010_incident_attachments.sql")),
+    (11, include_str!("migrations/0011_detail_history.sql")),
+    (12, include_str!("migrations/0012_incident_details_nullable.sql")),
+    (13, include_str!("migrations/0013_incident_plain_text.sql")),
+    (14, include_str!("migrations/0014_incident_history_idempotency_key.sql")),
+    (15, include_str!("migrations/0015_detail_history_validators.sql")),
+    (16, include_str!("migrations/0016_incident_fetched_at.sql")),
+    (17, include_str!("migrations/0017_incident_text_truncation.sql")),
+    (18, include_str!("migrations/0018_incident_row_hmac.sql")),
+    (19, include_str!("migrations/0019_run_config.sql")),
+    (20, include_str!("migrations/0020_incident_history_compressed.sql")),
+    (21, include_str!("migrations/0021_export_watermarks.sql")),
+    (22, include_str!("migrations/0022_incident_unpublished_at.sql")),
+];
+
+/// Full schema at the latest version, for setting up a new database
+const SCHEMA: &str = include_str!("schema.sql");
+
+/// Schema version this binary expects
+const SCHEMA_VERSION: i32 = MIGRATIONS[MIGRATIONS.len() - 1].0;
+
+/// Get the current schema version, 0 if the database is not versioned yet
+async fn get_schema_version(pool: &sqlx::PgPool) -> Result<i32> {
+    trace!("Getting schema version");
+    let versioned: bool = sqlx::query_scalar("SELECT to_regclass('schema_version') IS NOT NULL")
+        .fetch_one(pool)
         .await
-        .context("Failed to connect to database")
+        .context("Failed to check for schema_version table")?;
+    if !versioned {
+        return Ok(0);
+    }
+
+    sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM schema_version")
+        .fetch_one(pool)
+        .await
+        .context("Failed to fetch schema version")
+}
+
+/// Apply all outstanding migrations, each one in its own transaction
+async fn run_migrations(pool: &sqlx::PgPool) -> Result<()> {
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            applied_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+        )"#,
+    )
+        .execute(pool)
+        .await
+        .context("Failed to create schema_version table")?;
+
+    let current = get_schema_version(pool).await?;
+    info!("Database is at schema version {}, binary expects {}", current, SCHEMA_VERSION);
+
+    for (version, sql) in MIGRATIONS.iter().filter(|(version, _)| *version > current) {
+        info!("Applying migration {}", version);
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
+        sqlx::raw_sql(sql)
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to apply migration {}", version))?;
+        sqlx::query("INSERT INTO schema_version (version) VALUES ($1)")
+            .bind(version)
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to record migration {}", version))?;
+        tx.commit().await.with_context(|| format!("Failed to commit migration {}", version))?;
+    }
+    Ok(())
 }
 
-async fn verify_tables(pool: &sqlx::PgPool) -> Result<()> {
+/// Tables that have to be created via `schema.sql` before running
+const REQUIRED_TABLES: &[&str] = &["incidents", "incident_history", "failed_incidents", "incident_revisions", "incident_details", "incident_attachments", "detail_history"];
+
+/// `incident_history` is only required if raw snapshots are stored and `require_raw_store` is set,
+/// otherwise a missing table is logged and runs continue without snapshots
+async fn verify_tables(pool: &sqlx::PgPool, raw_store: bool, require_raw_store: bool) -> Result<()> {
+    trace!("Verifying schema version");
+    let version = get_schema_version(pool).await?;
+    if version < SCHEMA_VERSION {
+        anyhow::bail!("Database schema version {} is older than the expected version {}, run the `migrate` subcommand or pass --auto-migrate", version, SCHEMA_VERSION);
+    }
+    if version > SCHEMA_VERSION {
+        anyhow::bail!("Database schema version {} is newer than the expected version {}, update the binary", version, SCHEMA_VERSION);
+    }
+
     trace!("Verifying tables in database");
+    let required: Vec<&str> = REQUIRED_TABLES
+        .iter()
+        .copied()
+        .filter(|table| raw_store || *table != "incident_history")
+        .collect();
     let tables: Vec<String> = sqlx::query_scalar(
         r#"SELECT table_name FROM information_schema.tables
-           WHERE table_schema = 'public'
-           AND table_name IN ('incidents', 'incident_history')"#,
+           WHERE table_schema = current_schema()
+           AND table_name = ANY($1)"#,
     )
+        .bind(&required)
         .fetch_all(pool)
         .await
         .context("Failed to verify tables")?;
 
-    debug!("Found {} tables in database: {:?}, expected to be present: incidents & incident_history", tables.len(), tables);
+    debug!("Found {} tables in database: {:?}, expected to be present: {:?}", tables.len(), tables, required);
+    if partitioning::is_partitioned(pool).await? {
+        debug!("incidents is partitioned by year");
+    }
 
-    if tables.len() != 2 {
+    let missing: Vec<&str> = required.iter().copied().filter(|table| !tables.iter().any(|found| found == table)).collect();
+    if missing == ["incident_history"] && !require_raw_store {
+        warn!("Table incident_history is missing, raw responses can't be stored");
+    } else if !missing.is_empty() {
         anyhow::bail!("Missing required database tables");
     }
     Ok(())
@@ -105,178 +294,3583 @@ async fn get_existing_incident_ids(pool: &sqlx::PgPool) -> Result<HashSet<i32>>
     Ok(ids.into_iter().collect())
 }
 
-/// Fetch incidents from the website
-async fn fetch_incidents(pool: &sqlx::PgPool) -> Result<Vec<Incident>> {
-    info!("Fetching incidents from website");
-    let client = reqwest::Client::new();
-    let response = client
-        .get("https://www.dsgvo-portal.de/sicherheitsvorfall-datenbank/?cmd=getIncidents")
-        .header("Accept", "application/json")
-        .header("Referer", "https://www.dsgvo-portal.de/sicherheitsvorfall-datenbank/")
-        .send()
+/// Ids of stored incidents last fetched longer than `max_age` ago or before fetch times were recorded
+async fn get_stale_incident_ids(pool: &sqlx::PgPool, max_age: Duration) -> Result<HashSet<i32>> {
+    trace!("Getting incidents fetched longer than {:?} ago", max_age);
+    let ids: Vec<i32> = sqlx::query_scalar(
+        "SELECT incident_id FROM incidents WHERE fetched_at IS NULL OR fetched_at < CURRENT_TIMESTAMP - make_interval(secs => $1)",
+    )
+        .bind(max_age.as_secs_f64())
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch stale incidents")?;
+    Ok(ids.into_iter().collect())
+}
+
+/// Ids of stored incidents without details, left by `--disable-detail-fetch` or an interrupted run
+async fn get_incomplete_incident_ids(pool: &sqlx::PgPool) -> Result<HashSet<i32>> {
+    trace!("Getting incidents stored without details");
+    let ids: Vec<i32> = sqlx::query_scalar(
+        "SELECT incident_id FROM incidents i WHERE publish_date IS NULL OR NOT EXISTS (SELECT 1 FROM incident_details d WHERE d.incident_id = i.incident_id)",
+    )
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch incomplete incidents")?;
+    Ok(ids.into_iter().collect())
+}
+
+/// Fetch the modified dates of all stored incidents
+async fn get_stored_modified_dates(pool: &sqlx::PgPool) -> Result<HashMap<i32, chrono::NaiveDateTime>> {
+    trace!("Getting modified dates of stored incidents");
+    // Connections use UTC, matching how the naive modified dates were stored
+    let rows: Vec<(i32, chrono::NaiveDateTime)> = sqlx::query_as("SELECT incident_id, modified_date::timestamp FROM incidents")
+        .fetch_all(pool)
         .await
-        .context("Failed to fetch incidents")?;
-    trace!("Got cmd response: {}, getting body", response.status());
-    let body = response.text().await.context("Failed to read response body")?;
+        .context("Failed to fetch modified dates of stored incidents")?;
+    Ok(rows.into_iter().collect())
+}
+
+/// URLs of the portal, composed from a base url
+struct Endpoints {
+    base_url: String,
+    profile: profile::PortalProfile,
+}
+
+impl Endpoints {
+    /// Endpoints of `profile`, `base_url` overrides the base URL of the profile
+    fn new(profile: profile::PortalProfile, base_url: Option<&str>) -> Self {
+        let base_url = base_url.unwrap_or(&profile.base_url).trim_end_matches('/').to_owned();
+        Self { base_url, profile }
+    }
+
+    fn incidents(&self) -> String {
+        format!("{}{}", self.base_url, self.profile.incidents_path)
+    }
+
+    fn incidents_referer(&self) -> String {
+        format!("{}{}", self.base_url, self.profile.incidents_referer_path)
+    }
+
+    fn incident_detail(&self, incident_id: i32) -> String {
+        format!("{}{}", self.base_url, self.profile.incident_detail_path.replace("{id}", &incident_id.to_string()))
+    }
+
+    fn incident_detail_referer(&self) -> String {
+        format!("{}{}", self.base_url, self.profile.incident_detail_referer_path)
+    }
+
+    /// Host of the base URL
+    fn host(&self) -> Result<String> {
+        let url = reqwest::Url::parse(&self.base_url).with_context(|| format!("Invalid base URL {}", self.base_url))?;
+        url.host_str().map(str::to_owned).with_context(|| format!("Base URL {} has no host", self.base_url))
+    }
+}
+
+/// Connection pooling of the http client, so connections the portal closed while idle, e.g.
+/// between watch cycles, aren't reused
+#[derive(Debug, Clone, Copy)]
+struct HttpPoolSettings {
+    idle_timeout: Duration,
+    max_idle_per_host: usize,
+}
+
+/// HTTP version used to talk to the portal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HttpVersion {
+    /// HTTP/2 if negotiated via ALPN, HTTP/1.1 otherwise
+    Auto,
+    Http1,
+    /// HTTP/2 with prior knowledge, fails against HTTP/1.1-only servers
+    Http2,
+}
+
+/// HTTP client for the portal together with the pacing of its requests
+struct PortalClient<H = http::Transport> {
+    http: H,
+    pacers: pacing::HostPacers,
+    retry: retry::RetryPolicy,
+    /// Host of the portal, only requests to it are authenticated
+    portal_host: Option<String>,
+    /// See `--auth-header` and `--bearer-token`
+    auth_headers: Vec<(reqwest::header::HeaderName, String)>,
+}
+
+impl PortalClient {
+    fn new(options: &RunOptions) -> Result<Self> {
+        let auth_host = if options.auth_headers.is_empty() { None } else { Some(options.endpoints.host()?) };
+        let client = build_client(options.http_version, options.http_pool, options.bind_address, options.ip_family, options.tor, None, auth_host.as_deref())?;
+        let pinned = match &options.pinned_cert {
+            Some(cert) => Some((
+                options.endpoints.host()?,
+                build_client(options.http_version, options.http_pool, options.bind_address, options.ip_family, options.tor, Some(cert), auth_host.as_deref())?,
+            )),
+            None => None,
+        };
+        Ok(Self::with_http(http::Transport::new(client, pinned), options))
+    }
+}
+
+impl<H: http::HttpClient> PortalClient<H> {
+    /// Client using `http` as transport, e.g. a fake portal
+    fn with_http(http: H, options: &RunOptions) -> Self {
+        Self {
+            http,
+            pacers: pacing::HostPacers::new(Duration::from_millis(options.delay), options.request_rate_interval, options.throttle, options.delay_per_host),
+            retry: options.retry.clone(),
+            portal_host: options.endpoints.host().ok(),
+            auth_headers: options.auth_headers.clone(),
+        }
+    }
+
+    /// Add the auth headers to a request to the portal, other hosts like those of attachments never get them
+    fn authenticate<'a>(&self, mut request: http::HttpRequest<'a>) -> http::HttpRequest<'a> {
+        if self.auth_headers.is_empty() {
+            return request;
+        }
+        let host = reqwest::Url::parse(request.url).ok().and_then(|url| url.host_str().map(str::to_owned));
+        if host.is_some() && host == self.portal_host {
+            for (name, value) in &self.auth_headers {
+                request = request.secret_header(name.clone(), value.clone());
+            }
+        }
+        request
+    }
+
+    /// Pacer of the host of `url`
+    fn pacer(&self, url: &str) -> std::sync::Arc<pacing::Pacer> {
+        self.pacers.for_url(url)
+    }
+
+    /// Send a request, counting it for the request rate. GETs are idempotent, so failed requests
+    /// and server errors are retried according to `--retries`, the last response is returned as is
+    async fn get(&self, request: http::HttpRequest<'_>) -> Result<http::HttpResponse> {
+        let request = self.authenticate(request);
+        let mut retry = 0;
+        loop {
+            let pacer = self.pacer(request.url);
+            pacer.record_request();
+            let started = std::time::Instant::now();
+            let result = self.http.get(request.clone()).await;
+            pacer.record_response(request.url, result.as_ref().ok().map(|response| response.status), started.elapsed());
+            let failure = match &result {
+                Ok(response) if response.status.is_server_error() || response.status == reqwest::StatusCode::TOO_MANY_REQUESTS => response.status.to_string(),
+                Ok(_) => return result,
+                Err(err) => format!("{:#}", err),
+            };
+            if retry >= self.retry.retries {
+                return result;
+            }
+            let delay = self.retry.backoff(retry);
+            warn!(attempt = retry + 1, "Request to {} failed, retrying in {:?}: {}", request.url, delay, failure);
+            tokio::time::sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+/// Check `--bind-address` and `--ip-family` at startup. Without an explicit family connections use
+/// the family of the bind address, so no request leaves from another IP
+fn resolve_ip_family(bind_address: Option<std::net::IpAddr>, ip_family: Option<http::IpFamily>) -> Result<http::IpFamily> {
+    let Some(bind_address) = bind_address else {
+        return Ok(ip_family.unwrap_or(http::IpFamily::Any));
+    };
+    // Fails if the address isn't assigned to this host
+    std::net::UdpSocket::bind((bind_address, 0)).with_context(|| format!("Can't send from --bind-address {}", bind_address))?;
+    let bind_family = if bind_address.is_ipv4() { http::IpFamily::Ipv4 } else { http::IpFamily::Ipv6 };
+    match ip_family {
+        Some(family) if family != http::IpFamily::Any && family != bind_family => {
+            anyhow::bail!("--bind-address {} is not an {} address as required by --ip-family", bind_address, family)
+        }
+        Some(family) => Ok(family),
+        None => Ok(bind_family),
+    }
+}
+
+/// Client for `http_version`, sending from `bind_address` if given and connecting via `family` only.
+/// With `tor` all requests are routed through Tor, with `pinned_cert` only that certificate is trusted
+fn build_client(http_version: HttpVersion, pool: HttpPoolSettings, bind_address: Option<std::net::IpAddr>, family: http::IpFamily, tor: Option<http::TorSettings>, pinned_cert: Option<&reqwest::Certificate>, auth_host: Option<&str>) -> Result<reqwest::Client> {
+    trace!("Building http client for {:?} with {:?}", http_version, pool);
+    // A pooled connection would keep using the circuit it was opened on
+    let max_idle_per_host = if tor.is_some_and(|tor| tor.isolate) { 0 } else { pool.max_idle_per_host };
+    let builder = reqwest::Client::builder()
+        .pool_idle_timeout(pool.idle_timeout)
+        .pool_max_idle_per_host(max_idle_per_host);
+    let mut builder = match http_version {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    };
+
+    if let Some(bind_address) = bind_address {
+        debug!("Sending requests from {}", bind_address);
+        builder = builder.local_address(bind_address);
+    }
+    if family != http::IpFamily::Any {
+        debug!("Connecting via {:?} only", family);
+        builder = builder.dns_resolver(std::sync::Arc::new(http::FamilyResolver { family }));
+    }
+    if let Some(tor) = tor {
+        debug!("Routing requests through Tor at {}, isolating streams: {}", tor.proxy, tor.isolate);
+        builder = builder.proxy(tor.proxy()?);
+    }
+    if let Some(cert) = pinned_cert {
+        builder = builder.tls_built_in_root_certs(false).add_root_certificate(cert.clone());
+    }
+    if let Some(host) = auth_host {
+        builder = builder.redirect(http::auth_redirect_policy(host.to_owned()));
+    }
+    builder.build().context("Failed to build http client")
+}
+
+/// Response body of a portal endpoint together with its validators for conditional requests
+struct PortalResponse {
+    body: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Fetch incidents from the website or `--incidents-file`, returns `None` if the list didn't change since the last stored snapshot
+async fn fetch_incidents<H: http::HttpClient, D: db::Database>(client: &PortalClient<H>, db: &D, options: &RunOptions) -> Result<Option<Vec<Incident>>> {
+    let response = match &options.incidents_file {
+        Some(path) => read_incident_list_file(path)?,
+        None => match fetch_incident_list(client, db, options).await? {
+            Some(response) => response,
+            None => return Ok(None),
+        },
+    };
+
+    let trimmed = response.body.trim();
+
+    if options.raw_store {
+        trace!("Storing raw response");
+        // Store raw response before parsing
+        let sequence = options.fetch_sequence.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let idempotency_key = format!("{}:{}", options.run_id, sequence);
+        let raw = sink::RawResponse {
+            content: trimmed,
+            etag: response.etag.as_deref(),
+            last_modified: response.last_modified.as_deref(),
+            idempotency_key: &idempotency_key,
+        };
+        let store = options.sinks.store_raw_response(&raw);
+        // The incidents are the primary output, the raw snapshot is only needed for audits and replays
+        match telemetry::in_span("store_raw_response", vec![], store).await {
+            Ok(()) => {}
+            Err(err) if !options.require_raw_store => warn!("Failed to store raw response, continuing without it: {:#}", err),
+            Err(err) => return Err(err),
+        }
+    }
+
+    let body = options.field_mapping.remap_incidents(trimmed)?;
+    check_schema(schema_validation::INCIDENTS_SCHEMA, &body, "incident list", options.schema_validation)?;
+
+    let incidents: Vec<Incident> = parse_json(&body).context("Failed to parse incident response")?;
+    check_incident_count(incidents.len(), options.min_incidents, options.empty_result)?;
+    Ok(Some(incidents))
+}
+
+/// Guard against a list that parses but is empty or suspiciously small, which usually means the
+/// portal changed its response in a way that still parses
+fn check_incident_count(count: usize, min_incidents: usize, mode: CheckMode) -> Result<()> {
+    if mode == CheckMode::Off || count >= min_incidents {
+        return Ok(());
+    }
+    if mode == CheckMode::Strict {
+        anyhow::bail!("Incident list contains only {} incidents, expected at least {}", count, min_incidents);
+    }
+    warn!("Incident list contains only {} incidents, expected at least {}", count, min_incidents);
+    Ok(())
+}
+
+/// Number of characters shown before and after the location of a JSON parse error
+const JSON_ERROR_CONTEXT: usize = 200;
+
+/// Part of `body` around the line and column reported by a JSON parse error
+fn json_error_snippet(body: &str, err: &serde_json::Error) -> String {
+    let line_start: usize = body.split_inclusive('\n').take(err.line().saturating_sub(1)).map(str::len).sum();
+    let mut offset = (line_start + err.column().saturating_sub(1)).min(body.len());
+    while !body.is_char_boundary(offset) {
+        offset -= 1;
+    }
+
+    let before: String = body[..offset].chars().rev().take(JSON_ERROR_CONTEXT).collect::<Vec<_>>().into_iter().rev().collect();
+    let after: String = body[offset..].chars().take(JSON_ERROR_CONTEXT).collect();
+    format!("{}<-- here -->{}", before, after)
+}
+
+/// Parse JSON, including the part of the body around the error location if it fails
+fn parse_json<T: serde::de::DeserializeOwned>(body: &str) -> Result<T> {
+    serde_json::from_str(body).map_err(|err| {
+        let snippet = json_error_snippet(body, &err);
+        anyhow::Error::new(err).context(format!("Invalid JSON near: {}", logging::Sensitive(&snippet)))
+    })
+}
+
+/// Fetch the incident list from the website, returns `None` if it didn't change since the last stored snapshot
+async fn fetch_incident_list<H: http::HttpClient, D: db::Database>(client: &PortalClient<H>, db: &D, options: &RunOptions) -> Result<Option<PortalResponse>> {
+    info!("Fetching incidents from website");
+    let endpoints = &options.endpoints;
+    // Without stored snapshots there is nothing to compare against
+    let (etag, last_modified) = if options.raw_store {
+        match options.retry.idempotent("Getting snapshot validators", || db.last_snapshot_validators()).await {
+            Ok(validators) => validators,
+            Err(err) if !options.require_raw_store => {
+                warn!("Failed to get validators of the last snapshot, sending an unconditional request: {:#}", err);
+                (None, None)
+            }
+            Err(err) => return Err(err),
+        }
+    } else {
+        (None, None)
+    };
+
+    let url = endpoints.incidents();
+    let mut request = http::HttpRequest::get(&url)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .header(reqwest::header::REFERER, endpoints.incidents_referer());
+    if let Some(etag) = etag {
+        trace!("Sending If-None-Match: {}", etag);
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = last_modified {
+        trace!("Sending If-Modified-Since: {}", last_modified);
+        request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let started = std::time::Instant::now();
+    let response = client.get(request).await.context("Failed to fetch incidents")?;
+    options.statsd.timing("fetch.list", started.elapsed());
+    trace!(status = %response.status, "Got cmd response");
+    client.pacer(&url).observe(&response.headers);
+    debug!(protocol = ?response.version, "Negotiated protocol for incident list");
+
+    if response.status == reqwest::StatusCode::NOT_MODIFIED {
+        info!("No change since last run, using the last stored snapshot");
+        return Ok(None);
+    }
+
+    let etag = response.header(reqwest::header::ETAG);
+    let last_modified = response.header(reqwest::header::LAST_MODIFIED);
+    let body = response.text().context("Failed to read response body")?;
     trace!("Successfully got body");
 
-    let trimmed = body.trim();
+    Ok(Some(PortalResponse { body, etag, last_modified }))
+}
+
+/// Read the incident list from a local file instead of the website
+fn read_incident_list_file(path: &std::path::Path) -> Result<PortalResponse> {
+    info!("Reading incidents from {}", path.display());
+    let body = std::fs::read_to_string(path).with_context(|| format!("Failed to read incidents file {}", path.display()))?;
+    serde_json::from_str::<serde_json::Value>(&body).with_context(|| format!("Incidents file {} is not valid JSON", path.display()))?;
+    Ok(PortalResponse { body, etag: None, last_modified: None })
+}
 
-    trace!("Storing raw response");
-    // Store raw response before parsing
-    store_raw_response(pool, trimmed).await?;
+/// Validate a response body against an embedded JSON schema, logging violations or failing in strict mode
+fn check_schema(schema: &str, body: &str, name: &str, mode: CheckMode) -> Result<()> {
+    if mode == CheckMode::Off {
+        return Ok(());
+    }
 
-    serde_json::from_str(trimmed)
-        .context("Failed to parse incident response")
+    trace!("Validating {} against schema", name);
+    let value: serde_json::Value = serde_json::from_str(body).with_context(|| format!("Failed to parse {} as JSON", name))?;
+    let violations = schema_validation::validate(schema, &value)?;
+    for violation in &violations {
+        warn!("Schema violation in {}: {}", name, violation);
+    }
+    if mode == CheckMode::Strict && !violations.is_empty() {
+        anyhow::bail!("{} violates the expected schema in {} places", name, violations.len());
+    }
+    Ok(())
 }
 
-async fn store_raw_response(pool: &sqlx::PgPool, content: &str) -> Result<()> {
-    trace!("Storing raw incident history");
-    sqlx::query("INSERT INTO incident_history (content) VALUES ($1::jsonb)")
-        .bind(content)
-        .execute(pool)
+/// Incidents of the last stored snapshot, which is current if the portal answered 304
+async fn get_last_snapshot_incidents(pool: &sqlx::PgPool, options: &RunOptions) -> Result<Vec<Incident>> {
+    trace!("Getting incidents of last stored snapshot");
+    let (content, content_gzip): (Option<String>, Option<Vec<u8>>) = sqlx::query_as(
+        "SELECT content::text, content_gzip FROM incident_history WHERE is_json ORDER BY id DESC LIMIT 1",
+    )
+        .fetch_one(pool)
         .await
-        .context("Failed to store raw response")?;
-    Ok(())
+        .context("Failed to fetch last snapshot")?;
+    let content = history::snapshot_content(content, content_gzip)?.context("Last snapshot has no content")?;
+    let content = options.field_mapping.remap_incidents(&content)?;
+    serde_json::from_str(&content).context("Failed to parse last snapshot")
 }
 
-async fn process_new_incidents(incidents: Vec<Incident>, pool: &sqlx::PgPool, request_delay: u64) -> Result<()> {
-    trace!("Processing {} new incidents: {:?}", incidents.len(), incidents);
-    let client = reqwest::Client::new();
+/// Get the ETag and Last-Modified of the last stored snapshot for conditional requests
+async fn get_last_snapshot_validators(pool: &sqlx::PgPool) -> Result<(Option<String>, Option<String>)> {
+    trace!("Getting validators of last stored snapshot");
+    let validators: Option<(Option<String>, Option<String>)> = sqlx::query_as(
+        "SELECT etag, last_modified FROM incident_history WHERE is_json ORDER BY id DESC LIMIT 1",
+    )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to fetch last snapshot validators")?;
+    Ok(validators.unwrap_or_default())
+}
 
-    for incident in incidents {
-        let id = incident.incident_id;
-        debug!("Processing incident: {}", id);
-        process_incident(&client, &pool, incident)
+/// Store the raw incident list, a response that isn't valid JSON (e.g. an HTML error page) is
+/// stored as text with `is_json = false` instead of failing the run before it can be inspected.
+/// A snapshot with the same `idempotency_key` (run id and fetch sequence) is only stored once.
+/// With `compress` valid JSON is stored gzip-compressed in `content_gzip` instead of as jsonb
+async fn store_raw_response(pool: &sqlx::PgPool, content: &str, etag: Option<&str>, last_modified: Option<&str>, idempotency_key: &str, compress: bool) -> Result<()> {
+    trace!(idempotency_key, "Storing raw incident history");
+    let is_json = serde_json::from_str::<serde::de::IgnoredAny>(content).is_ok();
+    if is_json && compress {
+        let content_gzip = history::compress(content)?;
+        debug!(idempotency_key, "Compressed raw response from {} to {} bytes", content.len(), content_gzip.len());
+        let result = sqlx::query(
+            "INSERT INTO incident_history (content_gzip, etag, last_modified, idempotency_key) VALUES ($1, $2, $3, $4) ON CONFLICT (idempotency_key) DO NOTHING",
+        )
+            .bind(content_gzip)
+            .bind(etag)
+            .bind(last_modified)
+            .bind(idempotency_key)
+            .execute(pool)
             .await
-            .context(format!("Failed to process incident: {}", id))?;
-        tokio::time::sleep(Duration::from_millis(request_delay)).await;
+            .context("Failed to store compressed raw response")?;
+        if result.rows_affected() == 0 {
+            info!(idempotency_key, "Raw response of this fetch is already stored");
+        }
+        return Ok(());
+    }
+    let inserted = insert_raw_content(pool, "incident_history", "idempotency_key", idempotency_key, content, etag, last_modified)
+        .await
+        .context("Failed to store raw response")?;
+    if !inserted {
+        info!(idempotency_key, "Raw response of this fetch is already stored");
     }
-
     Ok(())
 }
 
-async fn process_incident(client: &reqwest::Client, pool: &sqlx::PgPool, incident: Incident) -> Result<()> {
-    debug!("Processing incident {}", incident.incident_id);
-    let detail = fetch_incident_detail(client, incident.incident_id).await?;
-    store_incident(pool, &incident, &detail).await?;
+/// Store a raw incident detail response in `detail_history`, as text if it can't be stored as jsonb
+async fn store_raw_detail(pool: &sqlx::PgPool, incident_id: i32, content: &str, etag: Option<&str>, last_modified: Option<&str>) -> Result<()> {
+    trace!(incident_id, "Storing raw incident detail");
+    insert_raw_content(pool, "detail_history", "incident_id", incident_id, content, etag, last_modified)
+        .await
+        .with_context(|| format!("Failed to store raw detail of incident {}", incident_id))?;
     Ok(())
 }
 
-async fn fetch_incident_detail(client: &reqwest::Client, incident_id: i32) -> Result<IncidentDetail> {
-    debug!("Fetching incident detail from website for incident {}", incident_id);
-    let url = format!(
-        "https://www.dsgvo-portal.de/sicherheitsvorfall-datenbank/incidentDetails.php?incident={}",
-        incident_id
-    );
-    trace!("Fetching url: {}", url);
+/// Insert a raw response into `content` of `table` keyed by `key_column`. A response that isn't valid JSON
+/// or can't be stored as jsonb is stored in `raw_text` with `is_json = false`. Returns whether a row was
+/// inserted, rows conflicting with a unique key are skipped
+async fn insert_raw_content<K>(pool: &sqlx::PgPool, table: &str, key_column: &str, key: K, content: &str, etag: Option<&str>, last_modified: Option<&str>) -> Result<bool>
+where
+    K: for<'q> sqlx::Encode<'q, sqlx::Postgres> + sqlx::Type<sqlx::Postgres> + std::fmt::Display + Clone + Send,
+{
+    if serde_json::from_str::<serde::de::IgnoredAny>(content).is_ok() {
+        let result = sqlx::query(&format!(
+            "INSERT INTO {} ({}, content, etag, last_modified) VALUES ($1, $2::jsonb, $3, $4) ON CONFLICT DO NOTHING",
+            table, key_column,
+        ))
+            .bind(key.clone())
+            .bind(content)
+            .bind(etag)
+            .bind(last_modified)
+            .execute(pool)
+            .await;
+        match result {
+            Ok(result) => return Ok(result.rows_affected() > 0),
+            // Data exceptions, e.g. `\u0000` which is valid JSON but can't be stored as jsonb
+            Err(sqlx::Error::Database(err)) if err.code().is_some_and(|code| code.starts_with("22")) => {
+                warn!("Failed to store raw response with {} {} as jsonb, storing it as text: {}", key_column, key, err);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    } else {
+        warn!("Raw response with {} {} isn't valid JSON, storing it as text", key_column, key);
+    }
 
-    let response = client
-        .get(&url)
-        .header("Accept", "application/json")
-        .header("Referer", "https://www.dsgvo-portal.de/sicherheitsvorfaelle/")
-        .send()
+    let result = sqlx::query(&format!(
+        "INSERT INTO {} ({}, raw_text, is_json, etag, last_modified) VALUES ($1, $2, FALSE, $3, $4) ON CONFLICT DO NOTHING",
+        table, key_column,
+    ))
+        .bind(key)
+        .bind(content)
+        .bind(etag)
+        .bind(last_modified)
+        .execute(pool)
         .await
-        .with_context(|| format!("Failed to fetch details for incident {}", incident_id))?;
+        .context("Failed to store raw response as text")?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Randomly order incidents, seeded so a run can be reproduced
+fn shuffle_incidents(incidents: &mut [Incident], seed: Option<u64>) {
+    let seed = seed.unwrap_or_else(rand::random);
+    info!("Shuffling {} incidents using seed {}", incidents.len(), seed);
 
-    trace!("Response status: {}", response.status());
+    let mut rng = StdRng::seed_from_u64(seed);
+    incidents.shuffle(&mut rng);
+}
+
+/// Order in which new incidents are processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IncidentOrder {
+    /// Order of the portal's response
+    AsIs,
+    IdAsc,
+    IdDesc,
+    /// Oldest `orgPublishDate` first
+    Date,
+}
 
-    if !response.status().is_success() {
-        anyhow::bail!("Unexpected status code: {}", response.status());
+fn order_incidents(incidents: &mut [Incident], order: IncidentOrder) {
+    match order {
+        IncidentOrder::AsIs => {}
+        IncidentOrder::IdAsc => incidents.sort_by_key(|incident| incident.incident_id),
+        IncidentOrder::IdDesc => incidents.sort_by_key(|incident| std::cmp::Reverse(incident.incident_id)),
+        IncidentOrder::Date => incidents.sort_by_key(|incident| (incident.org_publish_date, incident.incident_id)),
     }
+}
 
-    let body = response.text().await
-        .with_context(|| format!("Failed to read response body for incident {}", incident_id))?;
+/// Restriction of the processed incidents to `--include-id` and `--exclude-id`
+#[derive(Debug, Default)]
+struct IdFilter {
+    /// Only these incidents are processed if given
+    include: Option<HashSet<i32>>,
+    /// Never processed, takes precedence over `include`
+    exclude: HashSet<i32>,
+}
+
+impl IdFilter {
+    fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty()
+    }
 
-    trace!("Response body: {}", body.trim());
+    fn allows(&self, incident_id: i32) -> bool {
+        !self.exclude.contains(&incident_id) && self.include.as_ref().is_none_or(|include| include.contains(&incident_id))
+    }
 
-    serde_json::from_str(body.trim())
-        .with_context(|| format!("Failed to parse details for incident {}", incident_id))
+    fn apply(&self, incidents: &mut Vec<Incident>) {
+        if self.is_empty() {
+            return;
+        }
+        let mut excluded = 0;
+        let mut not_included = 0;
+        incidents.retain(|incident| {
+            if self.exclude.contains(&incident.incident_id) {
+                excluded += 1;
+                false
+            } else if self.include.as_ref().is_some_and(|include| !include.contains(&incident.incident_id)) {
+                not_included += 1;
+                false
+            } else {
+                true
+            }
+        });
+        info!("Skipping {} excluded incidents and {} incidents not included", excluded, not_included);
+    }
 }
 
-async fn store_incident(pool: &sqlx::PgPool, incident: &Incident, detail: &IncidentDetail) -> Result<()> {
-    trace!("Storing incident: {}", incident.incident_id);
+/// Read incident ids from a file, one per line. Blank lines and `#` comments are ignored
+fn read_id_file(path: &std::path::Path) -> Result<Vec<i32>> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read id file {}", path.display()))?;
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.parse().with_context(|| format!("Invalid incident id '{}' in {}", line, path.display())))
+        .collect()
+}
 
-    let parsed: serde_json::Value = serde_json::from_str(&detail.reference).context("Failed to parse references in details")?;
+/// Incident ids given directly via `ids` and in files via `files`
+fn collect_ids(matches: &clap::ArgMatches, ids: &str, files: &str) -> Result<Option<HashSet<i32>>> {
+    let mut collected: Option<HashSet<i32>> = None;
+    if let Some(values) = matches.get_many::<i32>(ids) {
+        collected.get_or_insert_default().extend(values.copied());
+    }
+    if let Some(paths) = matches.get_many::<std::path::PathBuf>(files) {
+        for path in paths {
+            collected.get_or_insert_default().extend(read_id_file(path)?);
+        }
+    }
+    Ok(collected)
+}
 
-    sqlx::query(
-        r#"INSERT INTO incidents (
-            incident_id, org_publish_date, modified_date, published, publish_date,
-            affected_obj, affected_type, country, details_text, tags, href,
-            "references", incident_text
-        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12::jsonb, $13)"#,
-    )
-        .bind(incident.incident_id)
-        .bind(incident.org_publish_date)
-        .bind(incident.modified_date.clone())
-        .bind(incident.published)
-        .bind(detail.publish_date.clone())
-        .bind(&detail.affected_obj)
-        .bind(&detail.affected_type)
-        .bind(&incident.country)
-        .bind(&detail.details_text)
-        .bind(&detail.tags)
-        .bind(&detail.href)
-        .bind(&parsed)
-        .bind(&incident.incident_text)
-        .execute(pool)
-        .await
-        .with_context(|| format!("Failed to store incident {}", incident.incident_id))?;
+/// Randomly pick `count` incidents, seeded so a run can be reproduced
+fn sample_incidents(mut incidents: Vec<Incident>, count: usize, seed: Option<u64>) -> Vec<Incident> {
+    info!("Sampling {} of {} incidents", count.min(incidents.len()), incidents.len());
+    shuffle_incidents(&mut incidents, seed);
+    incidents.truncate(count);
 
-    info!("Successfully stored incident {}", incident.incident_id);
-    Ok(())
+    let ids: Vec<i32> = incidents.iter().map(|incident| incident.incident_id).collect();
+    info!("Selected incidents: {:?}", ids);
+    incidents
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    setup_logger();
+/// Outcome of processing new incidents
+#[derive(Debug, Default)]
+struct ProcessReport {
+    succeeded: Vec<i32>,
+    /// Incident ids with the error that made them fail
+    failed: Vec<(i32, String)>,
+    /// Incidents that were not attempted because processing stopped at a failure or the timeout
+    /// budget, or not stored because of implausible dates
+    skipped: Vec<i32>,
+    /// Whether processing stopped because `--timeout-budget` elapsed
+    budget_exhausted: bool,
+}
 
-    let matches = clap::builder::Command::new("dsgvo-downloader")
-        .arg(clap::Arg::new("delay")
-            .short('d')
-            .long("delay")
-            .default_value("500")
-            .action(clap::ArgAction::Set)
-            .value_parser(value_parser!(u64))
-            .help("Delay time in milliseconds")
-            .long_help("Delay time in milliseconds as to not overwhelm the server and disable the api")
-        )
-        .arg(clap::Arg::new("database-url")
-            .short('u')
-            .long("database-url")
-            .default_value("postgres://postgres@localhost:5432/dsgvo")
-            .action(clap::ArgAction::Set)
-            .value_parser(value_parser!(String))
-            .help("Database URL for a postgres instance")
-            .long_help("Database URL for a postgres instance, the tables have to be preconfigured via `schema.sql`")
-        )
-        .get_matches();
+/// Consume a stream of new incidents, fetching and storing the details of each one.
+/// Processing stops at the first failed incident or once `deadline` passed, the remaining ones
+/// are reported as skipped. An incident whose details were fetched is always stored
+async fn process_new_incidents<H: http::HttpClient, D: db::Database>(client: &PortalClient<H>, incidents: impl Stream<Item = Incident>, db: &D, options: &RunOptions, deadline: Option<std::time::Instant>) -> Result<ProcessReport> {
+    let mut incidents = std::pin::pin!(incidents);
+    let mut report = ProcessReport::default();
+    // With `--commit-every` the sinks are flushed every N incidents
+    let mut uncommitted: u64 = 0;
 
-    let delay: u64 = *matches.get_one("delay").context("missing required argument delay")?;
-    if delay < 500 {
-        log::error!("delay has a minimum of 500ms");
+    while let Some(incident) = incidents.next().await {
+        let id = incident.incident_id;
+        if !report.budget_exhausted && deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+            report.budget_exhausted = true;
+        }
+        if !report.failed.is_empty() || report.budget_exhausted {
+            report.skipped.push(id);
+            continue;
+        }
+
+        debug!(incident_id = id, "Processing incident");
+        match telemetry::in_span("process_incident", vec![("incident.id", id.to_string())], process_incident(client, db, &incident, options)).await {
+            Ok(Processed::Stored | Processed::Unchanged) => {
+                options.statsd.count("incidents.succeeded", 1);
+                report.succeeded.push(id);
+            }
+            Ok(Processed::Skipped) => report.skipped.push(id),
+            Err(err) => {
+                options.statsd.count("incidents.failed", 1);
+                db.record_failed_incident(&incident, &err).await?;
+                report.failed.push((id, format!("{:#}", err)));
+                continue;
+            }
+        }
+        uncommitted += 1;
+        if uncommitted >= options.commit_every {
+            options.sinks.flush().await.with_context(|| format!("Failed to flush {} stored incidents", uncommitted))?;
+            uncommitted = 0;
+        }
+        // Without details no request is sent per incident
+        if !options.disable_detail_fetch {
+            client.pacer(&options.endpoints.incident_detail(id)).wait().await;
+        }
+    }
+    // The remainder of the last batch, also when processing stopped early
+    options.sinks.flush().await.with_context(|| format!("Failed to flush {} stored incidents", uncommitted))?;
+    if !report.skipped.is_empty() {
+        options.statsd.count("incidents.skipped", report.skipped.len() as u64);
     }
 
-    let database_url: &str = matches.get_one("database-url").context("missing required argument database-url").map(String::as_str)?;
+    Ok(report)
+}
 
-    trace!("Setting up database pool and verifying tables");
-    let pool = setup_database(database_url).await?;
-    verify_tables(&pool).await?;
+/// Outcome of [`process_incident`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Processed {
+    Stored,
+    /// Not stored again as the portal answered that its detail didn't change and the stored incident
+    /// has the listed modified date, see `--revalidate-details`
+    Unchanged,
+    /// Not stored because of implausible dates, see `--strict-dates skip`
+    Skipped,
+}
 
-    trace!("Fetching existing incidents");
-    let existing_ids = get_existing_incident_ids(&pool).await?;
-    trace!("Fetching incidents from website");
-    let current_incidents = fetch_incidents(&pool).await?;
+/// Fetch an incident and store it in the sinks. With `--commit-every` it is only durable once the
+/// caller flushed the sinks
+async fn process_incident<H: http::HttpClient, D: db::Database>(client: &PortalClient<H>, db: &D, incident: &Incident, options: &RunOptions) -> Result<Processed> {
+    debug!(incident_id = incident.incident_id, "Processing incident");
+    // The dates of the list are checked before fetching the details of an incident that is skipped anyway
+    if let Some(date_check) = &options.date_check {
+        if !date_check.check(incident, &[])? {
+            return Ok(Processed::Skipped);
+        }
+    }
+    let (details, not_modified) = if options.disable_detail_fetch {
+        (Vec::new(), false)
+    } else {
+        telemetry::in_span("fetch_incident_detail", vec![], fetch_revalidated_incident_detail(client, db, options, incident)).await?
+    };
+    if let Some(date_check) = &options.date_check {
+        if !date_check.check(incident, &details)? {
+            return Ok(Processed::Skipped);
+        }
+    }
+    if let Some(detail) = details.first() {
+        check_consistency(incident, detail, options.consistency)?;
+    }
+    if not_modified && db.touch_unchanged_incident(incident).await? {
+        debug!(incident_id = incident.incident_id, "Incident and its detail didn't change, skipping store");
+        return Ok(Processed::Unchanged);
+    }
+    telemetry::in_span("store_incident", vec![], options.sinks.store_incident(incident, &details)).await?;
+    if let Some(hook) = &options.hook {
+        if let Err(err) = hook.on_stored(incident, &details).await {
+            if options.strict_hook {
+                // Recorded as failed instead, which is only possible until the sinks are flushed
+                options.sinks.discard(incident).await?;
+                return Err(err.context(format!("Hook failed for incident {}", incident.incident_id)));
+            }
+            warn!(incident_id = incident.incident_id, "Hook failed: {:#}", err);
+        }
+    }
+    if let Some(attachments) = &options.attachments {
+        let references: Vec<&str> = details.iter().map(|detail| detail.reference.as_str()).collect();
+        attachments::fetch_attachments(client, db, attachments, incident.incident_id, &references).await;
+    }
+    Ok(Processed::Stored)
+}
 
-    // Filter for new incidents
-    let new_incidents: Vec<_> = current_incidents
-        .into_iter()
-        .filter(|incident| !existing_ids.contains(&incident.incident_id))
-        .collect();
+/// How optional data-quality checks are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CheckMode {
+    Off,
+    Warn,
+    Strict,
+}
+
+/// Normalize text for comparison, ignoring case and whitespace differences
+fn normalize_text(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Compare the list-provided incident with its details, logging divergences or failing in strict mode
+fn check_consistency(incident: &Incident, detail: &IncidentDetail, consistency: CheckMode) -> Result<()> {
+    if consistency == CheckMode::Off {
+        return Ok(());
+    }
 
-    info!("Found {} new incidents", new_incidents.len());
-    process_new_incidents(new_incidents, &pool, delay).await?;
+    let mut divergences = Vec::new();
+    let incident_text = normalize_text(&incident.incident_text);
+    let details_text = normalize_text(&detail.details_text);
+    if incident_text.is_empty() != details_text.is_empty() {
+        divergences.push("only one of incident_text and details_text is empty");
+    } else if !incident_text.contains(&details_text) && !details_text.contains(&incident_text) {
+        divergences.push("incident_text and details_text differ");
+    }
+    if detail.publish_date < incident.org_publish_date {
+        divergences.push("publish_date is before org_publish_date");
+    }
 
+    for divergence in &divergences {
+        warn!(incident_id = incident.incident_id, divergence, "Incident is inconsistent");
+    }
+    if consistency == CheckMode::Strict && !divergences.is_empty() {
+        anyhow::bail!("Incident {} is inconsistent: {}", incident.incident_id, divergences.join(", "));
+    }
     Ok(())
 }
+
+/// What happens to an incident with an implausible date
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DateCheckAction {
+    /// Log a warning and don't store the incident
+    Skip,
+    Fail,
+}
+
+/// Plausible window for the dates of an incident, from `min` to `max_ahead` after now
+struct DateCheck {
+    action: DateCheckAction,
+    min: chrono::NaiveDate,
+    max_ahead: Duration,
+}
+
+impl DateCheck {
+    /// Implausible dates of the incident as `field=value`
+    fn violations(&self, incident: &Incident, details: &[IncidentDetail]) -> Vec<String> {
+        let max = (chrono::Utc::now().naive_utc() + self.max_ahead).date();
+        let mut dates = vec![
+            ("org_publish_date", incident.org_publish_date),
+            ("modified_date", incident.modified_date.date()),
+        ];
+        dates.extend(details.iter().map(|detail| ("publish_date", detail.publish_date)));
+        let mut violations: Vec<String> = dates
+            .into_iter()
+            .filter(|(_, date)| *date < self.min || *date > max)
+            .map(|(field, date)| format!("{}={}", field, date))
+            .collect();
+        violations.dedup();
+        violations
+    }
+
+    /// Check the dates of an incident, `false` if it should be skipped
+    fn check(&self, incident: &Incident, details: &[IncidentDetail]) -> Result<bool> {
+        let violations = self.violations(incident, details);
+        if violations.is_empty() {
+            return Ok(true);
+        }
+        let message = format!("Incident {} has dates outside {}..=now+{:?}: {}", incident.incident_id, self.min, self.max_ahead, violations.join(", "));
+        if self.action == DateCheckAction::Fail {
+            anyhow::bail!(message);
+        }
+        warn!(incident_id = incident.incident_id, "{}, skipping", message);
+        Ok(false)
+    }
+}
+
+/// Record a failed incident so it can be picked up again by `retry-failed`
+async fn record_failed_incident(pool: &sqlx::PgPool, incident: &Incident, err: &anyhow::Error) -> Result<()> {
+    debug!(incident_id = incident.incident_id, "Recording failed incident");
+    sqlx::query(
+        r#"INSERT INTO failed_incidents (incident_id, incident, error)
+           VALUES ($1, $2, $3)
+           ON CONFLICT (incident_id) DO UPDATE SET
+               incident = EXCLUDED.incident,
+               error = EXCLUDED.error,
+               attempts = failed_incidents.attempts + 1,
+               last_attempt_at = CURRENT_TIMESTAMP"#,
+    )
+        .bind(incident.incident_id)
+        .bind(sqlx::types::Json(incident))
+        .bind(format!("{:#}", err))
+        .execute(pool)
+        .await
+        .with_context(|| format!("Failed to record failed incident {}", incident.incident_id))?;
+    Ok(())
+}
+
+/// Re-attempt incidents from the `failed_incidents` table, giving up on those that reached `max_attempts`
+async fn retry_failed_incidents(pool: &sqlx::PgPool, options: &RunOptions, max_attempts: i32) -> Result<()> {
+    trace!("Getting failed incidents from database");
+    let failed: Vec<(sqlx::types::Json<Incident>, i32)> = sqlx::query_as(
+        "SELECT incident, attempts FROM failed_incidents WHERE NOT permanently_failed ORDER BY incident_id",
+    )
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch failed incidents")?;
+
+    info!("Found {} failed incidents to retry", failed.len());
+    let years = failed.iter().map(|(incident, _)| incident.org_publish_date.year()).collect();
+    partitioning::ensure_year_partitions(pool, &years).await?;
+    let client = PortalClient::new(options)?;
+
+    for (sqlx::types::Json(incident), attempts) in failed {
+        let id = incident.incident_id;
+        if !options.id_filter.allows(id) {
+            debug!(incident_id = id, "Skipping retry of incident filtered by --include-id/--exclude-id");
+            continue;
+        }
+        if attempts >= max_attempts {
+            warn!(incident_id = id, attempts, "Incident reached the maximum attempts, marking as permanently failed");
+            mark_permanently_failed(pool, id).await?;
+            continue;
+        }
+
+        debug!(incident_id = id, attempt = attempts + 1, "Retrying incident");
+        match telemetry::in_span("process_incident", vec![("incident.id", id.to_string())], process_incident(&client, pool, &incident, options)).await {
+            Ok(Processed::Stored) => {
+                options.sinks.flush().await?;
+                info!(incident_id = id, "Successfully retried incident");
+            }
+            Ok(Processed::Unchanged) => info!(incident_id = id, "Retried incident didn't change since it was stored"),
+            Ok(Processed::Skipped) => {
+                // Not a failure, a run skips it again as long as its dates are implausible
+                info!(incident_id = id, "Skipped retried incident because of its dates, no longer retrying it");
+                clear_failed_incident(pool, id).await?;
+            }
+            Err(err) => {
+                warn!(incident_id = id, "Retry of incident failed: {:#}", err);
+                record_failed_incident(pool, &incident, &err).await?;
+                if attempts + 1 >= max_attempts {
+                    warn!(incident_id = id, attempts = attempts + 1, "Incident reached the maximum attempts, marking as permanently failed");
+                    mark_permanently_failed(pool, id).await?;
+                }
+            }
+        }
+        client.pacer(&options.endpoints.incident_detail(id)).wait().await;
+    }
+    log_delay_stats(&client.pacers);
+
+    Ok(())
+}
+
+async fn clear_failed_incident(db: impl sqlx::PgExecutor<'_>, incident_id: i32) -> Result<()> {
+    sqlx::query("DELETE FROM failed_incidents WHERE incident_id = $1")
+        .bind(incident_id)
+        .execute(db)
+        .await
+        .with_context(|| format!("Failed to remove failed incident {}", incident_id))?;
+    Ok(())
+}
+
+async fn clear_failed_incidents(pool: &sqlx::PgPool, incident_ids: &[i32]) -> Result<()> {
+    sqlx::query("DELETE FROM failed_incidents WHERE incident_id = ANY($1)")
+        .bind(incident_ids)
+        .execute(pool)
+        .await
+        .context("Failed to remove failed incidents")?;
+    Ok(())
+}
+
+async fn mark_permanently_failed(pool: &sqlx::PgPool, incident_id: i32) -> Result<()> {
+    sqlx::query("UPDATE failed_incidents SET permanently_failed = TRUE WHERE incident_id = $1")
+        .bind(incident_id)
+        .execute(pool)
+        .await
+        .with_context(|| format!("Failed to mark incident {} as permanently failed", incident_id))?;
+    Ok(())
+}
+
+async fn get_last_stored_detail(pool: &sqlx::PgPool, incident_id: i32) -> Result<Option<db::StoredDetail>> {
+    trace!(incident_id, "Getting last stored raw detail");
+    let row: Option<(String, Option<String>, Option<String>)> = sqlx::query_as(
+        "SELECT content::text, etag, last_modified FROM detail_history WHERE incident_id = $1 AND is_json ORDER BY id DESC LIMIT 1",
+    )
+        .bind(incident_id)
+        .fetch_optional(pool)
+        .await
+        .with_context(|| format!("Failed to fetch last stored detail of incident {}", incident_id))?;
+    Ok(row.map(|(content, etag, last_modified)| db::StoredDetail { content, etag, last_modified }))
+}
+
+/// Fetch the raw detail response of an incident from the website. With the validators of a
+/// `stored` response a conditional request is sent, `None` is returned if the detail didn't change
+async fn fetch_incident_detail_body<H: http::HttpClient>(client: &PortalClient<H>, options: &RunOptions, incident_id: i32, stored: Option<&db::StoredDetail>) -> Result<Option<PortalResponse>> {
+    debug!(incident_id, "Fetching incident detail from website");
+    #[cfg(feature = "simulate-errors")]
+    if let Some(simulation) = &options.simulate_errors {
+        simulation.maybe_fail(&format!("detail request for incident {}", incident_id))?;
+    }
+    let url = options.endpoints.incident_detail(incident_id);
+    trace!(incident_id, language = options.language, "Fetching url: {}", url);
+
+    let mut request = http::HttpRequest::get(&url)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .header(reqwest::header::REFERER, options.endpoints.incident_detail_referer())
+        .header(reqwest::header::ACCEPT_LANGUAGE, &options.language);
+    if let Some(etag) = stored.and_then(|stored| stored.etag.as_deref()) {
+        trace!(incident_id, "Sending If-None-Match: {}", etag);
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = stored.and_then(|stored| stored.last_modified.as_deref()) {
+        trace!(incident_id, "Sending If-Modified-Since: {}", last_modified);
+        request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let started = std::time::Instant::now();
+    let response = client.get(request)
+        .await
+        .with_context(|| format!("Failed to fetch details for incident {}", incident_id))?;
+    options.statsd.timing("fetch.detail", started.elapsed());
+
+    trace!(incident_id, status = %response.status, "Got detail response");
+    client.pacer(&url).observe(&response.headers);
+    debug!(incident_id, protocol = ?response.version, "Negotiated protocol for incident detail");
+
+    if response.status == reqwest::StatusCode::NOT_MODIFIED && stored.is_some() {
+        return Ok(None);
+    }
+    if !response.status.is_success() {
+        anyhow::bail!("Unexpected status code: {}", response.status);
+    }
+
+    let etag = response.header(reqwest::header::ETAG);
+    let last_modified = response.header(reqwest::header::LAST_MODIFIED);
+    let body = response.text()
+        .with_context(|| format!("Failed to read response body for incident {}", incident_id))?;
+    Ok(Some(PortalResponse { body, etag, last_modified }))
+}
+
+/// Fetch the details of an incident, at least one, from `--detail-cache` or the website
+async fn fetch_incident_detail<H: http::HttpClient, D: db::Database>(client: &PortalClient<H>, db: &D, options: &RunOptions, incident: &Incident) -> Result<Vec<IncidentDetail>> {
+    Ok(fetch_revalidated_incident_detail(client, db, options, incident).await?.0)
+}
+
+/// Mark an incident stored with the listed modified date as fetched now and clear its failures, returns
+/// whether it is stored like this
+async fn touch_unchanged_incident(pool: &sqlx::PgPool, incident: &Incident) -> Result<bool> {
+    let touched = sqlx::query("UPDATE incidents SET fetched_at = CURRENT_TIMESTAMP WHERE incident_id = $1 AND modified_date = $2")
+        .bind(incident.incident_id)
+        .bind(incident.modified_date)
+        .execute(pool)
+        .await
+        .with_context(|| format!("Failed to mark incident {} as fetched", incident.incident_id))?
+        .rows_affected() > 0;
+    if touched {
+        clear_failed_incident(pool, incident.incident_id).await?;
+    }
+    Ok(touched)
+}
+
+/// Like [`fetch_incident_detail`], also returning whether the portal answered a `--revalidate-details`
+/// request with `304 Not Modified`, so the details were parsed from `detail_history`
+async fn fetch_revalidated_incident_detail<H: http::HttpClient, D: db::Database>(client: &PortalClient<H>, db: &D, options: &RunOptions, incident: &Incident) -> Result<(Vec<IncidentDetail>, bool)> {
+    let incident_id = incident.incident_id;
+    let cached = match &options.detail_cache {
+        Some(cache) => cache.get(incident_id, &options.language, incident.modified_date).await?,
+        None => None,
+    };
+    let from_cache = cached.is_some();
+    let mut not_modified = false;
+    let raw_body = match cached {
+        Some(body) => {
+            debug!(incident_id, "Using cached incident detail");
+            body
+        }
+        None => {
+            let stored = if options.revalidate_details {
+                db.last_stored_detail(incident_id).await?
+            } else {
+                None
+            };
+            match fetch_incident_detail_body(client, options, incident_id, stored.as_ref()).await? {
+                Some(response) => {
+                    if options.store_raw_details {
+                        // Store raw response before parsing
+                        db.store_raw_detail(incident_id, response.body.trim(), response.etag.as_deref(), response.last_modified.as_deref()).await?;
+                    }
+                    response.body
+                }
+                None => {
+                    debug!(incident_id, "Incident detail didn't change since it was last stored");
+                    not_modified = true;
+                    stored.context("Got 304 without a stored detail")?.content
+                }
+            }
+        }
+    };
+
+    trace!("Response body: {}", logging::Sensitive(raw_body.trim()));
+
+    let body = options.field_mapping.remap_detail(raw_body.trim())?;
+    if body.starts_with('[') {
+        // The schema describes a single detail, so validate each one of a list
+        if options.schema_validation != CheckMode::Off {
+            let details: Vec<serde_json::Value> = serde_json::from_str(&body).with_context(|| format!("Failed to parse details of incident {} as JSON", incident_id))?;
+            for (position, detail) in details.iter().enumerate() {
+                check_schema(schema_validation::INCIDENT_DETAIL_SCHEMA, &detail.to_string(), &format!("detail {} of incident {}", position, incident_id), options.schema_validation)?;
+            }
+        }
+    } else {
+        check_schema(schema_validation::INCIDENT_DETAIL_SCHEMA, &body, &format!("details of incident {}", incident_id), options.schema_validation)?;
+    }
+
+    let details: Vec<IncidentDetail> = if body.starts_with('[') {
+        parse_json(&body)
+    } else {
+        parse_json(&body).map(|detail| vec![detail])
+    }
+        .with_context(|| format!("Failed to parse details for incident {}", incident_id))?;
+    if details.is_empty() {
+        anyhow::bail!("Incident {} has no details", incident_id);
+    }
+    if details.len() > 1 {
+        debug!(incident_id, count = details.len(), "Incident has multiple details");
+    }
+
+    // Only cache responses that could be parsed, so a broken response is fetched again on retry
+    if let (Some(cache), false) = (&options.detail_cache, from_cache) {
+        cache.put(incident_id, &options.language, incident.modified_date, &raw_body).await?;
+    }
+    Ok((details, not_modified))
+}
+
+/// `text` cut to at most `max_bytes` at a character boundary, with its original length in bytes if it was cut
+fn truncate_text(text: &str, max_bytes: Option<usize>) -> (&str, Option<i32>) {
+    let Some(max_bytes) = max_bytes.filter(|max_bytes| text.len() > *max_bytes) else {
+        return (text, None);
+    };
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    (&text[..end], Some(i32::try_from(text.len()).unwrap_or(i32::MAX)))
+}
+
+/// Store an incident with its first detail in one transaction, all details are stored in `incident_details`.
+/// Takes anything a transaction can be started on, e.g. the pool or a connection inside a test transaction
+/// that is rolled back. With `strip_html` plain text versions of the texts are stored alongside
+async fn store_incident<'c>(db: impl sqlx::Acquire<'c, Database = sqlx::Postgres>, incident: &Incident, details: &[IncidentDetail], options: &sink::StoreOptions) -> Result<()> {
+    trace!(incident_id = incident.incident_id, "Storing incident");
+    // Without details only the list fields are stored, the detail columns stay NULL
+    let detail = details.first();
+
+    let parsed: Option<serde_json::Value> = detail
+        .map(IncidentDetail::references)
+        .transpose()
+        .context("Failed to parse references in details")?;
+    let (strip_html, max_text_bytes) = (options.strip_html, options.max_text_bytes);
+    let incident_text_plain = strip_html.then(|| html_text::to_plain_text(&incident.incident_text));
+    let details_text_plain = detail.filter(|_| strip_html).map(|detail| html_text::to_plain_text(&detail.details_text));
+
+    // The full texts are kept in the raw history and incident_details
+    let (incident_text, incident_text_original_bytes) = truncate_text(&incident.incident_text, max_text_bytes);
+    let (details_text, details_text_original_bytes) = match detail {
+        Some(detail) => {
+            let (text, original_bytes) = truncate_text(&detail.details_text, max_text_bytes);
+            (Some(text), original_bytes)
+        }
+        None => (None, None),
+    };
+    if incident_text_original_bytes.is_some() || details_text_original_bytes.is_some() {
+        warn!(incident_id = incident.incident_id, "Truncating texts to {} bytes", max_text_bytes.unwrap_or_default());
+    }
+    let incident_text_plain = incident_text_plain.as_deref().map(|text| truncate_text(text, max_text_bytes).0);
+    let details_text_plain = details_text_plain.as_deref().map(|text| truncate_text(text, max_text_bytes).0);
+
+    let mut tx = db.begin().await.context("Failed to start transaction")?;
+
+    let previous: Option<serde_json::Value> = sqlx::query_scalar(
+        "SELECT to_jsonb(incidents) - 'search_vector' - 'fetched_at' - 'row_hmac' FROM incidents WHERE incident_id = $1 FOR UPDATE",
+    )
+        .bind(incident.incident_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to fetch previous state of incident {}", incident.incident_id))?;
+
+    // An update instead of an upsert, so a changed org_publish_date moves the row into the right
+    // partition if incidents is partitioned by year. The row HMAC is cleared and signed again below
+    // if a key is given, so a row changed without the key reports as unsigned instead of altered
+    let sql = if previous.is_some() {
+        r#"UPDATE incidents SET
+            org_publish_date = $2,
+            modified_date = $3,
+            published = $4,
+            publish_date = COALESCE($5, publish_date),
+            affected_obj = COALESCE($6, affected_obj),
+            affected_type = COALESCE($7, affected_type),
+            country = $8,
+            details_text = COALESCE($9, details_text),
+            tags = COALESCE($10, tags),
+            href = COALESCE($11, href),
+            "references" = COALESCE($12::jsonb, "references"),
+            incident_text = $13,
+            incident_text_plain = COALESCE($14, incident_text_plain),
+            details_text_plain = COALESCE($15, details_text_plain),
+            fetched_at = CURRENT_TIMESTAMP,
+            incident_text_original_bytes = $16,
+            details_text_original_bytes = CASE WHEN $9 IS NULL THEN details_text_original_bytes ELSE $17 END,
+            unpublished_at = CASE
+                WHEN NOT $18 THEN unpublished_at
+                WHEN published = 1 AND $4 <> 1 THEN CURRENT_TIMESTAMP
+                WHEN $4 = 1 THEN NULL
+                ELSE unpublished_at
+            END,
+            row_hmac = NULL
+        WHERE incident_id = $1
+        RETURNING to_jsonb(incidents) - 'search_vector' - 'fetched_at' - 'row_hmac'"#
+    } else {
+        r#"INSERT INTO incidents (
+            incident_id, org_publish_date, modified_date, published, publish_date,
+            affected_obj, affected_type, country, details_text, tags, href,
+            "references", incident_text, incident_text_plain, details_text_plain, fetched_at,
+            incident_text_original_bytes, details_text_original_bytes
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12::jsonb, $13, $14, $15, CURRENT_TIMESTAMP, $16, $17)
+        ON CONFLICT DO NOTHING
+        RETURNING to_jsonb(incidents) - 'search_vector' - 'fetched_at' - 'row_hmac'"#
+    };
+    let query = sqlx::query_scalar(sql)
+        .bind(incident.incident_id)
+        .bind(incident.org_publish_date)
+        .bind(incident.modified_date)
+        .bind(incident.published)
+        .bind(detail.map(|detail| detail.publish_date))
+        .bind(detail.map(|detail| &detail.affected_obj))
+        .bind(detail.map(|detail| &detail.affected_type))
+        .bind(&incident.country)
+        .bind(details_text)
+        .bind(detail.map(|detail| &detail.tags))
+        .bind(detail.map(|detail| &detail.href))
+        .bind(&parsed)
+        .bind(incident_text)
+        .bind(incident_text_plain)
+        .bind(details_text_plain)
+        .bind(incident_text_original_bytes)
+        .bind(details_text_original_bytes);
+    // Only the update compares the published flags
+    let query = if previous.is_some() { query.bind(options.mark_unpublished) } else { query };
+    let current: Option<serde_json::Value> = query
+        .fetch_optional(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to store incident {}", incident.incident_id))?;
+
+    // Inserted by a concurrent run since the previous state was read
+    let Some(current) = current else {
+        warn!(incident_id = incident.incident_id, "Incident already exists, skipping duplicate insert");
+        return Ok(());
+    };
+
+    let previous_published = previous.as_ref().and_then(|previous| previous.get("published")).and_then(serde_json::Value::as_i64);
+    if let Some(row_hmac) = &options.row_hmac {
+        integrity::store_row_hmac(&mut tx, row_hmac, incident.incident_id, &current).await?;
+    }
+    if let Some(previous) = previous.filter(|previous| *previous != current) {
+        debug!(incident_id = incident.incident_id, "Incident changed, storing previous state as revision");
+        store_revision(&mut tx, incident, &previous).await?;
+    }
+    if !details.is_empty() {
+        store_details(&mut tx, incident.incident_id, details).await?;
+    }
+
+    tx.commit().await.with_context(|| format!("Failed to commit incident {}", incident.incident_id))?;
+
+    if let Some(previous_published) = previous_published.filter(|published| *published != i64::from(incident.published)) {
+        log_publish_transition(incident, previous_published);
+    }
+    info!(incident_id = incident.incident_id, "Successfully stored incident");
+    Ok(())
+}
+
+/// Log a change of the published flag as its own event, so editorial changes of the portal can
+/// be followed in the logs
+fn log_publish_transition(incident: &Incident, previous_published: i64) {
+    let (incident_id, published) = (incident.incident_id, incident.published);
+    if previous_published == 1 {
+        warn!(incident_id, event = "incident_unpublished", previous_published, published, "Incident was unpublished on the portal");
+    } else if incident.is_published() {
+        info!(incident_id, event = "incident_republished", previous_published, published, "Incident was published again on the portal");
+    } else {
+        info!(incident_id, event = "incident_published_changed", previous_published, published, "Published flag of incident changed");
+    }
+}
+
+/// Replace the stored details of an incident
+async fn store_details(tx: &mut sqlx::PgConnection, incident_id: i32, details: &[IncidentDetail]) -> Result<()> {
+    sqlx::query("DELETE FROM incident_details WHERE incident_id = $1")
+        .bind(incident_id)
+        .execute(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to delete details of incident {}", incident_id))?;
+
+    for (position, detail) in details.iter().enumerate() {
+        let references = detail.references().context("Failed to parse references in details")?;
+        sqlx::query(
+            r#"INSERT INTO incident_details (
+                incident_id, position, publish_date, affected_obj, affected_type,
+                details_text, tags, href, "references"
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#,
+        )
+            .bind(incident_id)
+            .bind(position as i32)
+            .bind(detail.publish_date)
+            .bind(&detail.affected_obj)
+            .bind(&detail.affected_type)
+            .bind(&detail.details_text)
+            .bind(&detail.tags)
+            .bind(&detail.href)
+            .bind(&references)
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to store detail {} of incident {}", position, incident_id))?;
+    }
+    Ok(())
+}
+
+/// Store the previous state of an incident, valid from its own modified date until the modified date of the new state
+async fn store_revision(tx: &mut sqlx::PgConnection, incident: &Incident, previous: &serde_json::Value) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO incident_revisions (incident_id, snapshot, valid_from, valid_to)
+           VALUES ($1, $2, ($2->>'modified_date')::timestamptz, $3)"#,
+    )
+        .bind(incident.incident_id)
+        .bind(previous)
+        .bind(incident.modified_date)
+        .execute(tx)
+        .await
+        .with_context(|| format!("Failed to store revision of incident {}", incident.incident_id))?;
+    Ok(())
+}
+
+fn log_delay_stats(pacers: &pacing::HostPacers) {
+    for (host, pacer) in pacers.all() {
+        let host = host.as_deref().unwrap_or("all hosts");
+        let stats = pacer.stats();
+        if stats.count > 0 {
+            info!(host, "Effective delay between requests: min {:?}, avg {:?}, max {:?}", stats.min, stats.avg(), stats.max);
+        }
+        let (requests, rate) = pacer.request_rate();
+        if requests > 0 {
+            info!(host, requests, "Average request rate: {:.1} requests per minute", rate);
+        }
+        if let Some(reason) = pacer.throttling() {
+            warn!(host, "Likely throttled by the portal during this run ({}), consider increasing --delay", reason);
+        }
+    }
+}
+
+/// Language of the details text the portal returns by default
+const DEFAULT_DETAIL_LANGUAGE: &str = "de";
+
+/// Minimum `--delay` in milliseconds, unless `--allow-low-delay` is given
+const MIN_DELAY: u64 = 500;
+
+/// Options for a single fetch-and-store cycle
+struct RunOptions {
+    delay: u64,
+    /// Pace every host separately instead of all requests together
+    delay_per_host: bool,
+    /// Retries of idempotent operations
+    retry: retry::RetryPolicy,
+    /// Commit stored incidents every this many incidents instead of each one separately
+    commit_every: u64,
+    /// Fail a share of the detail requests, see `--simulate-errors`
+    #[cfg(feature = "simulate-errors")]
+    simulate_errors: Option<simulate::ErrorSimulation>,
+    sample: Option<usize>,
+    /// Abort if more new incidents are found, unless `force_new_incidents` is set
+    max_new_incidents: Option<usize>,
+    force_new_incidents: bool,
+    shuffle: bool,
+    seed: Option<u64>,
+    order: IncidentOrder,
+    consistency: CheckMode,
+    endpoints: Endpoints,
+    http_version: HttpVersion,
+    http_pool: HttpPoolSettings,
+    /// Local address to send requests from
+    bind_address: Option<std::net::IpAddr>,
+    /// IP family to connect with
+    ip_family: http::IpFamily,
+    /// Route requests through Tor
+    tor: Option<http::TorSettings>,
+    /// Only trust this certificate for the portal host
+    pinned_cert: Option<reqwest::Certificate>,
+    /// Headers authenticating requests to the portal host, with secret values
+    auth_headers: Vec<(reqwest::header::HeaderName, String)>,
+    /// Store the raw incident list in `incident_history`
+    raw_store: bool,
+    /// Fail the run if the raw incident list can't be stored instead of logging a warning
+    require_raw_store: bool,
+    /// Only store the fields of the incident list, without fetching details
+    disable_detail_fetch: bool,
+    /// Id of this invocation, for correlation and idempotency keys
+    run_id: String,
+    /// Number of incident list fetches of this invocation so far
+    fetch_sequence: std::sync::atomic::AtomicU32,
+    /// Send conditional detail requests with the validators of the last raw detail in `detail_history`
+    revalidate_details: bool,
+    /// Store the raw detail responses in `detail_history`
+    store_raw_details: bool,
+    schema_validation: CheckMode,
+    /// Check for an incident list with fewer than `min_incidents` incidents
+    empty_result: CheckMode,
+    min_incidents: usize,
+    /// Read the incident list from this file instead of the website
+    incidents_file: Option<std::path::PathBuf>,
+    field_mapping: field_mapping::FieldMapping,
+    /// Skip incidents that aren't published
+    published_only: bool,
+    id_filter: IdFilter,
+    /// Language requested for incident details
+    language: String,
+    /// Local cache of raw detail responses
+    detail_cache: Option<detail_cache::DetailCache>,
+    /// Download documents linked in the references
+    attachments: Option<attachments::AttachmentOptions>,
+    /// Destinations of the incidents, the database first
+    sinks: sink::Sinks,
+    /// Called after every stored incident
+    hook: Option<Box<dyn hooks::IncidentHook>>,
+    /// Fail the incident if the hook fails instead of logging a warning
+    strict_hook: bool,
+    /// Check that dates are plausible
+    date_check: Option<DateCheck>,
+    /// Log the rolling request rate this often
+    request_rate_interval: Option<Duration>,
+    /// When to warn that the portal is likely throttling this client
+    throttle: pacing::ThrottleThresholds,
+    /// Time after which no further details are fetched in a run, fetched ones are still stored
+    timeout_budget: Option<Duration>,
+    /// Fetch stored incidents again once they were fetched longer than this ago
+    max_age: Option<Duration>,
+    /// Fetch stored incidents without details again before the new ones
+    resume_incomplete: bool,
+    /// Directory to write the diff manifest of each run to
+    diff_manifest_dir: Option<std::path::PathBuf>,
+    /// Write a Markdown report of every run to this file
+    report_file: Option<std::path::PathBuf>,
+    /// Metrics sent during the run
+    statsd: statsd::Statsd,
+}
+
+/// Write the diff of the incident list against the stored incidents before they are updated
+async fn write_diff_manifest(pool: &sqlx::PgPool, dir: &std::path::Path, incidents: &[Incident]) -> Result<()> {
+    let stored = get_stored_modified_dates(pool).await?;
+    let manifest = manifest::DiffManifest::compute(incidents, &stored, chrono::Utc::now());
+    manifest.write(dir)?;
+    Ok(())
+}
+
+/// Close the sinks before the process exits, keeping the error of the run if it failed
+async fn close_sinks(options: &RunOptions, result: Result<()>) -> Result<()> {
+    let closed = options.sinks.close().await;
+    result.and(closed)
+}
+
+/// Resolves on Ctrl-C or, on Unix, on SIGTERM as sent by container runtimes to stop `--watch`
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// Perform a full fetch-and-store cycle, writing the `--report-file` also if it is aborted by an error
+async fn run(pool: &sqlx::PgPool, options: &RunOptions) -> Result<()> {
+    let mut report = report::RunReport { run_id: options.run_id.clone(), started_at: chrono::Utc::now(), ..Default::default() };
+    let result = match PortalClient::new(options) {
+        Ok(client) => {
+            let result = sync(&client, pool, options, &mut report).await;
+            report.requests = client.pacers.total_requests();
+            report.throttling = client.pacers.throttling();
+            result
+        }
+        Err(err) => Err(err),
+    };
+
+    if let Some(path) = &options.report_file {
+        report.finished_at = chrono::Utc::now();
+        report.error = result.as_ref().err().map(|err| format!("{:#}", err));
+        match report.write(path) {
+            Err(err) if result.is_ok() => return Err(err),
+            // The error of the run matters more than the report
+            Err(err) => warn!("{:#}", err),
+            Ok(()) => {}
+        }
+    }
+    result?;
+    if let Some((id, err)) = report.process.failed.first() {
+        anyhow::bail!("Failed to process incident {}: {}", id, err);
+    }
+    Ok(())
+}
+
+/// Fetch the list, then fetch and store the details of new, stale and incomplete incidents, recording
+/// the outcome in `report`
+async fn sync<H: http::HttpClient>(client: &PortalClient<H>, pool: &sqlx::PgPool, options: &RunOptions, report: &mut report::RunReport) -> Result<()> {
+    let started_at = report.started_at;
+    // A budget too long to represent never elapses
+    let deadline = options.timeout_budget.and_then(|budget| std::time::Instant::now().checked_add(budget));
+    trace!("Fetching existing incidents");
+    let mut existing_ids = telemetry::in_span("get_existing_incident_ids", vec![], options.sinks.existing_ids()).await?;
+    // Stale incidents are fetched again like new ones, updating them in place
+    let stale_ids = match options.max_age {
+        Some(max_age) => options.retry.idempotent("Getting stale incidents", || get_stale_incident_ids(pool, max_age)).await?,
+        None => HashSet::new(),
+    };
+    // Incomplete incidents are fetched again as well, before the new ones
+    let incomplete_ids = if options.resume_incomplete {
+        options.retry.idempotent("Getting incomplete incidents", || get_incomplete_incident_ids(pool)).await?
+    } else {
+        HashSet::new()
+    };
+    existing_ids.retain(|id| !stale_ids.contains(id) && !incomplete_ids.contains(id));
+    // Modified dates before this run, to report changed incidents
+    let stored = if options.report_file.is_some() {
+        options.retry.idempotent("Getting stored modified dates", || get_stored_modified_dates(pool)).await?
+    } else {
+        HashMap::new()
+    };
+    trace!("Fetching incidents from website");
+    // An unchanged list is diffed like a fetched one, an earlier run may have stored the snapshot
+    // but not all of its new incidents, and stale and incomplete incidents are due regardless
+    let (current_incidents, unchanged) = match telemetry::in_span("fetch_incidents", vec![], fetch_incidents(client, pool, options)).await? {
+        Some(incidents) => (incidents, false),
+        None => (get_last_snapshot_incidents(pool, options).await?, true),
+    };
+    if let Some(dir) = &options.diff_manifest_dir {
+        write_diff_manifest(pool, dir, &current_incidents).await?;
+    }
+    report.listed = (!unchanged).then_some(current_incidents.len());
+    report.unchanged = unchanged;
+    report.changed = match &options.report_file {
+        Some(_) => manifest::DiffManifest::compute(&current_incidents, &stored, started_at).changed,
+        None => Vec::new(),
+    };
+
+    // Filter for new incidents
+    let mut new_incidents = model::select_new_incidents(current_incidents, &existing_ids);
+    if options.published_only {
+        let count = new_incidents.len();
+        new_incidents.retain(Incident::is_published);
+        info!("Skipping {} unpublished incidents", count - new_incidents.len());
+    }
+    options.id_filter.apply(&mut new_incidents);
+
+    if let Some(count) = options.sample {
+        new_incidents = sample_incidents(new_incidents, count, options.seed);
+    } else if options.shuffle {
+        shuffle_incidents(&mut new_incidents, options.seed);
+    }
+    if !options.shuffle {
+        order_incidents(&mut new_incidents, options.order);
+    }
+    // Stable, so the order is kept among the incomplete and among the other incidents
+    new_incidents.sort_by_key(|incident| !incomplete_ids.contains(&incident.incident_id));
+
+    let incomplete = new_incidents.iter().filter(|incident| incomplete_ids.contains(&incident.incident_id)).count();
+    if incomplete > 0 {
+        info!("Completing {} incidents stored without details", incomplete);
+    }
+    let resync = new_incidents
+        .iter()
+        .filter(|incident| stale_ids.contains(&incident.incident_id) && !incomplete_ids.contains(&incident.incident_id))
+        .count();
+    if resync > 0 {
+        info!("Re-syncing {} incidents fetched longer than {:?} ago", resync, options.max_age.unwrap_or_default());
+    }
+    let new = new_incidents.len() - incomplete - resync;
+    info!("Found {} new incidents", new);
+    if let Some(max) = options.max_new_incidents.filter(|max| new > *max) {
+        if !options.force_new_incidents {
+            anyhow::bail!(
+                "Found {} new incidents, more than --max-new-incidents {}. Were stored incidents lost? Check the incidents table, use --force if the run is intended",
+                new,
+                max,
+            );
+        }
+        warn!("Found {} new incidents, more than --max-new-incidents {}, processing them because of --force", new, max);
+    }
+
+    let years = new_incidents.iter().map(|incident| incident.org_publish_date.year()).collect();
+    partitioning::ensure_year_partitions(pool, &years).await?;
+    if options.disable_detail_fetch {
+        info!("Skipping detail fetching, storing only the incident list fields");
+    }
+    trace!("Processing {} new incidents: {:?}", new_incidents.len(), new_incidents);
+    report.new = match &options.report_file {
+        Some(_) => new_incidents
+            .iter()
+            .filter(|incident| !stale_ids.contains(&incident.incident_id) && !incomplete_ids.contains(&incident.incident_id))
+            .map(|incident| (incident.incident_id, report::incident_title(&incident.incident_text)))
+            .collect(),
+        None => Vec::new(),
+    };
+    let processed = process_new_incidents(client, stream::iter(new_incidents), pool, options, deadline).await?;
+
+    info!("Processed new incidents: {} succeeded, {} failed, {} skipped", processed.succeeded.len(), processed.failed.len(), processed.skipped.len());
+    if processed.budget_exhausted {
+        warn!("Stopped fetching details as the timeout budget of {:?} elapsed, {} incidents are left for the next run", options.timeout_budget.unwrap_or_default(), processed.skipped.len());
+    }
+    if !processed.skipped.is_empty() {
+        debug!("Skipped incidents: {:?}", processed.skipped);
+    }
+    log_delay_stats(&client.pacers);
+    options.statsd.count("requests", client.pacers.total_requests());
+    report.process = processed;
+
+    Ok(())
+}
+
+/// Print the outcome of a preflight step of `--validate-only`
+fn report_step<T>(validate_only: bool, step: &str, result: Result<T>) -> Result<T> {
+    if validate_only {
+        match &result {
+            Ok(_) => println!("OK   {}", step),
+            Err(err) => println!("FAIL {}: {:#}", step, err),
+        }
+    }
+    result
+}
+
+/// Fetch and parse the incident list once without processing or storing anything
+async fn validate(pool: &sqlx::PgPool, options: &RunOptions) -> Result<()> {
+    let client = report_step(true, "build http client", PortalClient::new(options))?;
+    let incidents = report_step(true, "fetch and parse incident list", fetch_incidents(&client, pool, options).await)?;
+    println!("Incident list contains {} incidents", incidents.map_or(0, |incidents| incidents.len()));
+    Ok(())
+}
+
+/// Report how many listed incidents are new or changed without fetching details or storing incidents
+async fn count_only(pool: &sqlx::PgPool, options: &RunOptions) -> Result<()> {
+    let stored = get_stored_modified_dates(pool).await?;
+    let client = PortalClient::new(options)?;
+    let incidents = match fetch_incidents(&client, pool, options).await? {
+        Some(incidents) => incidents,
+        None => get_last_snapshot_incidents(pool, options).await?,
+    };
+
+    let new = incidents.iter().filter(|incident| !stored.contains_key(&incident.incident_id)).count();
+    let changed = incidents
+        .iter()
+        .filter(|incident| stored.get(&incident.incident_id).is_some_and(|modified_date| *modified_date != incident.modified_date))
+        .count();
+    info!(total = incidents.len(), new, changed, "Counted incidents");
+    println!("total={} new={} changed={}", incidents.len(), new, changed);
+    Ok(())
+}
+
+/// Randomly vary a duration by up to ± `percent` percent
+fn jitter(duration: Duration, percent: u8) -> Duration {
+    if percent == 0 {
+        return duration;
+    }
+    let factor = 1.0 + rand::random_range(-1.0..=1.0) * f64::from(percent) / 100.0;
+    // A huge --interval can't be stretched further, mul_f64 would panic
+    Duration::try_from_secs_f64(duration.as_secs_f64() * factor).unwrap_or(Duration::MAX)
+}
+
+fn parse_latency_factor(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(factor) if factor >= 1.0 && factor.is_finite() => Ok(factor),
+        _ => Err(format!("'{}' is not a factor of at least 1", value)),
+    }
+}
+
+/// Parse a duration like `90`, `30s`, `15m`, `2h` or `1d`, plain numbers are seconds
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number: u64 = number.parse().map_err(|_| format!("invalid duration '{}'", value))?;
+    let factor = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(format!("invalid duration unit '{}', expected one of s, m, h, d", unit)),
+    };
+    let seconds = number.checked_mul(factor).ok_or_else(|| format!("duration '{}' is too long", value))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Longest `--max-age`, far enough back for any stored incident. Larger ones are out of the range of
+/// a Postgres interval or of the timestamp `CURRENT_TIMESTAMP` minus it
+const MAX_AGE_LIMIT: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+fn parse_max_age(value: &str) -> Result<Duration, String> {
+    let max_age = parse_duration(value)?;
+    if max_age > MAX_AGE_LIMIT {
+        return Err(format!("max age '{}' is longer than {}d", value.trim(), MAX_AGE_LIMIT.as_secs() / (24 * 60 * 60)));
+    }
+    Ok(max_age)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let command = clap::builder::Command::new("dsgvo-downloader")
+        .arg(clap::Arg::new("delay")
+            .short('d')
+            .long("delay")
+            .default_value("500")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(u64))
+            .help("Delay time in milliseconds")
+            .long_help("Delay time in milliseconds as to not overwhelm the server and disable the api. Values below 500 are rejected unless --allow-low-delay is given")
+        )
+        .arg(clap::Arg::new("delay-per-host")
+            .long("delay-per-host")
+            .overrides_with("delay-global")
+            .action(clap::ArgAction::SetTrue)
+            .help("Pace every host requests are sent to separately")
+            .long_help("Pace every host requests are sent to separately: each host gets its own --delay, announced rate limit and delay stats, so e.g. a slow document host doesn't slow down the portal. Only makes a difference if requests go to more than one host, e.g. with --fetch-attachments")
+        )
+        .arg(clap::Arg::new("delay-global")
+            .long("delay-global")
+            .overrides_with("delay-per-host")
+            .action(clap::ArgAction::SetTrue)
+            .help("Pace all requests together regardless of their host (default)")
+        )
+        .arg(clap::Arg::new("commit-every")
+            .long("commit-every")
+            .default_value("1")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(u64).range(1..))
+            .help("Commit stored incidents every N incidents")
+            .long_help("Commit stored incidents every N incidents in one transaction instead of each one separately, trading durability for fewer round trips: a crash loses at most N-1 stored incidents, which are fetched again by the next run. The remainder is committed when processing ends or stops early. Stores within a batch aren't retried and --on-stored hooks run before the commit")
+        )
+        .arg(clap::Arg::new("retries")
+            .long("retries")
+            .default_value("0")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(u32))
+            .help("Retry idempotent operations this often on transient failures")
+            .long_help("Retry idempotent operations this often on transient failures: GET requests failing or answered with a server error or 429, read queries and the stores that are deduplicated or transactional upserts. Stores that could write twice are never retried, failed incidents are left to retry-failed")
+        )
+        .arg(clap::Arg::new("retry-backoff")
+            .long("retry-backoff")
+            .default_value("1s")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_duration)
+            .help("Delay before the first retry, doubled for every further one")
+        )
+        .arg(clap::Arg::new("db-retries")
+            .long("db-retries")
+            .default_value("3")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(u32))
+            .help("Retry idempotent database operations this often on errors listed in --db-retry-codes")
+            .long_help("Retry idempotent database operations like storing an incident this often with a short backoff starting at 50ms when they fail with a SQLSTATE listed in --db-retry-codes, independent of --retries. 0 disables these retries")
+        )
+        .arg(clap::Arg::new("db-retry-codes")
+            .long("db-retry-codes")
+            .default_value("40001,40P01")
+            .action(clap::ArgAction::Set)
+            .value_delimiter(',')
+            .value_parser(retry::parse_sqlstate)
+            .help("SQLSTATE codes or classes of database errors retried with --db-retries")
+            .long_help("Comma separated SQLSTATE codes, or two character classes of them, of database errors that are retried with --db-retries, by default serialization failures (40001) and deadlocks (40P01). Other database errors like constraint violations are never retried, lost connections are retried with --retries")
+        )
+        .arg(clap::Arg::new("allow-low-delay")
+            .long("allow-low-delay")
+            .action(clap::ArgAction::SetTrue)
+            .help("Allow a --delay below 500ms")
+            .long_help("Allow a --delay below 500ms, e.g. against a local mock portal. Don't use this against the real portal")
+        )
+        .arg(clap::Arg::new("database-url")
+            .short('u')
+            .long("database-url")
+            .default_value("postgres://postgres@localhost:5432/dsgvo")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("Database URL for a postgres instance")
+            .long_help("Database URL for a postgres instance, the tables have to be preconfigured via `schema.sql`")
+        )
+        .arg(clap::Arg::new("database-password-file")
+            .long("database-password-file")
+            .env("DATABASE_PASSWORD_FILE")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("File to read the database password from")
+            .long_help("File to read the database password from, e.g. a mounted Docker or Kubernetes secret, so the password doesn't appear in the arguments or environment. A trailing newline is ignored. Overrides a password in --database-url")
+        )
+        .arg(clap::Arg::new("row-hmac-key")
+            .long("row-hmac-key")
+            .env("ROW_HMAC_KEY")
+            .hide_env_values(true)
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("Secret key for the row HMACs of stored incidents, checked by verify-hash")
+            .long_help("Secret key for an HMAC-SHA256 over the stored fields of every incident, so rows altered out of band can be detected with verify-hash. Prefer passing it via ROW_HMAC_KEY or --row-hmac-key-file so it doesn't appear in the process list. Rows aren't signed if not given")
+        )
+        .arg(clap::Arg::new("row-hmac-key-file")
+            .long("row-hmac-key-file")
+            .env("ROW_HMAC_KEY_FILE")
+            .conflicts_with("row-hmac-key")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("File to read the --row-hmac-key from, a trailing newline is ignored")
+        )
+        .arg(clap::Arg::new("db-idle-timeout")
+            .long("db-idle-timeout")
+            .default_value("5m")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_duration)
+            .help("Close database connections that are idle for longer than this")
+        )
+        .arg(clap::Arg::new("db-max-lifetime")
+            .long("db-max-lifetime")
+            .default_value("30m")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_duration)
+            .help("Replace database connections older than this")
+        )
+        .arg(clap::Arg::new("db-test-before-acquire")
+            .long("db-test-before-acquire")
+            .default_value("true")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(bool))
+            .help("Check database connections before using them")
+            .long_help("Check database connections before using them, so connections closed by the server are replaced instead of failing the next query")
+        )
+        .arg(clap::Arg::new("db-statement-timeout")
+            .long("db-statement-timeout")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_duration)
+            .help("Abort database statements running longer than this")
+            .long_help("Abort database statements running longer than this via Postgres' statement_timeout, e.g. `30s`. A store exceeding it fails the incident instead of hanging the run. Disabled if not given")
+        )
+        .arg(clap::Arg::new("db-schema")
+            .long("db-schema")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_schema_name)
+            .help("Postgres schema of the tables instead of public")
+            .long_help("Postgres schema of the tables instead of public, set as search_path of every connection, e.g. to keep the mirror apart from other data. The schema must exist, migrate creates the tables in it. Only lowercase identifiers are accepted")
+        )
+        .arg(clap::Arg::new("base-url")
+            .long("base-url")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("Base URL of the portal, overrides the one of the portal profile")
+            .long_help("Base URL of the portal, all endpoints and referers are composed from it. Overrides the base URL of --portal-profile (default: https://www.dsgvo-portal.de). Useful for mirrors or a mock portal")
+        )
+        .arg(clap::Arg::new("portal-profile")
+            .long("portal-profile")
+            .default_value(profile::DEFAULT_PROFILE)
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("Named endpoint layout of the portal")
+            .long_help("Named endpoint layout of the portal bundling the base URL, endpoint paths, referers and field mapping. Built in is `dsgvo-portal`, more can be defined with --portal-profiles")
+        )
+        .arg(clap::Arg::new("portal-profiles")
+            .long("portal-profiles")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("JSON file with additional portal profiles")
+            .long_help("JSON file with additional portal profiles by name, e.g. {\"sister\": {\"base_url\": \"https://example.org\", \"incident_detail_path\": \"/api/incidents/{id}\"}}. Fields that aren't given use the values of the built-in dsgvo-portal profile, a profile with a built-in name replaces it")
+        )
+        .arg(clap::Arg::new("detail-url-template")
+            .long("detail-url-template")
+            .action(clap::ArgAction::Set)
+            .value_parser(profile::parse_detail_url_template)
+            .help("Path of the incident details with an {id} placeholder, overrides the one of the portal profile")
+            .long_help("Path of the incident details relative to the base URL, {id} is replaced by the incident id, e.g. /api/incidents/{id}. Overrides incident_detail_path of --portal-profile (default: /sicherheitsvorfall-datenbank/incidentDetails.php?incident={id}), so a changed detail endpoint can be followed without a profiles file")
+        )
+        .arg(clap::Arg::new("http-version")
+            .long("http-version")
+            .default_value("auto")
+            .action(clap::ArgAction::Set)
+            .value_parser(["auto", "1", "2"])
+            .help("HTTP version to use")
+            .long_help("HTTP version to use, `auto` uses HTTP/2 if the server offers it via ALPN and HTTP/1.1 otherwise, `2` forces HTTP/2 with prior knowledge and fails against HTTP/1.1-only servers")
+        )
+        .arg(clap::Arg::new("http-pool-idle-timeout")
+            .long("http-pool-idle-timeout")
+            .default_value("30s")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_duration)
+            .help("Close http connections that are idle for longer than this")
+            .long_help("Close http connections that are idle for longer than this, so a connection the portal already closed isn't reused for the first request of the next watch cycle. Should be shorter than the keep-alive timeout of the portal")
+        )
+        .arg(clap::Arg::new("http-pool-max-idle")
+            .long("http-pool-max-idle")
+            .default_value("4")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(usize))
+            .help("Maximum idle http connections kept per host")
+        )
+        .arg(clap::Arg::new("bind-address")
+            .long("bind-address")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::net::IpAddr))
+            .help("Local IP address to send requests from")
+            .long_help("Local IP address to send requests from, e.g. for egress firewall rules that only allow a specific source IP on multi-homed hosts. Connections are limited to the IP family of the address unless --ip-family is given")
+        )
+        .arg(clap::Arg::new("ip-family")
+            .long("ip-family")
+            .action(clap::ArgAction::Set)
+            .value_parser(["any", "4", "6"])
+            .help("Connect via IPv4 or IPv6 only")
+            .long_help("Connect via IPv4 (`4`) or IPv6 (`6`) only, by only using resolved addresses of that family. `any` (the default without --bind-address) uses both")
+        )
+        .arg(clap::Arg::new("tor")
+            .long("tor")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("ip-family")
+            .help("Route all requests through the SOCKS5 proxy of a local Tor client")
+            .long_help("Route all requests through the SOCKS5 proxy of a local Tor client at --tor-proxy. Host names are resolved by Tor. Fails at startup if the proxy isn't reachable")
+        )
+        .arg(clap::Arg::new("tor-proxy")
+            .long("tor-proxy")
+            .default_value("127.0.0.1:9050")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::net::SocketAddr))
+            .help("Address of the Tor SOCKS5 proxy used with --tor")
+        )
+        .arg(clap::Arg::new("tor-isolate")
+            .long("tor-isolate")
+            .action(clap::ArgAction::SetTrue)
+            .requires("tor")
+            .help("Use a separate Tor circuit for every request")
+            .long_help("Use a separate Tor circuit for every request via random SOCKS credentials, so requests can't be linked by their exit node. Disables connection reuse and is considerably slower")
+        )
+        .arg(clap::Arg::new("pinned-cert")
+            .long("pinned-cert")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("Only trust this PEM certificate or CA for the portal host")
+            .long_help("Only trust this PEM certificate for connections to the portal host instead of the system's CAs, so an interceptor with an otherwise valid certificate is rejected with a pinning violation. Give the portal's certificate if it is self-signed, otherwise the CA that issued it. Requests to other hosts, e.g. of attachments, use the system's CAs. Requires an https base URL")
+        )
+        .arg(clap::Arg::new("auth-header")
+            .long("auth-header")
+            .env("PORTAL_AUTH_HEADER")
+            .hide_env_values(true)
+            .action(clap::ArgAction::Append)
+            // Parsed after clap, whose errors would contain the secret value
+            .value_parser(value_parser!(String))
+            .value_name("NAME: VALUE")
+            .help("Header authenticating requests to the portal, e.g. 'X-Api-Key: ...', repeatable")
+            .long_help("Header authenticating requests to the portal, e.g. 'X-Api-Key: ...', repeatable. Only sent to the portal host, never to the hosts of attachments, and never logged. Prefer PORTAL_AUTH_HEADER or --auth-header-file so the secret doesn't appear in the process list")
+        )
+        .arg(clap::Arg::new("auth-header-file")
+            .long("auth-header-file")
+            .env("PORTAL_AUTH_HEADER_FILE")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("File with headers authenticating requests to the portal, one 'Name: value' per line")
+        )
+        .arg(clap::Arg::new("bearer-token")
+            .long("bearer-token")
+            .env("PORTAL_BEARER_TOKEN")
+            .hide_env_values(true)
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("Token sent as 'Authorization: Bearer <token>' to the portal")
+            .long_help("Token sent as 'Authorization: Bearer <token>' to the portal host and never logged. Prefer PORTAL_BEARER_TOKEN or --bearer-token-file so it doesn't appear in the process list")
+        )
+        .arg(clap::Arg::new("bearer-token-file")
+            .long("bearer-token-file")
+            .env("PORTAL_BEARER_TOKEN_FILE")
+            .conflicts_with("bearer-token")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("File to read the --bearer-token from, a trailing newline is ignored")
+        )
+        .arg(clap::Arg::new("incidents-file")
+            .long("incidents-file")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("Read the incident list from a file instead of the website")
+            .long_help("Read the incident list JSON from a local file instead of the website, details are still fetched from --base-url. Useful to reproduce a specific list state")
+        )
+        .arg(clap::Arg::new("detail-cache")
+            .long("detail-cache")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("SQLite file caching fetched incident details")
+            .long_help("SQLite file caching raw incident detail responses by incident id, --language and modified date, so retries and restarts within --detail-cache-ttl don't fetch the same details again. Disabled if not given")
+        )
+        .arg(clap::Arg::new("detail-cache-ttl")
+            .long("detail-cache-ttl")
+            .default_value("1h")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_duration)
+            .help("Time cached incident details stay valid")
+        )
+        .arg(clap::Arg::new("log-request-rate")
+            .long("log-request-rate")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_duration)
+            .help("Log the requests of the last minute at this interval, e.g. 1m")
+            .long_help("Log the achieved request rate (requests within the last minute) at this interval during a run, e.g. `1m`, to check that --delay produces the intended load. The average rate of a run is always logged at its end")
+        )
+        .arg(clap::Arg::new("throttle-warn-responses")
+            .long("throttle-warn-responses")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(u32))
+            .default_value("3")
+            .help("Warn that the portal is likely throttling after this many 429/503 responses, 0 disables it")
+            .long_help("Warn that the portal is likely throttling this client after this many responses with status 429 or 503 in a run, also if retries recovered from them, suggesting a higher --delay. The warning is repeated at the end of the run and in --report-file. 0 disables the check")
+        )
+        .arg(clap::Arg::new("throttle-warn-latency-factor")
+            .long("throttle-warn-latency-factor")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_latency_factor)
+            .default_value("3")
+            .help("Warn that the portal is likely throttling once response times grow by this factor")
+            .long_help("Warn that the portal is likely throttling this client once the average time of the last 5 responses exceeds that of the first 5 responses of the run by this factor and is at least a second")
+        )
+        .arg(clap::Arg::new("timeout-budget")
+            .long("timeout-budget")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_duration)
+            .help("Stop fetching details once this much time of the run elapsed")
+            .long_help("Time budget for fetching the incident list and details of a run, e.g. `15m`. Once it elapsed no further details are fetched, but incidents whose details were already fetched are still stored. The remaining incidents are left for the next run")
+        )
+        .arg(clap::Arg::new("max-age")
+            .long("max-age")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_max_age)
+            .help("Fetch stored incidents again once they were fetched longer than this ago")
+            .long_help("Fetch listed incidents again that were stored longer than this ago, e.g. `30d`, regardless of their modifiedDate, to catch upstream changes the portal didn't reflect in it. They are updated in place, keeping a revision if anything changed. Incidents stored before fetch times were recorded count as stale. Also applies when the incident list is unchanged, using the last snapshot. At most 36500d")
+        )
+        .arg(clap::Arg::new("resume-incomplete")
+            .long("resume-incomplete")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("disable-detail-fetch")
+            .help("Complete listed incidents stored without details before processing new ones")
+            .long_help("Fetch listed incidents again that are stored without details, e.g. left by an interrupted run or stored with --disable-detail-fetch, and complete them before processing new incidents")
+        )
+        .arg(clap::Arg::new("record-config")
+            .long("record-config")
+            .action(clap::ArgAction::SetTrue)
+            .help("Record the effective configuration of the run in run_config")
+            .long_help("Record the resolved value and source (default, env or command line) of every option together with the run id in the run_config table, so the settings behind a run can be looked up later. The password of --database-url and other URLs and --row-hmac-key are redacted")
+        )
+        .arg(clap::Arg::new("statsd-addr")
+            .long("statsd-addr")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("Send metrics to a statsd or dogstatsd endpoint over UDP, e.g. `localhost:8125`")
+            .long_help("Send metrics to a statsd or dogstatsd endpoint over UDP during the run, e.g. `localhost:8125`, for cron jobs that can't be scraped: counters of succeeded, failed and skipped incidents and sent requests and timers of the incident list and detail requests. Metrics are best-effort, failures to send them are only logged at debug level")
+        )
+        .arg(clap::Arg::new("statsd-prefix")
+            .long("statsd-prefix")
+            .default_value("dsgvo_downloader")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("Prefix of the metric names sent to --statsd-addr")
+        )
+        .arg(clap::Arg::new("report-file")
+            .long("report-file")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("Write a human-readable Markdown report of the run to this file")
+            .long_help("Write a human-readable Markdown report of the run to this file: a summary, the new incidents with their titles, changed incidents, failures with their reasons and the duration. Written on failed runs too, also if an error aborts the run, e.g. an unreachable portal, in watch mode every cycle overwrites it")
+        )
+        .arg(clap::Arg::new("diff-manifest-dir")
+            .long("diff-manifest-dir")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("Write the ids of new, changed and removed incidents of each run to this directory")
+            .long_help("Write a JSON manifest `diff-<timestamp>.json` per run to this directory, listing the ids of new incidents, changed incidents with their old and new modifiedDate, and stored incidents that are no longer listed")
+        )
+        .arg(clap::Arg::new("disable-detail-fetch")
+            .long("disable-detail-fetch")
+            .action(clap::ArgAction::SetTrue)
+            .help("Only store the incident list fields without fetching details")
+            .long_help("Only store the fields of the incident list, the detail columns of incidents stay NULL. A single request per run instead of one per incident. Incidents stored this way aren't fetched again by later runs with details")
+        )
+        .arg(clap::Arg::new("run-id")
+            .long("run-id")
+            .env("RUN_ID")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("Id of this invocation, generated if not given")
+            .long_help("Id of this invocation, generated from the start time if not given. It is included in every log line as `run_id`. Raw snapshots are stored with the run id and fetch sequence as idempotency key, so a job retried with the same run id doesn't store the same snapshot twice")
+        )
+        .arg(clap::Arg::new("max-incident-text-bytes")
+            .long("max-incident-text-bytes")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(usize))
+            .help("Truncate incident_text and details_text of incidents to this many bytes")
+            .long_help("Truncate incident_text and details_text (and their plain text versions) stored in incidents to this many bytes, so a single enormous incident can't bloat the table and its search index. The original length of a truncated text is stored in incident_text_original_bytes or details_text_original_bytes, the full texts are kept in incident_details and the raw history. Disabled if not given")
+        )
+        .arg(clap::Arg::new("strip-html")
+            .long("strip-html")
+            .action(clap::ArgAction::SetTrue)
+            .help("Also store plain text versions of incident_text and details_text")
+            .long_help("Also store plain text versions of the HTML in incident_text and details_text in incident_text_plain and details_text_plain, for cleaner full-text search and display. Malformed HTML is converted as well as possible, the raw texts are kept")
+        )
+        .arg(clap::Arg::new("store-raw-details")
+            .long("store-raw-details")
+            .action(clap::ArgAction::SetTrue)
+            .help("Store the raw detail responses in detail_history")
+            .long_help("Store every incident detail response fetched from the portal in detail_history before parsing, a complete audit trail of the details that allows reparsing them after a parser fix")
+        )
+        .arg(clap::Arg::new("revalidate-details")
+            .long("revalidate-details")
+            .requires("store-raw-details")
+            .action(clap::ArgAction::SetTrue)
+            .help("Send conditional detail requests using the validators of the last stored raw detail")
+            .long_help("Send If-None-Match/If-Modified-Since with the ETag and Last-Modified of the last raw detail of an incident in detail_history. A detail the portal answers with 304 Not Modified is parsed from detail_history instead of being downloaded and stored again, which saves portal load when refetching unchanged incidents. Requires --store-raw-details")
+        )
+        .arg(clap::Arg::new("sink")
+            .long("sink")
+            .action(clap::ArgAction::Append)
+            .value_parser(["jsonl"])
+            .help("Additional destination of stored incidents, can be given multiple times")
+            .long_help("Additional destination of stored incidents, can be given multiple times. The database is always written. `jsonl` writes every stored incident as a line of `{\"incident\": ..., \"details\": [...]}` to stdout, e.g. to pipe into a message queue")
+        )
+        .arg(clap::Arg::new("kafka-brokers")
+            .long("kafka-brokers")
+            .action(clap::ArgAction::Set)
+            .value_delimiter(',')
+            .value_parser(value_parser!(String))
+            .help("Publish stored incidents to Kafka via these comma-separated brokers (host:port)")
+            .long_help("Publish every stored incident as a JSON message of `{\"incident\": ..., \"details\": [...]}` keyed by the incident id to partition 0 of --kafka-topic via these comma-separated brokers (host:port). Messages are sent whenever stored incidents are committed, see --commit-every. While the brokers are unavailable incidents are buffered and a warning is logged, the run doesn't fail. Requires a build with the `kafka` feature")
+        )
+        .arg(clap::Arg::new("kafka-topic")
+            .long("kafka-topic")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .default_value("dsgvo-incidents")
+            .requires("kafka-brokers")
+            .help("Kafka topic stored incidents are published to")
+        )
+        .arg(clap::Arg::new("on-stored")
+            .long("on-stored")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("Shell command run after every stored incident")
+            .long_help("Shell command run after every stored incident, e.g. for enrichment, indexing or notifications. It gets `{\"incident\": ..., \"details\": [...]}` as JSON on stdin and the incident id in INCIDENT_ID. Failures are logged unless --strict-hook is given")
+        )
+        .arg(clap::Arg::new("strict-hook")
+            .long("strict-hook")
+            .action(clap::ArgAction::SetTrue)
+            .help("Fail the incident if the --on-stored command fails")
+            .long_help("Fail the incident if the --on-stored command fails, it is then retried by retry-failed. The incident itself is stored already")
+        )
+        .arg(clap::Arg::new("fetch-attachments")
+            .long("fetch-attachments")
+            .action(clap::ArgAction::SetTrue)
+            .help("Download documents linked in the references of new incidents")
+            .long_help("Download documents (PDF, Word, OpenDocument, RTF, plain text) linked in the references of new incidents into incident_attachments, honouring robots.txt and --delay. Other links are skipped. This can grow the database considerably")
+        )
+        .arg(clap::Arg::new("attachment-max-bytes")
+            .long("attachment-max-bytes")
+            .default_value("10485760")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(u64))
+            .help("Skip attachments larger than this many bytes")
+        )
+        .arg(clap::Arg::new("attachment-dir")
+            .long("attachment-dir")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("Store attachments as files in this directory instead of in the database")
+            .long_help("Store attachments as files named by their SHA-256 in this directory instead of as bytea in incident_attachments, which then only records the path")
+        )
+        .arg(clap::Arg::new("language")
+            .long("language")
+            .default_value(DEFAULT_DETAIL_LANGUAGE)
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("Language requested for incident details via Accept-Language")
+            .long_help("Language requested for incident details via Accept-Language. For languages other than `de` the details text is read from `description_<language>`, falling back to `description_de` if the portal doesn't provide the translation")
+        )
+        .arg(clap::Arg::new("field-mapping")
+            .long("field-mapping")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("JSON file mapping field names to the portal's current keys")
+            .long_help("JSON file mapping canonical field names to the keys the portal currently uses, e.g. {\"incident\": {\"incident_id\": \"incidentId\"}, \"detail\": {\"details_text\": \"description\"}}. Allows adapting to renamed fields without a new release, unmapped fields use the built-in keys")
+        )
+        .arg(clap::Arg::new("no-raw-store")
+            .long("no-raw-store")
+            .action(clap::ArgAction::SetTrue)
+            .help("Don't store the raw incident list in incident_history")
+            .long_help("Don't store the raw incident list in incident_history to reduce database growth. Without the raw snapshots past runs can't be reparsed or replayed later and conditional requests are disabled")
+        )
+        .arg(clap::Arg::new("require-raw-store")
+            .long("require-raw-store")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("no-raw-store")
+            .help("Fail the run if the raw incident list can't be stored")
+            .long_help("Fail the run if the raw incident list can't be stored in incident_history, e.g. because the table is missing. By default such a failure is logged as a warning and the incidents are processed anyway")
+        )
+        .arg(clap::Arg::new("compress-history")
+            .long("compress-history")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("no-raw-store")
+            .help("Store the raw incident list gzip-compressed")
+            .long_help("Store the raw incident list gzip-compressed in incident_history.content_gzip instead of as JSONB in content. Saves a lot of space when every snapshot is kept, but the snapshots can't be queried in SQL anymore. Conditional requests, flatten-history and compact-history decompress them transparently")
+        )
+        .arg(clap::Arg::new("sample")
+            .long("sample")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(usize))
+            .help("Only process N randomly selected new incidents")
+            .long_help("Only process N randomly selected new incidents, useful to spot-check parsing across the whole id range without a full run")
+        )
+        .arg(clap::Arg::new("max-new-incidents")
+            .long("max-new-incidents")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(usize))
+            .help("Abort the run if more than N new incidents are found")
+            .long_help("Abort the run before any detail is fetched if more than N new incidents are found, e.g. because the incidents table was truncated or the ids can't be read and every listed incident looks new. Protects the portal from a runaway run. Re-synced and incomplete incidents don't count, use --force for an intended large run like the first one")
+        )
+        .arg(clap::Arg::new("force")
+            .long("force")
+            .action(clap::ArgAction::SetTrue)
+            .requires("max-new-incidents")
+            .help("Process the new incidents even if there are more than --max-new-incidents")
+        )
+        .arg(clap::Arg::new("shuffle")
+            .long("shuffle")
+            .action(clap::ArgAction::SetTrue)
+            .help("Process new incidents in random order")
+            .long_help("Process new incidents in random order instead of a long monotonic run of sequential ids, which looks bot-like")
+        )
+        .arg(clap::Arg::new("published-only")
+            .long("published-only")
+            .action(clap::ArgAction::SetTrue)
+            .help("Skip incidents that aren't published")
+            .long_help("Skip new incidents whose published flag isn't 1, as unpublished ones may be drafts or retracted")
+        )
+        .arg(clap::Arg::new("mark-unpublished")
+            .long("mark-unpublished")
+            .action(clap::ArgAction::SetTrue)
+            .help("Record when a stored incident is unpublished in unpublished_at")
+            .long_help("Record when the published flag of a stored incident goes from 1 to something else in incidents.unpublished_at, and clear it when the incident is published again. Changes of the published flag are always logged as separate events, with this flag they can also be queried")
+        )
+        .arg(clap::Arg::new("include-id")
+            .long("include-id")
+            .action(clap::ArgAction::Append)
+            .value_parser(value_parser!(i32))
+            .help("Only process this incident, can be repeated")
+            .long_help("Only process the given incidents, can be repeated and combined with --include-ids-file. --exclude-id takes precedence")
+        )
+        .arg(clap::Arg::new("include-ids-file")
+            .long("include-ids-file")
+            .action(clap::ArgAction::Append)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("Only process the incidents listed in this file, one id per line")
+        )
+        .arg(clap::Arg::new("exclude-id")
+            .long("exclude-id")
+            .action(clap::ArgAction::Append)
+            .value_parser(value_parser!(i32))
+            .help("Never process this incident, can be repeated")
+            .long_help("Never process the given incidents, e.g. a known-broken incident until it's fixed upstream. Can be repeated and combined with --exclude-ids-file")
+        )
+        .arg(clap::Arg::new("exclude-ids-file")
+            .long("exclude-ids-file")
+            .action(clap::ArgAction::Append)
+            .value_parser(value_parser!(std::path::PathBuf))
+            .help("Never process the incidents listed in this file, one id per line")
+        )
+        .arg(clap::Arg::new("order")
+            .long("order")
+            .default_value("as-is")
+            .action(clap::ArgAction::Set)
+            .value_parser(["as-is", "id-asc", "id-desc", "date"])
+            .conflicts_with("shuffle")
+            .help("Order in which new incidents are processed")
+            .long_help("Order in which new incidents are processed: `as-is` keeps the portal's order, `id-asc`/`id-desc` sort by incident id and `date` by orgPublishDate. A fixed order makes backfills reproducible and resumable")
+        )
+        .arg(clap::Arg::new("seed")
+            .long("seed")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(u64))
+            .help("Seed for --sample and --shuffle")
+            .long_help("Seed for --sample and --shuffle, a random seed is chosen and logged if not given so a run can be reproduced")
+        )
+        .arg(clap::Arg::new("log-format")
+            .long("log-format")
+            .default_value("text")
+            .action(clap::ArgAction::Set)
+            .value_parser(["text", "json"])
+            .help("Format of the logs")
+            .long_help("Format of the logs, `json` writes one JSON object per line including the structured fields of each event")
+        )
+        .arg(clap::Arg::new("environment")
+            .long("environment")
+            .env("DSGVO_ENVIRONMENT")
+            .default_value("development")
+            .action(clap::ArgAction::Set)
+            .value_parser(["development", "production"])
+            .help("Deployment environment, `production` turns on --redact-logs by default")
+            .long_help("Deployment environment the tool runs in. `production` turns on privacy defaults, currently --redact-logs, which can be turned off again with --no-redact-logs")
+        )
+        .arg(clap::Arg::new("redact-logs")
+            .long("redact-logs")
+            .overrides_with("no-redact-logs")
+            .action(clap::ArgAction::SetTrue)
+            .help("Replace incident and details texts in logs by a length/hash placeholder (default in production)")
+            .long_help("Replace incident_text, details_text and raw detail responses in all log output by a placeholder with their length and a hash prefix, as they may contain personal data. On by default with --environment production")
+        )
+        .arg(clap::Arg::new("no-redact-logs")
+            .long("no-redact-logs")
+            .overrides_with("redact-logs")
+            .action(clap::ArgAction::SetTrue)
+            .help("Log incident and details texts verbatim, also with --environment production")
+        )
+        .arg(clap::Arg::new("no-color")
+            .long("no-color")
+            .action(clap::ArgAction::SetTrue)
+            .help("Disable colored log output")
+            .long_help("Disable colored log output, colors are only used when logging to a terminal and can also be disabled via the NO_COLOR env var")
+        )
+        .arg(clap::Arg::new("once")
+            .long("once")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("watch")
+            .help("Perform a single run and exit (default)")
+        )
+        .arg(clap::Arg::new("watch")
+            .long("watch")
+            .action(clap::ArgAction::SetTrue)
+            .help("Keep running and repeat the run every --interval")
+            .long_help("Keep running and repeat the run every --interval, a failed run is logged and retried in the next cycle. Ctrl-C or SIGTERM finish the current cycle and close the sinks")
+        )
+        .arg(clap::Arg::new("interval")
+            .long("interval")
+            .default_value("1h")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_duration)
+            .help("Time between runs in watch mode")
+            .long_help("Time between runs in watch mode, e.g. `90`, `30s`, `15m`, `2h` or `1d`, plain numbers are seconds")
+        )
+        .arg(clap::Arg::new("interval-jitter")
+            .long("interval-jitter")
+            .default_value("0")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(u8).range(0..=100))
+            .help("Randomly vary the watch interval by up to ± this percentage")
+            .long_help("Randomly vary the watch interval by up to ± this percentage, so multiple instances don't hit the portal at the same time")
+        )
+        .arg(clap::Arg::new("check-consistency")
+            .long("check-consistency")
+            .action(clap::ArgAction::SetTrue)
+            .help("Warn when an incident and its details diverge")
+            .long_help("Warn when the incident text from the incident list and the overlapping detail fields diverge, which can reveal portal inconsistencies or mapping bugs")
+        )
+        .arg(clap::Arg::new("strict-consistency")
+            .long("strict-consistency")
+            .action(clap::ArgAction::SetTrue)
+            .help("Fail incidents whose details diverge")
+            .long_help("Like --check-consistency, but fail the incident instead of only logging a warning")
+        )
+        .arg(clap::Arg::new("warn-on-empty-result")
+            .long("warn-on-empty-result")
+            .action(clap::ArgAction::SetTrue)
+            .help("Warn when the incident list has fewer than --min-incidents incidents")
+            .long_help("Warn when the incident list parses but has fewer than --min-incidents incidents, which usually means the portal changed its response in a way that still parses")
+        )
+        .arg(clap::Arg::new("strict-empty-result")
+            .long("strict-empty-result")
+            .action(clap::ArgAction::SetTrue)
+            .help("Fail when the incident list has fewer than --min-incidents incidents")
+            .long_help("Like --warn-on-empty-result, but fail the run instead of only logging a warning")
+        )
+        .arg(clap::Arg::new("min-incidents")
+            .long("min-incidents")
+            .default_value("1")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(usize))
+            .help("Smallest expected number of incidents in the list")
+        )
+        .arg(clap::Arg::new("strict-dates")
+            .long("strict-dates")
+            .num_args(0..=1)
+            .default_missing_value("skip")
+            .require_equals(true)
+            .action(clap::ArgAction::Set)
+            .value_parser(["skip", "fail"])
+            .help("Reject incidents with dates outside --min-date..=now+--max-date-ahead")
+            .long_help("Check that org_publish_date, modified_date and publish_date lie within --min-date..=now+--max-date-ahead. `--strict-dates` or `--strict-dates=skip` logs a warning with the offending field and value and doesn't store the incident, `--strict-dates=fail` fails it")
+        )
+        .arg(clap::Arg::new("min-date")
+            .long("min-date")
+            .default_value("2015-01-01")
+            .action(clap::ArgAction::Set)
+            .value_parser(|value: &str| chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|err| err.to_string()))
+            .help("Earliest plausible date for --strict-dates, as YYYY-MM-DD")
+        )
+        .arg(clap::Arg::new("max-date-ahead")
+            .long("max-date-ahead")
+            .default_value("1d")
+            .action(clap::ArgAction::Set)
+            .value_parser(parse_duration)
+            .help("How far in the future dates are plausible for --strict-dates")
+        )
+        .arg(clap::Arg::new("validate-schema")
+            .long("validate-schema")
+            .action(clap::ArgAction::SetTrue)
+            .help("Validate responses against the embedded JSON schemas")
+            .long_help("Validate responses against the embedded JSON schemas before parsing and log violations, to catch format changes of the portal early")
+        )
+        .arg(clap::Arg::new("strict-schema")
+            .long("strict-schema")
+            .action(clap::ArgAction::SetTrue)
+            .help("Fail responses that violate the embedded JSON schemas")
+            .long_help("Like --validate-schema, but fail instead of only logging the violations")
+        )
+        .arg(clap::Arg::new("auto-migrate")
+            .long("auto-migrate")
+            .action(clap::ArgAction::SetTrue)
+            .help("Apply outstanding schema migrations before running")
+        )
+        .arg(clap::Arg::new("validate-only")
+            .long("validate-only")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("auto-migrate")
+            .help("Check database, schema and incident list, then exit")
+            .long_help("Run the preflight steps, connecting to the database, verifying the schema and fetching and parsing the incident list once, report OK/FAIL for each step and exit without processing or storing anything")
+        )
+        .arg(clap::Arg::new("count-only")
+            .long("count-only")
+            .action(clap::ArgAction::SetTrue)
+            .help("Report how many incidents are new or changed, then exit")
+            .long_help("Fetch the incident list and print the number of total, new and changed (by modifiedDate) incidents as `total=N new=N changed=N`, without fetching details or storing incidents. The raw list is still stored unless --no-raw-store is given")
+        )
+        .subcommand(clap::builder::Command::new("print-urls")
+            .about("Print the endpoints that would be used and exit, without any network or database access")
+        )
+        .subcommand(clap::builder::Command::new("print-schema")
+            .about("Print the database schema this binary expects and exit, e.g. to pipe into psql")
+        )
+        .subcommand(clap::builder::Command::new("migrate")
+            .about("Apply outstanding schema migrations and exit")
+            .arg(clap::Arg::new("partition-by-year")
+                .long("partition-by-year")
+                .action(clap::ArgAction::SetTrue)
+                .help("Convert incidents into a table partitioned by year of orgPublishDate")
+                .long_help("Convert incidents into a table partitioned by year of orgPublishDate after migrating, moving all stored incidents. Partitions for new years are created before storing. The foreign key of incident_revisions is dropped, as a partitioned table can't have a unique constraint on incident_id alone")
+            )
+        )
+        .subcommand(clap::builder::Command::new("export")
+            .about("Export stored incidents as JSON lines")
+            .arg(clap::Arg::new("output")
+                .short('o')
+                .long("output")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(std::path::PathBuf))
+                .help("File to write to, defaults to stdout")
+            )
+            .arg(clap::Arg::new("redact")
+                .long("redact")
+                .action(clap::ArgAction::SetTrue)
+                .requires("redact-salt")
+                .help("Pseudonymize potentially personal fields")
+                .long_help("Pseudonymize potentially personal fields by replacing them with a salted HMAC-SHA256, so the same value always maps to the same hash. Redaction is best-effort, personal data can still be contained in fields that are not redacted")
+            )
+            .arg(clap::Arg::new("redact-fields")
+                .long("redact-fields")
+                .action(clap::ArgAction::Set)
+                .value_delimiter(',')
+                .default_values(export::DEFAULT_REDACT_FIELDS)
+                .help("Comma separated list of fields to redact")
+            )
+            .arg(clap::Arg::new("redact-salt")
+                .long("redact-salt")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(String))
+                .help("Secret salt for --redact")
+                .long_help("Secret salt for --redact, keep it private and reuse it to get consistent hashes across exports")
+            )
+            .arg(clap::Arg::new("format")
+                .long("format")
+                .default_value("jsonl")
+                .action(clap::ArgAction::Set)
+                .value_parser(["jsonl", "parquet"])
+                .help("Output format, `parquet` requires the `parquet` feature")
+                .long_help("Output format: JSON lines (`jsonl`) or a Snappy-compressed Parquet file (`parquet`) for DuckDB, Spark and other analytics tools. `parquet` is only available in builds with the `parquet` feature")
+            )
+            .arg(clap::Arg::new("order-by")
+                .long("order-by")
+                .default_value("id")
+                .action(clap::ArgAction::Set)
+                .value_parser(["id", "publish_date", "country"])
+                .help("Order of the exported incidents")
+            )
+            .arg(clap::Arg::new("descending")
+                .long("descending")
+                .action(clap::ArgAction::SetTrue)
+                .help("Export in descending order, e.g. the most recent incidents first")
+            )
+            .arg(clap::Arg::new("limit")
+                .long("limit")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(i64).range(0..))
+                .help("Maximum number of incidents to export")
+            )
+            .arg(clap::Arg::new("offset")
+                .long("offset")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(i64).range(0..))
+                .help("Number of incidents to skip, for paging through an export with --limit")
+            )
+            .arg(clap::Arg::new("since")
+                .long("since")
+                .action(clap::ArgAction::Set)
+                .value_parser(export::parse_since)
+                .help("Only export incidents stored after this RFC 3339 timestamp or YYYY-MM-DD date")
+                .long_help("Only export incidents stored or updated after this RFC 3339 timestamp or YYYY-MM-DD date (midnight UTC), by their fetched_at. Incidents stored before fetched_at existed are only contained in full exports")
+            )
+            .arg(clap::Arg::new("incremental")
+                .long("incremental")
+                .action(clap::ArgAction::Set)
+                .num_args(0..=1)
+                .default_missing_value("default")
+                .value_name("NAME")
+                .conflicts_with_all(["since", "limit", "offset"])
+                .help("Only export incidents stored since the last incremental export with the same name")
+                .long_help("Only export incidents stored or updated since the last incremental export with the same name (default: `default`), tracked by a watermark in export_watermarks that is advanced once the output is written. The first export exports everything. Use separate names for separate downstream systems")
+            )
+        )
+        .subcommand(clap::builder::Command::new("search")
+            .about("Full-text search over the stored incident texts")
+            .arg(clap::Arg::new("query")
+                .required(true)
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(String))
+                .help("Search terms")
+            )
+            .arg(clap::Arg::new("language")
+                .long("language")
+                .default_value(search::DEFAULT_LANGUAGE)
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(String))
+                .help("Postgres text search configuration")
+                .long_help("Postgres text search configuration, the index is only used for `german` since the data is primarily German")
+            )
+            .arg(clap::Arg::new("limit")
+                .long("limit")
+                .default_value("20")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(i64))
+                .help("Maximum number of results")
+            )
+        )
+        .subcommand(clap::builder::Command::new("list")
+            .about("List the stored incidents published in a date range")
+            .arg(clap::Arg::new("from")
+                .long("from")
+                .action(clap::ArgAction::Set)
+                .value_parser(|value: &str| chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|err| err.to_string()))
+                .help("First publish date to list, e.g. 2024-01-01")
+            )
+            .arg(clap::Arg::new("to")
+                .long("to")
+                .action(clap::ArgAction::Set)
+                .value_parser(|value: &str| chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|err| err.to_string()))
+                .help("Last publish date to list, e.g. 2024-12-31")
+            )
+            .arg(clap::Arg::new("country")
+                .long("country")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(String))
+                .help("Only list incidents of this country code, e.g. DE")
+            )
+            .arg(clap::Arg::new("tag")
+                .long("tag")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(String))
+                .help("Only list incidents with this tag, case insensitive")
+            )
+            .arg(clap::Arg::new("limit")
+                .long("limit")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(i64))
+                .help("Maximum number of incidents")
+            )
+            .arg(clap::Arg::new("json")
+                .long("json")
+                .action(clap::ArgAction::SetTrue)
+                .help("Print one JSON object per incident instead of a table")
+            )
+        )
+        .subcommand(clap::builder::Command::new("list-countries")
+            .about("List the countries of the stored incidents with their number of incidents, most frequent first")
+            .arg(clap::Arg::new("json")
+                .long("json")
+                .action(clap::ArgAction::SetTrue)
+                .help("Print one JSON object per country instead of a table")
+            )
+        )
+        .subcommand(clap::builder::Command::new("flatten-history")
+            .about("Rebuild incident_history_latest with the newest state of every incident across all raw snapshots")
+        )
+        .subcommand(clap::builder::Command::new("compact-history")
+            .about("Prune old raw snapshots from incident_history according to a retention policy")
+            .arg(clap::Arg::new("keep-last")
+                .long("keep-last")
+                .default_value("10")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(u64).range(1..))
+                .help("Always keep this many of the latest snapshots")
+            )
+            .arg(clap::Arg::new("keep-all-within")
+                .long("keep-all-within")
+                .default_value("7d")
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_duration)
+                .help("Keep every snapshot younger than this, older ones are thinned out to one per day")
+            )
+            .arg(clap::Arg::new("keep-first")
+                .long("keep-first")
+                .action(clap::ArgAction::SetTrue)
+                .help("Keep the very first snapshot")
+            )
+            .arg(clap::Arg::new("keep-changes")
+                .long("keep-changes")
+                .action(clap::ArgAction::SetTrue)
+                .help("Keep every snapshot whose content differs from the previous one")
+                .long_help("Keep every snapshot whose content differs from the previous one, so no snapshot that captured a unique state is deleted")
+            )
+            .arg(clap::Arg::new("dry-run")
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue)
+                .help("Only log which snapshots would be pruned")
+            )
+            .arg(clap::Arg::new("yes")
+                .short('y')
+                .long("yes")
+                .action(clap::ArgAction::SetTrue)
+                .help("Prune without asking for confirmation")
+                .long_help("Prune without asking for confirmation. Required when stdin isn't a terminal, e.g. in cron jobs, otherwise the pruning is refused")
+            )
+        )
+        .subcommand(clap::builder::Command::new("audit")
+            .about("Compare the stored incidents against the current details on the portal without modifying anything")
+            .arg(clap::Arg::new("sample")
+                .long("sample")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(usize))
+                .help("Only audit this many randomly selected incidents, reproducible with --seed")
+            )
+        )
+        .subcommand(clap::builder::Command::new("refetch")
+            .about("Fetch and upsert the incidents whose ids are read from stdin, one per line")
+            .long_about("Fetch the details of the incidents whose ids are read from stdin, one per line, and upsert them until EOF, e.g. to refresh incidents found by another tool. The list fields are taken from the current incident list. Malformed lines and ids that aren't listed are skipped with a warning, failed incidents are recorded for retry-failed")
+        )
+        .subcommand(clap::builder::Command::new("self-test")
+            .about("Store a synthetic incident, check that every field round-trips and remove it again")
+            .long_about("Store a synthetic incident with the reserved id -1 through the regular store path, read it back and print PASS or FAIL for every field of incidents and incident_details, including the references and dates. Runs in a transaction that is rolled back, so nothing is left behind. Checks the schema, the serialization and the database connection without the portal, e.g. after a migration or a deployment. Fails if any field doesn't round-trip")
+        )
+        .subcommand(clap::builder::Command::new("verify-hash")
+            .about("Check the row HMACs of the stored incidents to detect rows altered out of band")
+            .long_about("Recompute the HMACs of the stored incidents with --row-hmac-key and print the ones that don't match as `MISMATCH <id>` and the ones stored without HMAC as `UNSIGNED <id>`, followed by a summary. Fails if any row doesn't match")
+        )
+        .subcommand(clap::builder::Command::new("retry-failed")
+            .about("Re-attempt incidents that failed in a previous run")
+            .arg(clap::Arg::new("max-attempts")
+                .long("max-attempts")
+                .default_value("5")
+                .action(clap::ArgAction::Set)
+                .value_parser(value_parser!(i32))
+                .help("Maximum attempts per incident")
+                .long_help("Maximum attempts per incident, after which it is marked as permanently failed and no longer retried")
+            )
+        );
+    #[cfg(feature = "otlp")]
+    let command = command
+        .arg(clap::Arg::new("otlp-endpoint")
+            .long("otlp-endpoint")
+            .action(clap::ArgAction::Set)
+            .value_parser(value_parser!(String))
+            .help("OTLP/HTTP endpoint to export traces to")
+            .long_help("OTLP/HTTP endpoint to export traces to, e.g. `http://localhost:4318`. Tracing is disabled if not given")
+        );
+    #[cfg(feature = "simulate-errors")]
+    let command = command
+        .arg(clap::Arg::new("simulate-errors")
+            .long("simulate-errors")
+            .hide(true)
+            .action(clap::ArgAction::Set)
+            .value_parser(simulate::parse_rate)
+            .help("Fail this share of detail requests, e.g. 0.2, for resilience testing")
+            .long_help("Fail this share of detail requests with a synthetic error, e.g. 0.2 for 20%, to exercise retries and failed_incidents without a flaky portal. Reproducible with --seed. Only available with the simulate-errors feature")
+        )
+        .arg(clap::Arg::new("simulate-db-errors")
+            .long("simulate-db-errors")
+            .hide(true)
+            .action(clap::ArgAction::Set)
+            .value_parser(simulate::parse_rate)
+            .help("Fail this share of incident stores with a serialization failure, e.g. 0.2, for resilience testing")
+            .long_help("Fail this share of incident stores with a serialization failure (SQLSTATE 40001) raised by Postgres, e.g. 0.2 for 20%, to exercise --db-retries without concurrent load. Reproducible with --seed. Only available with the simulate-errors feature")
+        );
+    let matches = command.get_matches();
+
+    // Initialize logging
+    let log_format = match matches.get_one::<String>("log-format").map(String::as_str) {
+        Some("json") => logging::LogFormat::Json,
+        _ => logging::LogFormat::Text,
+    };
+    logging::setup_logger(log_format, logging::use_color(matches.get_flag("no-color")));
+    let production = matches.get_one::<String>("environment").is_some_and(|environment| environment == "production");
+    logging::set_redact(matches.get_flag("redact-logs") || (production && !matches.get_flag("no-redact-logs")));
+    let run_id = match matches.get_one::<String>("run-id") {
+        Some(run_id) => run_id.clone(),
+        None => format!("{}-{:08x}", chrono::Utc::now().format("%Y%m%dT%H%M%SZ"), rand::random::<u32>()),
+    };
+    // Every following event is logged within this span and thereby with the run id. The guard can be held
+    // across awaits as main's future is only ever polled on this thread by block_on and nothing is spawned
+    let _run_span = tracing::info_span!("run", run_id = %run_id).entered();
+
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = matches.get_one::<String>("otlp-endpoint") {
+        telemetry::init(endpoint);
+    }
+
+    let delay: u64 = *matches.get_one("delay").context("missing required argument delay")?;
+    if delay < MIN_DELAY {
+        if !matches.get_flag("allow-low-delay") {
+            // Exits with the same code as other invalid arguments
+            clap::Error::raw(
+                clap::error::ErrorKind::ValueValidation,
+                format!("--delay has a minimum of {}ms, pass --allow-low-delay to use {}ms anyway\n", MIN_DELAY, delay),
+            ).exit();
+        }
+        warn!("Using a delay of {}ms below the minimum of {}ms", delay, MIN_DELAY);
+    }
+
+    let database_url: &str = matches.get_one("database-url").context("missing required argument database-url").map(String::as_str)?;
+    let password_file = matches.get_one::<std::path::PathBuf>("database-password-file").map(std::path::PathBuf::as_path);
+    let sample: Option<usize> = matches.get_one("sample").copied();
+    let seed: Option<u64> = matches.get_one("seed").copied();
+
+    let profile_name: &str = matches.get_one("portal-profile").context("missing required argument portal-profile").map(String::as_str)?;
+    let mut profile = profile::resolve(profile_name, matches.get_one::<std::path::PathBuf>("portal-profiles").map(std::path::PathBuf::as_path))?;
+    if let Some(template) = matches.get_one::<String>("detail-url-template") {
+        debug!("Fetching details from {} instead of {}", template, profile.incident_detail_path);
+        profile.incident_detail_path = template.clone();
+    }
+    let profile_field_mapping = profile.field_mapping.clone();
+    let endpoints = Endpoints::new(profile, matches.get_one::<String>("base-url").map(String::as_str));
+
+    if matches.subcommand_matches("print-urls").is_some() {
+        println!("getIncidents: {}", endpoints.incidents());
+        println!("getIncidents referer: {}", endpoints.incidents_referer());
+        println!("incidentDetails: {}", endpoints.incident_detail(1));
+        println!("incidentDetails referer: {}", endpoints.incident_detail_referer());
+        return Ok(());
+    }
+    if matches.subcommand_matches("print-schema").is_some() {
+        print!("{}", SCHEMA);
+        return Ok(());
+    }
+
+    trace!("Setting up database pool and verifying tables");
+    let pool_settings = PoolSettings {
+        idle_timeout: *matches.get_one("db-idle-timeout").context("missing required argument db-idle-timeout")?,
+        max_lifetime: *matches.get_one("db-max-lifetime").context("missing required argument db-max-lifetime")?,
+        test_before_acquire: *matches.get_one("db-test-before-acquire").context("missing required argument db-test-before-acquire")?,
+        statement_timeout: matches.get_one("db-statement-timeout").copied(),
+        schema: matches.get_one("db-schema").cloned(),
+    };
+    let validate_only = matches.get_flag("validate-only");
+    let pool = report_step(validate_only, "setup database", setup_database(database_url, password_file, &pool_settings).await)?;
+
+    if let Some(migrate_matches) = matches.subcommand_matches("migrate") {
+        run_migrations(&pool).await?;
+        if migrate_matches.get_flag("partition-by-year") {
+            partitioning::partition_by_year(&pool).await?;
+        }
+        return Ok(());
+    }
+    if matches.get_flag("auto-migrate") {
+        run_migrations(&pool).await?;
+    }
+    let raw_store = !matches.get_flag("no-raw-store");
+    let require_raw_store = matches.get_flag("require-raw-store");
+    report_step(validate_only, "verify tables", verify_tables(&pool, raw_store, require_raw_store).await)?;
+
+    if matches.subcommand_matches("self-test").is_some() {
+        return self_test::self_test(&pool).await;
+    }
+
+    // The mapping file takes precedence over the quirks of the profile
+    let mut field_mapping = match matches.get_one::<std::path::PathBuf>("field-mapping") {
+        Some(path) => field_mapping::FieldMapping::load(path)?,
+        None => field_mapping::FieldMapping::default(),
+    }
+        .with_defaults(&profile_field_mapping);
+    let language: String = matches.get_one::<String>("language").cloned().context("missing required argument language")?;
+    if language != DEFAULT_DETAIL_LANGUAGE {
+        // Falls back to description_de if the portal ignores Accept-Language
+        field_mapping.map_detail_default("details_text", format!("description_{}", language));
+    }
+    if matches.subcommand_matches("flatten-history").is_some() {
+        return history::flatten_history(&pool, &field_mapping).await;
+    }
+
+    if let Some(compact_matches) = matches.subcommand_matches("compact-history") {
+        let policy = history::RetentionPolicy {
+            keep_last: *compact_matches.get_one("keep-last").context("missing required argument keep-last")?,
+            keep_all_within: *compact_matches.get_one("keep-all-within").context("missing required argument keep-all-within")?,
+            keep_first: compact_matches.get_flag("keep-first"),
+            keep_changes: compact_matches.get_flag("keep-changes"),
+            dry_run: compact_matches.get_flag("dry-run"),
+            yes: compact_matches.get_flag("yes"),
+        };
+        return history::compact_history(&pool, &policy).await;
+    }
+
+    if let Some(search_matches) = matches.subcommand_matches("search") {
+        let query: &str = search_matches.get_one("query").context("missing required argument query").map(String::as_str)?;
+        let language: &str = search_matches.get_one("language").context("missing required argument language").map(String::as_str)?;
+        let limit: i64 = *search_matches.get_one("limit").context("missing required argument limit")?;
+        return search::search_incidents(&pool, query, language, limit).await;
+    }
+
+    if let Some(countries_matches) = matches.subcommand_matches("list-countries") {
+        return search::list_countries(&pool, countries_matches.get_flag("json")).await;
+    }
+
+    if let Some(list_matches) = matches.subcommand_matches("list") {
+        let filter = search::ListFilter {
+            from: list_matches.get_one("from").copied(),
+            to: list_matches.get_one("to").copied(),
+            country: list_matches.get_one("country").cloned(),
+            tag: list_matches.get_one("tag").cloned(),
+            limit: list_matches.get_one("limit").copied(),
+        };
+        return search::list_incidents(&pool, &filter, list_matches.get_flag("json")).await;
+    }
+
+    if let Some(export_matches) = matches.subcommand_matches("export") {
+        let redaction = if export_matches.get_flag("redact") {
+            Some(export::Redaction {
+                fields: export_matches.get_many::<String>("redact-fields").unwrap_or_default().cloned().collect(),
+                salt: export_matches.get_one::<String>("redact-salt").cloned().context("missing required argument redact-salt")?,
+            })
+        } else {
+            None
+        };
+        let order_by = match export_matches.get_one::<String>("order-by").map(String::as_str) {
+            Some("publish_date") => export::ExportOrder::PublishDate,
+            Some("country") => export::ExportOrder::Country,
+            _ => export::ExportOrder::Id,
+        };
+        let format = match export_matches.get_one::<String>("format").map(String::as_str) {
+            Some("parquet") => export::ExportFormat::Parquet,
+            _ => export::ExportFormat::JsonLines,
+        };
+        let options = export::ExportOptions {
+            output: export_matches.get_one("output").cloned(),
+            redaction,
+            format,
+            order_by,
+            descending: export_matches.get_flag("descending"),
+            limit: export_matches.get_one("limit").copied(),
+            offset: export_matches.get_one("offset").copied(),
+            since: export_matches.get_one("since").copied(),
+            watermark: export_matches.get_one("incremental").cloned(),
+        };
+        return export::export_incidents(&pool, &options).await;
+    }
+
+    let empty_result = if matches.get_flag("strict-empty-result") {
+        CheckMode::Strict
+    } else if matches.get_flag("warn-on-empty-result") {
+        CheckMode::Warn
+    } else {
+        CheckMode::Off
+    };
+    let consistency = if matches.get_flag("strict-consistency") {
+        CheckMode::Strict
+    } else if matches.get_flag("check-consistency") {
+        CheckMode::Warn
+    } else {
+        CheckMode::Off
+    };
+    let http_version = match matches.get_one::<String>("http-version").map(String::as_str) {
+        Some("1") => HttpVersion::Http1,
+        Some("2") => HttpVersion::Http2,
+        _ => HttpVersion::Auto,
+    };
+    let bind_address: Option<std::net::IpAddr> = matches.get_one("bind-address").copied();
+    let ip_family = match matches.get_one::<String>("ip-family").map(String::as_str) {
+        Some("4") => Some(http::IpFamily::Ipv4),
+        Some("6") => Some(http::IpFamily::Ipv6),
+        Some(_) => Some(http::IpFamily::Any),
+        None => None,
+    };
+    let ip_family = resolve_ip_family(bind_address, ip_family)?;
+    let tor = if matches.get_flag("tor") {
+        let tor = http::TorSettings {
+            proxy: *matches.get_one("tor-proxy").context("missing required argument tor-proxy")?,
+            isolate: matches.get_flag("tor-isolate"),
+        };
+        tor.check_reachable()?;
+        Some(tor)
+    } else {
+        None
+    };
+    let pinned_cert = match matches.get_one::<std::path::PathBuf>("pinned-cert") {
+        Some(path) => {
+            if !endpoints.base_url.starts_with("https://") {
+                anyhow::bail!("--pinned-cert requires an https base URL, got {}", endpoints.base_url);
+            }
+            let pem = std::fs::read(path).with_context(|| format!("Failed to read pinned certificate {}", path.display()))?;
+            Some(reqwest::Certificate::from_pem(&pem).with_context(|| format!("Invalid PEM certificate in {}", path.display()))?)
+        }
+        None => None,
+    };
+    let mut auth_headers = matches
+        .get_many::<String>("auth-header")
+        .unwrap_or_default()
+        .map(|header| http::parse_auth_header(header).map_err(|err| anyhow::anyhow!("Invalid --auth-header: {}", err)))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(path) = matches.get_one::<std::path::PathBuf>("auth-header-file") {
+        auth_headers.extend(http::read_auth_headers(path)?);
+    }
+    let bearer_token = match matches.get_one::<std::path::PathBuf>("bearer-token-file") {
+        Some(path) => Some(std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read bearer token file {}", path.display()))?
+            .trim_end_matches(['\r', '\n'])
+            .to_owned()),
+        None => matches.get_one::<String>("bearer-token").cloned(),
+    };
+    if let Some(token) = bearer_token {
+        auth_headers.push(http::parse_auth_header(&format!("Authorization: Bearer {}", token)).map_err(|err| anyhow::anyhow!("Invalid bearer token: {}", err))?);
+    }
+    if !auth_headers.is_empty() {
+        let names: Vec<&str> = auth_headers.iter().map(|(name, _)| name.as_str()).collect();
+        debug!("Authenticating requests to the portal with headers {:?}", names);
+        if !endpoints.base_url.starts_with("https://") {
+            warn!("Sending auth headers over plain http to {}, they can be read by anyone on the way", endpoints.base_url);
+        }
+    }
+    let order = match matches.get_one::<String>("order").map(String::as_str) {
+        Some("id-asc") => IncidentOrder::IdAsc,
+        Some("id-desc") => IncidentOrder::IdDesc,
+        Some("date") => IncidentOrder::Date,
+        _ => IncidentOrder::AsIs,
+    };
+    let schema_validation = if matches.get_flag("strict-schema") {
+        CheckMode::Strict
+    } else if matches.get_flag("validate-schema") {
+        CheckMode::Warn
+    } else {
+        CheckMode::Off
+    };
+    let detail_cache = match matches.get_one::<std::path::PathBuf>("detail-cache") {
+        Some(path) => {
+            let ttl: Duration = *matches.get_one("detail-cache-ttl").context("missing required argument detail-cache-ttl")?;
+            Some(detail_cache::DetailCache::open(path, ttl).await?)
+        }
+        None => None,
+    };
+    let attachments = if matches.get_flag("fetch-attachments") {
+        let dir = matches.get_one::<std::path::PathBuf>("attachment-dir").cloned();
+        if let Some(dir) = &dir {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create attachment directory {}", dir.display()))?;
+        }
+        let max_bytes: u64 = *matches.get_one("attachment-max-bytes").context("missing required argument attachment-max-bytes")?;
+        Some(attachments::AttachmentOptions::new(max_bytes, dir))
+    } else {
+        None
+    };
+    let row_hmac_key = match matches.get_one::<std::path::PathBuf>("row-hmac-key-file") {
+        Some(path) => Some(std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read row HMAC key file {}", path.display()))?
+            .trim_end_matches(['\r', '\n'])
+            .to_owned()),
+        None => matches.get_one::<String>("row-hmac-key").cloned(),
+    };
+    let row_hmac = row_hmac_key.map(integrity::RowHmac::new).transpose()?;
+    if matches.subcommand_matches("verify-hash").is_some() {
+        let row_hmac = row_hmac.as_ref().context("verify-hash requires --row-hmac-key or --row-hmac-key-file")?;
+        return integrity::verify_hashes(&pool, row_hmac).await;
+    }
+    let statsd = match matches.get_one::<String>("statsd-addr") {
+        Some(addr) => statsd::Statsd::connect(addr, matches.get_one::<String>("statsd-prefix").context("missing required argument statsd-prefix")?)?,
+        None => statsd::Statsd::disabled(),
+    };
+    let date_check = match matches.get_one::<String>("strict-dates").map(String::as_str) {
+        Some(action) => Some(DateCheck {
+            action: if action == "fail" { DateCheckAction::Fail } else { DateCheckAction::Skip },
+            min: *matches.get_one("min-date").context("missing required argument min-date")?,
+            max_ahead: *matches.get_one("max-date-ahead").context("missing required argument max-date-ahead")?,
+        }),
+        None => None,
+    };
+    let retry = retry::RetryPolicy {
+        retries: *matches.get_one("retries").context("missing required argument retries")?,
+        backoff: *matches.get_one("retry-backoff").context("missing required argument retry-backoff")?,
+        db_retries: *matches.get_one("db-retries").context("missing required argument db-retries")?,
+        db_codes: matches.get_many::<String>("db-retry-codes").unwrap_or_default().cloned().collect(),
+    };
+    let commit_every: u64 = *matches.get_one("commit-every").context("missing required argument commit-every")?;
+    let mut sinks = sink::Sinks::default();
+    let database_sink = sink::DatabaseSink::new(
+        pool.clone(),
+        retry.clone(),
+        commit_every > 1,
+        sink::StoreOptions {
+            strip_html: matches.get_flag("strip-html"),
+            max_text_bytes: matches.get_one("max-incident-text-bytes").copied(),
+            row_hmac,
+            mark_unpublished: matches.get_flag("mark-unpublished"),
+        },
+        matches.get_flag("compress-history"),
+    );
+    #[cfg(feature = "simulate-errors")]
+    let database_sink = database_sink.simulate_errors(matches.get_one::<f64>("simulate-db-errors").map(|rate| simulate::ErrorSimulation::new(*rate, seed, "incident stores")));
+    sinks.add(Box::new(database_sink));
+    for name in matches.get_many::<String>("sink").unwrap_or_default() {
+        match name.as_str() {
+            "jsonl" => sinks.add(Box::new(sink::JsonLinesSink)),
+            _ => anyhow::bail!("Unknown sink '{}'", name),
+        }
+    }
+    if let Some(brokers) = matches.get_many::<String>("kafka-brokers") {
+        #[cfg(feature = "kafka")]
+        {
+            let topic: String = matches.get_one::<String>("kafka-topic").cloned().context("missing required argument kafka-topic")?;
+            sinks.add(Box::new(kafka::KafkaSink::new(brokers.cloned().collect(), topic)));
+        }
+        #[cfg(not(feature = "kafka"))]
+        {
+            let _ = brokers;
+            anyhow::bail!("--kafka-brokers requires a build with the `kafka` feature");
+        }
+    }
+    let options = RunOptions {
+        delay,
+        delay_per_host: matches.get_flag("delay-per-host"),
+        commit_every,
+        #[cfg(feature = "simulate-errors")]
+        simulate_errors: matches.get_one::<f64>("simulate-errors").map(|rate| simulate::ErrorSimulation::new(*rate, seed, "detail requests")),
+        retry,
+        sample,
+        max_new_incidents: matches.get_one("max-new-incidents").copied(),
+        force_new_incidents: matches.get_flag("force"),
+        shuffle: matches.get_flag("shuffle"),
+        seed,
+        order,
+        published_only: matches.get_flag("published-only"),
+        id_filter: IdFilter {
+            include: collect_ids(&matches, "include-id", "include-ids-file")?,
+            exclude: collect_ids(&matches, "exclude-id", "exclude-ids-file")?.unwrap_or_default(),
+        },
+        consistency,
+        endpoints,
+        http_version,
+        http_pool: HttpPoolSettings {
+            idle_timeout: *matches.get_one("http-pool-idle-timeout").context("missing required argument http-pool-idle-timeout")?,
+            max_idle_per_host: *matches.get_one("http-pool-max-idle").context("missing required argument http-pool-max-idle")?,
+        },
+        bind_address,
+        ip_family,
+        tor,
+        pinned_cert,
+        auth_headers,
+        raw_store,
+        require_raw_store,
+        store_raw_details: matches.get_flag("store-raw-details"),
+        revalidate_details: matches.get_flag("revalidate-details"),
+        run_id,
+        fetch_sequence: Default::default(),
+        disable_detail_fetch: matches.get_flag("disable-detail-fetch"),
+        schema_validation,
+        empty_result,
+        min_incidents: *matches.get_one("min-incidents").context("missing required argument min-incidents")?,
+        incidents_file: matches.get_one("incidents-file").cloned(),
+        field_mapping,
+        language,
+        detail_cache,
+        attachments,
+        diff_manifest_dir: matches.get_one("diff-manifest-dir").cloned(),
+        report_file: matches.get_one("report-file").cloned(),
+        statsd,
+        timeout_budget: matches.get_one("timeout-budget").copied(),
+        max_age: matches.get_one("max-age").copied(),
+        resume_incomplete: matches.get_flag("resume-incomplete"),
+        request_rate_interval: matches.get_one("log-request-rate").copied(),
+        throttle: pacing::ThrottleThresholds {
+            responses: *matches.get_one("throttle-warn-responses").context("missing required argument throttle-warn-responses")?,
+            latency_factor: *matches.get_one("throttle-warn-latency-factor").context("missing required argument throttle-warn-latency-factor")?,
+        },
+        date_check,
+        sinks,
+        hook: matches
+            .get_one::<String>("on-stored")
+            .map(|command| Box::new(hooks::CommandHook { command: command.clone() }) as Box<dyn hooks::IncidentHook>),
+        strict_hook: matches.get_flag("strict-hook"),
+    };
+
+    if validate_only {
+        // Without the raw store nothing is written and conditional requests are skipped, so the full list is parsed
+        let options = RunOptions { raw_store: false, ..options };
+        return validate(&pool, &options).await;
+    }
+
+    if matches.get_flag("count-only") {
+        return count_only(&pool, &options).await;
+    }
+
+    if matches.get_flag("record-config") {
+        run_config::record(&pool, &options.run_id, &run_config::snapshot(&matches)).await?;
+    }
+
+    if let Some(audit_matches) = matches.subcommand_matches("audit") {
+        // Read-only, so nothing is stored and cached details can't hide drift
+        let options = RunOptions { store_raw_details: false, revalidate_details: false, detail_cache: None, ..options };
+        let client = PortalClient::new(&options)?;
+        return audit::audit_incidents(&client, &pool, &options, audit_matches.get_one("sample").copied()).await;
+    }
+
+    if matches.subcommand_matches("refetch").is_some() {
+        let client = PortalClient::new(&options)?;
+        let result = refetch::refetch_from_stdin(&client, &pool, &options).await;
+        return close_sinks(&options, result).await;
+    }
+
+    if let Some(retry_matches) = matches.subcommand_matches("retry-failed") {
+        let max_attempts: i32 = *retry_matches.get_one("max-attempts").context("missing required argument max-attempts")?;
+        let result = retry_failed_incidents(&pool, &options, max_attempts).await;
+        return close_sinks(&options, result).await;
+    }
+
+    if matches.get_flag("watch") {
+        let interval: Duration = *matches.get_one("interval").context("missing required argument interval")?;
+        let interval_jitter: u8 = *matches.get_one("interval-jitter").context("missing required argument interval-jitter")?;
+        let mut shutdown = std::pin::pin!(shutdown_signal());
+        loop {
+            let mut cycle = std::pin::pin!(telemetry::in_span("run", vec![], run(&pool, &options)));
+            let (result, stopping) = tokio::select! {
+                result = &mut cycle => (result, false),
+                () = &mut shutdown => {
+                    info!("Received shutdown signal, finishing the current cycle");
+                    (cycle.await, true)
+                }
+            };
+            telemetry::flush().await;
+            if let Err(err) = result {
+                error!("Cycle failed: {:#}", err);
+            }
+            if stopping {
+                break;
+            }
+            let sleep = jitter(interval, interval_jitter);
+            info!("Sleeping for {:?} until next cycle", sleep);
+            tokio::select! {
+                () = tokio::time::sleep(sleep) => {}
+                () = &mut shutdown => {
+                    info!("Received shutdown signal");
+                    break;
+                }
+            }
+        }
+        // Failed cycles were logged already, only a failure to close fails the process
+        let result = close_sinks(&options, Ok(())).await;
+        telemetry::flush().await;
+        return result;
+    }
+
+    let result = telemetry::in_span("run", vec![], run(&pool, &options)).await;
+    let result = close_sinks(&options, result).await;
+    telemetry::flush().await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::Database;
+
+    /// Pool on the database of `TEST_DATABASE_URL`, migrated with `migrate`. The tests using it are
+    /// ignored by default and fail without the variable, run them with `cargo test -- --include-ignored`
+    async fn test_pool() -> sqlx::PgPool {
+        let database_url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set for database tests");
+        sqlx::PgPool::connect(&database_url).await.expect("Failed to connect to TEST_DATABASE_URL")
+    }
+
+    /// Transaction on [`test_pool`] that is rolled back when dropped, tests that need to commit use the
+    /// pool and clean up after themselves
+    async fn test_transaction() -> sqlx::Transaction<'static, sqlx::Postgres> {
+        test_pool().await.begin().await.expect("Failed to start transaction")
+    }
+
+    async fn delete_incidents(pool: &sqlx::PgPool, incident_ids: &[i32]) {
+        for table in ["incident_details", "incident_revisions", "failed_incidents", "incidents"] {
+            sqlx::query(&format!("DELETE FROM {} WHERE incident_id = ANY($1)", table)).bind(incident_ids).execute(pool).await.unwrap();
+        }
+    }
+
+    /// Incident with one detail, negative ids don't collide with the portal's
+    fn incident(incident_id: i32, text: &str) -> (Incident, Vec<IncidentDetail>) {
+        let incident = serde_json::from_value(serde_json::json!({
+            "incidentID": incident_id,
+            "orgPublishDate": "2024-02-29",
+            "modifiedDate": "2024-03-01 12:00:00",
+            "published": 1,
+            "country": "DE",
+            "incidentText": text,
+        }))
+            .unwrap();
+        let detail = serde_json::from_value(serde_json::json!({
+            "publishDate": "2024-03-01",
+            "affectedObj": "Kunden",
+            "affectedType": "Unternehmen",
+            "description_de": format!("<p>{}</p>", text),
+            "tags": "Test",
+            "href": "https://example.org",
+            "reference": "[]",
+        }))
+            .unwrap();
+        (incident, vec![detail])
+    }
+
+    async fn stored_row(tx: &mut sqlx::PgConnection, incident_id: i32) -> (Option<String>, serde_json::Value) {
+        sqlx::query_as("SELECT row_hmac, to_jsonb(incidents) FROM incidents WHERE incident_id = $1")
+            .bind(incident_id)
+            .fetch_one(tx)
+            .await
+            .unwrap()
+    }
+
+    const PORTAL: &str = "https://portal.test";
+
+    /// Sink keeping the ids and number of details of the stored incidents in memory
+    #[derive(Clone, Default)]
+    struct RecordingSink(std::sync::Arc<std::sync::Mutex<Vec<(i32, usize)>>>);
+
+    impl sink::Sink for RecordingSink {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        fn existing_ids(&self) -> futures_util::future::BoxFuture<'_, Result<Option<HashSet<i32>>>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn store_raw_response<'a>(&'a self, _response: &'a sink::RawResponse<'a>) -> futures_util::future::BoxFuture<'a, Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn store_incident<'a>(&'a self, incident: &'a Incident, details: &'a [IncidentDetail]) -> futures_util::future::BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                self.0.lock().unwrap().push((incident.incident_id, details.len()));
+                Ok(())
+            })
+        }
+    }
+
+    /// Options of a run against [`PORTAL`] without retries, delays or optional steps, storing into `sink`
+    fn test_options(sink: RecordingSink) -> RunOptions {
+        let mut sinks = sink::Sinks::default();
+        sinks.add(Box::new(sink));
+        RunOptions {
+            delay: 0,
+            delay_per_host: false,
+            retry: retry::RetryPolicy { retries: 0, backoff: Duration::ZERO, db_retries: 0, db_codes: std::sync::Arc::new([]) },
+            commit_every: 1,
+            #[cfg(feature = "simulate-errors")]
+            simulate_errors: None,
+            sample: None,
+            max_new_incidents: None,
+            force_new_incidents: false,
+            shuffle: false,
+            seed: None,
+            order: IncidentOrder::AsIs,
+            consistency: CheckMode::Strict,
+            endpoints: Endpoints::new(profile::PortalProfile::default(), Some(PORTAL)),
+            http_version: HttpVersion::Auto,
+            http_pool: HttpPoolSettings { idle_timeout: Duration::from_secs(60), max_idle_per_host: 1 },
+            bind_address: None,
+            ip_family: http::IpFamily::Any,
+            tor: None,
+            pinned_cert: None,
+            auth_headers: Vec::new(),
+            raw_store: false,
+            require_raw_store: false,
+            disable_detail_fetch: false,
+            run_id: "test".to_owned(),
+            fetch_sequence: Default::default(),
+            revalidate_details: false,
+            store_raw_details: false,
+            schema_validation: CheckMode::Strict,
+            empty_result: CheckMode::Strict,
+            min_incidents: 1,
+            incidents_file: None,
+            field_mapping: field_mapping::FieldMapping::default(),
+            published_only: false,
+            id_filter: IdFilter::default(),
+            language: DEFAULT_DETAIL_LANGUAGE.to_owned(),
+            detail_cache: None,
+            attachments: None,
+            sinks,
+            hook: None,
+            strict_hook: false,
+            date_check: None,
+            request_rate_interval: None,
+            throttle: pacing::ThrottleThresholds { responses: 0, latency_factor: 3.0 },
+            timeout_budget: None,
+            max_age: None,
+            resume_incomplete: false,
+            diff_manifest_dir: None,
+            report_file: None,
+            statsd: statsd::Statsd::disabled(),
+        }
+    }
+
+    /// Options of [`test_options`] storing into `db` instead, in a batch committed by flushes with `batch`
+    fn database_options(db: &db::fake::FakeDatabase, batch: bool) -> RunOptions {
+        let mut options = test_options(RecordingSink::default());
+        let mut sinks = sink::Sinks::default();
+        sinks.add(Box::new(sink::DatabaseSink::new(db.clone(), options.retry.clone(), batch, sink::StoreOptions::default(), false)));
+        options.sinks = sinks;
+        options
+    }
+
+    #[tokio::test]
+    async fn fetch_incidents_parses_the_list_of_the_portal() {
+        let mut options = test_options(RecordingSink::default());
+        options.auth_headers = vec![http::parse_auth_header("X-Api-Key: secret").unwrap()];
+        let portal = http::fake::FakePortal::default().respond(options.endpoints.incidents(), reqwest::StatusCode::OK, include_str!("../fixtures/portal/incidents.json"));
+        let client = PortalClient::with_http(portal, &options);
+
+        let incidents = fetch_incidents(&client, &db::fake::FakeDatabase::default(), &options).await.unwrap().unwrap();
+        let ids: Vec<i32> = incidents.iter().map(|incident| incident.incident_id).collect();
+        assert_eq!(ids, [101, 102, 103, 104, 105]);
+        let api_key = reqwest::header::HeaderName::from_static("x-api-key");
+        assert_eq!(client.http.request_header(&options.endpoints.incidents(), &api_key).as_deref(), Some("secret"));
+    }
+
+    #[tokio::test]
+    async fn fetch_incidents_returns_none_if_not_modified() {
+        let options = test_options(RecordingSink::default());
+        let portal = http::fake::FakePortal::default().respond(options.endpoints.incidents(), reqwest::StatusCode::NOT_MODIFIED, "");
+        let client = PortalClient::with_http(portal, &options);
+
+        assert!(fetch_incidents(&client, &db::fake::FakeDatabase::default(), &options).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn process_incident_stores_the_fetched_details() {
+        let sink = RecordingSink::default();
+        let options = test_options(sink.clone());
+        let portal = http::fake::FakePortal::default().respond(options.endpoints.incident_detail(101), reqwest::StatusCode::OK, include_str!("../fixtures/portal/details/101.json"));
+        let client = PortalClient::with_http(portal, &options);
+        let incidents: Vec<Incident> = serde_json::from_str(include_str!("../fixtures/portal/incidents.json")).unwrap();
+
+        let processed = process_incident(&client, &db::fake::FakeDatabase::default(), &incidents[0], &options).await.unwrap();
+        assert_eq!(processed, Processed::Stored);
+        assert_eq!(*sink.0.lock().unwrap(), [(101, 1)]);
+    }
+
+    #[tokio::test]
+    async fn process_incident_fails_without_storing_on_server_errors() {
+        let sink = RecordingSink::default();
+        let options = test_options(sink.clone());
+        let portal = http::fake::FakePortal::default().respond(options.endpoints.incident_detail(101), reqwest::StatusCode::INTERNAL_SERVER_ERROR, "");
+        let client = PortalClient::with_http(portal, &options);
+        let incidents: Vec<Incident> = serde_json::from_str(include_str!("../fixtures/portal/incidents.json")).unwrap();
+
+        assert!(process_incident(&client, &db::fake::FakeDatabase::default(), &incidents[0], &options).await.is_err());
+        assert!(sink.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fetch_incidents_stores_the_raw_response() {
+        let db = db::fake::FakeDatabase::default();
+        let mut options = database_options(&db, false);
+        options.raw_store = true;
+        let portal = http::fake::FakePortal::default().respond(options.endpoints.incidents(), reqwest::StatusCode::OK, include_str!("../fixtures/portal/incidents.json"));
+        let client = PortalClient::with_http(portal, &options);
+
+        fetch_incidents(&client, &db, &options).await.unwrap().unwrap();
+        assert_eq!(db.raw_responses(), [include_str!("../fixtures/portal/incidents.json").trim()]);
+    }
+
+    #[tokio::test]
+    async fn process_incident_stores_in_the_database_and_clears_its_failure() {
+        let db = db::fake::FakeDatabase::default();
+        let options = database_options(&db, false);
+        let portal = http::fake::FakePortal::default().respond(options.endpoints.incident_detail(101), reqwest::StatusCode::OK, include_str!("../fixtures/portal/details/101.json"));
+        let client = PortalClient::with_http(portal, &options);
+        let incidents: Vec<Incident> = serde_json::from_str(include_str!("../fixtures/portal/incidents.json")).unwrap();
+        db.record_failed_incident(&incidents[0], &anyhow::anyhow!("Earlier failure")).await.unwrap();
+
+        let processed = process_incident(&client, &db, &incidents[0], &options).await.unwrap();
+        assert_eq!(processed, Processed::Stored);
+        assert_eq!(db.stored_ids(), [101]);
+        assert_eq!(db.failed_attempts(101), None);
+    }
+
+    #[tokio::test]
+    async fn failed_incidents_are_recorded_with_their_attempts() {
+        let db = db::fake::FakeDatabase::default();
+        let options = database_options(&db, false);
+        let portal = http::fake::FakePortal::default().respond(options.endpoints.incident_detail(101), reqwest::StatusCode::INTERNAL_SERVER_ERROR, "");
+        let client = PortalClient::with_http(portal, &options);
+        let incidents: Vec<Incident> = serde_json::from_str(include_str!("../fixtures/portal/incidents.json")).unwrap();
+
+        for _ in 0..2 {
+            let report = process_new_incidents(&client, stream::iter([incidents[0].clone()]), &db, &options, None).await.unwrap();
+            assert_eq!(report.failed.len(), 1);
+        }
+        assert!(db.stored_ids().is_empty());
+        assert_eq!(db.failed_attempts(101), Some(2));
+    }
+
+    #[tokio::test]
+    async fn discarded_incident_is_not_committed_with_the_batch() {
+        let db = db::fake::FakeDatabase::default();
+        let options = database_options(&db, true);
+        let (kept, details) = incident(-1, "Behalten");
+        let (discarded, _) = incident(-2, "Verworfen");
+        for incident in [&kept, &discarded] {
+            db.record_failed_incident(incident, &anyhow::anyhow!("Earlier failure")).await.unwrap();
+            options.sinks.store_incident(incident, &details).await.unwrap();
+        }
+        assert!(db.stored_ids().is_empty(), "nothing is visible before the flush");
+
+        options.sinks.discard(&discarded).await.unwrap();
+        options.sinks.flush().await.unwrap();
+        assert_eq!(db.stored_ids(), [-1]);
+        assert_eq!(db.failed_attempts(-1), None, "failures are cleared once the batch is committed");
+        assert_eq!(db.failed_attempts(-2), Some(1));
+    }
+
+    #[tokio::test]
+    async fn revalidated_incident_is_not_stored_again_if_its_detail_didnt_change() {
+        let db = db::fake::FakeDatabase::default();
+        let mut options = database_options(&db, false);
+        options.revalidate_details = true;
+        options.store_raw_details = true;
+        let url = options.endpoints.incident_detail(101);
+        let incidents: Vec<Incident> = serde_json::from_str(include_str!("../fixtures/portal/incidents.json")).unwrap();
+
+        let portal = http::fake::FakePortal::default().respond(&url, reqwest::StatusCode::OK, include_str!("../fixtures/portal/details/101.json"));
+        let processed = process_incident(&PortalClient::with_http(portal, &options), &db, &incidents[0], &options).await.unwrap();
+        assert_eq!(processed, Processed::Stored);
+
+        let portal = http::fake::FakePortal::default().respond(&url, reqwest::StatusCode::NOT_MODIFIED, "");
+        let processed = process_incident(&PortalClient::with_http(portal, &options), &db, &incidents[0], &options).await.unwrap();
+        assert_eq!(processed, Processed::Unchanged);
+        assert_eq!(db.touched(), [101]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn storing_an_incident_twice_is_a_no_op() {
+        let mut tx = test_transaction().await;
+        let (incident, details) = incident(-130, "Doppelt");
+        store_incident(&mut *tx, &incident, &details, &sink::StoreOptions::default()).await.unwrap();
+        store_incident(&mut *tx, &incident, &details, &sink::StoreOptions::default()).await.unwrap();
+
+        let counts: (i64, i64, i64) = sqlx::query_as(
+            r#"SELECT
+                (SELECT count(*) FROM incidents WHERE incident_id = $1),
+                (SELECT count(*) FROM incident_details WHERE incident_id = $1),
+                (SELECT count(*) FROM incident_revisions WHERE incident_id = $1)"#,
+        )
+            .bind(incident.incident_id)
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(counts, (1, 1, 0), "one incident with one detail and no revision, as nothing changed");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn concurrently_inserted_incident_is_not_overwritten() {
+        let pool = test_pool().await;
+        let ids = [-1301];
+        delete_incidents(&pool, &ids).await;
+        let (first, details) = incident(ids[0], "Zuerst");
+        let (second, _) = incident(ids[0], "Danach");
+
+        // The first run inserted the incident but didn't commit yet, so the second one doesn't see it
+        let mut tx = pool.begin().await.unwrap();
+        store_incident(&mut *tx, &first, &details, &sink::StoreOptions::default()).await.unwrap();
+        let concurrent = tokio::spawn({
+            let pool = pool.clone();
+            let details = details.clone();
+            async move { store_incident(&pool, &second, &details, &sink::StoreOptions::default()).await }
+        });
+        // Until its insert waits for the uncommitted row
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM pg_stat_activity WHERE wait_event_type = 'Lock' AND query LIKE 'INSERT INTO incidents%')")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+            .await
+            .expect("Second insert didn't wait for the first one");
+        tx.commit().await.unwrap();
+        concurrent.await.unwrap().unwrap();
+
+        let stored: (String, i64) = sqlx::query_as("SELECT incident_text, (SELECT count(*) FROM incident_revisions WHERE incident_id = $1) FROM incidents WHERE incident_id = $1")
+            .bind(ids[0])
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        delete_incidents(&pool, &ids).await;
+        assert_eq!(stored, ("Zuerst".to_owned(), 0), "the second insert is skipped instead of updating the first one");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn row_hmac_detects_alterations_and_is_cleared_without_key() {
+        let mut tx = test_transaction().await;
+        let (incident, details) = incident(-181, "Signiert");
+        let signed = sink::StoreOptions { row_hmac: Some(integrity::RowHmac::new("key".to_owned()).unwrap()), ..Default::default() };
+        let row_hmac = signed.row_hmac.as_ref().unwrap();
+
+        store_incident(&mut *tx, &incident, &details, &signed).await.unwrap();
+        let (hmac, row) = stored_row(&mut tx, incident.incident_id).await;
+        assert!(row_hmac.verify(&row, &hmac.unwrap()).unwrap());
+
+        sqlx::query("UPDATE incidents SET tags = 'Geändert' WHERE incident_id = $1").bind(incident.incident_id).execute(&mut *tx).await.unwrap();
+        let (hmac, row) = stored_row(&mut tx, incident.incident_id).await;
+        assert!(!row_hmac.verify(&row, &hmac.unwrap()).unwrap());
+
+        let (changed, details) = self::incident(incident.incident_id, "Ohne Schlüssel geändert");
+        store_incident(&mut *tx, &changed, &details, &sink::StoreOptions::default()).await.unwrap();
+        let (hmac, _) = stored_row(&mut tx, incident.incident_id).await;
+        assert_eq!(hmac, None, "a row updated without the key must report as unsigned");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn update_without_strip_html_keeps_plain_texts() {
+        let mut tx = test_transaction().await;
+        let (incident, details) = incident(-158, "Klartext");
+        let strip_html = sink::StoreOptions { strip_html: true, ..Default::default() };
+        store_incident(&mut *tx, &incident, &details, &strip_html).await.unwrap();
+        store_incident(&mut *tx, &incident, &details, &sink::StoreOptions::default()).await.unwrap();
+
+        let plain: (Option<String>, Option<String>) = sqlx::query_as("SELECT incident_text_plain, details_text_plain FROM incidents WHERE incident_id = $1")
+            .bind(incident.incident_id)
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(plain, (Some("Klartext".to_owned()), Some("Klartext".to_owned())));
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration(" 2h "), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("").is_err());
+        assert_eq!(parse_duration(&format!("{}s", u64::MAX)), Ok(Duration::from_secs(u64::MAX)));
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX / 60)), Err(format!("duration '{}d' is too long", u64::MAX / 60)));
+    }
+
+    #[test]
+    fn parses_max_age_within_the_range_of_postgres() {
+        assert_eq!(parse_max_age("30d"), Ok(Duration::from_secs(30 * 24 * 60 * 60)));
+        assert_eq!(parse_max_age("36500d"), Ok(MAX_AGE_LIMIT));
+        assert_eq!(parse_max_age("36501d"), Err("max age '36501d' is longer than 36500d".to_owned()));
+        assert!(parse_max_age(&format!("{}s", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn jitter_saturates_huge_intervals() {
+        // Half of the draws stretch the interval, which panicked before
+        for _ in 0..100 {
+            jitter(Duration::MAX, 100);
+        }
+        let jittered = jitter(Duration::from_secs(100), 10);
+        assert!((Duration::from_secs(90)..=Duration::from_secs(110)).contains(&jittered));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn strict_hook_failure_discards_the_incident_from_the_batch() {
+        let pool = test_pool().await;
+        let ids = [-1681, -1682];
+        delete_incidents(&pool, &ids).await;
+
+        let mut options = test_options(RecordingSink::default());
+        let mut sinks = sink::Sinks::default();
+        sinks.add(Box::new(sink::DatabaseSink::new(pool.clone(), options.retry.clone(), true, sink::StoreOptions::default(), false)));
+        options.sinks = sinks;
+        options.commit_every = 10;
+        options.consistency = CheckMode::Off;
+        options.hook = Some(Box::new(hooks::CommandHook { command: "cat > /dev/null; test \"$INCIDENT_ID\" != -1682".to_owned() }));
+        options.strict_hook = true;
+        let mut portal = http::fake::FakePortal::default();
+        for id in ids {
+            portal = portal.respond(options.endpoints.incident_detail(id), reqwest::StatusCode::OK, include_str!("../fixtures/portal/details/101.json"));
+        }
+        let client = PortalClient::with_http(portal, &options);
+        let incidents = ids.map(|id| incident(id, "Hook").0);
+
+        // Recording the failure waited for the batch transaction before
+        let report = tokio::time::timeout(Duration::from_secs(30), process_new_incidents(&client, stream::iter(incidents), &pool, &options, None))
+            .await
+            .expect("Processing hung")
+            .unwrap();
+        assert_eq!(report.succeeded, [-1681]);
+        assert_eq!(report.failed.len(), 1);
+
+        let stored: Vec<i32> = sqlx::query_scalar("SELECT incident_id FROM incidents WHERE incident_id = ANY($1)").bind(&ids[..]).fetch_all(&pool).await.unwrap();
+        let failed: Vec<i32> = sqlx::query_scalar("SELECT incident_id FROM failed_incidents WHERE incident_id = ANY($1)").bind(&ids[..]).fetch_all(&pool).await.unwrap();
+        delete_incidents(&pool, &ids).await;
+        assert_eq!(stored, [-1681], "the incident whose hook failed is discarded from the batch");
+        assert_eq!(failed, [-1682]);
+    }
+}
@@ -0,0 +1,66 @@
+//! Machine-readable manifest of how the incident list differs from the stored incidents, written
+//! per run for downstream audits
+
+use crate::model::Incident;
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+#[derive(Debug, Serialize)]
+pub struct ChangedIncident {
+    pub incident_id: i32,
+    #[serde(serialize_with = "crate::model::serialize_naive_datetime")]
+    pub old_modified_date: NaiveDateTime,
+    #[serde(serialize_with = "crate::model::serialize_naive_datetime")]
+    pub new_modified_date: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffManifest {
+    pub run_at: DateTime<Utc>,
+    /// Listed but not stored
+    pub new: Vec<i32>,
+    /// Listed with a different `modifiedDate` than stored
+    pub changed: Vec<ChangedIncident>,
+    /// Stored but no longer listed
+    pub removed: Vec<i32>,
+}
+
+impl DiffManifest {
+    /// Diff of the incident list against the modified dates of the stored incidents
+    pub fn compute(incidents: &[Incident], stored: &HashMap<i32, NaiveDateTime>, run_at: DateTime<Utc>) -> Self {
+        let mut new = Vec::new();
+        let mut changed = Vec::new();
+        for incident in incidents {
+            match stored.get(&incident.incident_id) {
+                None => new.push(incident.incident_id),
+                Some(modified_date) if *modified_date != incident.modified_date => changed.push(ChangedIncident {
+                    incident_id: incident.incident_id,
+                    old_modified_date: *modified_date,
+                    new_modified_date: incident.modified_date,
+                }),
+                Some(_) => {}
+            }
+        }
+        let listed: HashSet<i32> = incidents.iter().map(|incident| incident.incident_id).collect();
+        let mut removed: Vec<i32> = stored.keys().copied().filter(|id| !listed.contains(id)).collect();
+
+        new.sort_unstable();
+        changed.sort_unstable_by_key(|changed| changed.incident_id);
+        removed.sort_unstable();
+        Self { run_at, new, changed, removed }
+    }
+
+    /// Write the manifest as `diff-<run timestamp>.json` into `dir`
+    pub fn write(&self, dir: &Path) -> Result<PathBuf> {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create manifest directory {}", dir.display()))?;
+        let path = dir.join(format!("diff-{}.json", self.run_at.format("%Y%m%dT%H%M%SZ")));
+        let content = serde_json::to_string_pretty(self).context("Failed to serialize diff manifest")?;
+        std::fs::write(&path, content).with_context(|| format!("Failed to write diff manifest {}", path.display()))?;
+        info!(new = self.new.len(), changed = self.changed.len(), removed = self.removed.len(), "Wrote diff manifest {}", path.display());
+        Ok(path)
+    }
+}
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS failed_incidents (
+    incident_id INTEGER PRIMARY KEY,
+    incident JSONB NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 1,
+    permanently_failed BOOLEAN NOT NULL DEFAULT FALSE,
+    last_attempt_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,2 @@
+ALTER TABLE incident_history ADD COLUMN IF NOT EXISTS etag TEXT;
+ALTER TABLE incident_history ADD COLUMN IF NOT EXISTS last_modified TEXT;
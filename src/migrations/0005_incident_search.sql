@@ -0,0 +1,4 @@
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS search_vector TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector('german', incident_text || ' ' || details_text)) STORED;
+
+CREATE INDEX IF NOT EXISTS incidents_search_vector_idx ON incidents USING GIN (search_vector);
@@ -0,0 +1,14 @@
+-- All details of an incident, the portal may return multiple affected objects per incident.
+-- `incidents` keeps the first detail for compatibility
+CREATE TABLE IF NOT EXISTS incident_details (
+    incident_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    publish_date TIMESTAMP WITH TIME ZONE NOT NULL,
+    affected_obj TEXT NOT NULL,
+    affected_type TEXT NOT NULL,
+    details_text TEXT NOT NULL,
+    tags TEXT NOT NULL,
+    href TEXT NOT NULL,
+    "references" JSONB NOT NULL,
+    PRIMARY KEY (incident_id, position)
+);
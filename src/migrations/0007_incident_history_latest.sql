@@ -0,0 +1,8 @@
+-- Latest state of every incident across all raw snapshots, rebuilt by `flatten-history`
+CREATE TABLE IF NOT EXISTS incident_history_latest (
+    incident_id INTEGER PRIMARY KEY,
+    modified_date TIMESTAMP WITH TIME ZONE NOT NULL,
+    incident JSONB NOT NULL,
+    snapshot_id INTEGER NOT NULL,
+    snapshot_created_at TIMESTAMP WITH TIME ZONE
+);
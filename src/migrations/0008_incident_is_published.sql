@@ -0,0 +1 @@
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS is_published BOOLEAN GENERATED ALWAYS AS (published = 1) STORED;
@@ -0,0 +1,4 @@
+-- Responses that aren't valid JSON are kept as text, so the bytes that broke a run are still captured
+ALTER TABLE incident_history ALTER COLUMN content DROP NOT NULL;
+ALTER TABLE incident_history ADD COLUMN IF NOT EXISTS raw_text TEXT;
+ALTER TABLE incident_history ADD COLUMN IF NOT EXISTS is_json BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1,12 @@
+-- Documents linked in the references of incidents, downloaded with `--fetch-attachments`
+CREATE TABLE IF NOT EXISTS incident_attachments (
+    incident_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    sha256 TEXT NOT NULL,
+    content BYTEA,
+    path TEXT,
+    fetched_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (incident_id, url)
+);
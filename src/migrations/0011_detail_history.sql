@@ -0,0 +1,11 @@
+-- Raw incident detail responses, stored with `--store-raw-details`
+CREATE TABLE IF NOT EXISTS detail_history (
+    id SERIAL PRIMARY KEY,
+    incident_id INTEGER NOT NULL,
+    content JSONB,
+    raw_text TEXT,
+    is_json BOOLEAN NOT NULL DEFAULT TRUE,
+    fetched_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS detail_history_incident_id_idx ON detail_history (incident_id, fetched_at);
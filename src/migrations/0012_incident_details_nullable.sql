@@ -0,0 +1,14 @@
+-- Detail-derived columns are NULL for incidents stored with `--disable-detail-fetch`
+ALTER TABLE incidents ALTER COLUMN publish_date DROP NOT NULL;
+ALTER TABLE incidents ALTER COLUMN affected_obj DROP NOT NULL;
+ALTER TABLE incidents ALTER COLUMN affected_type DROP NOT NULL;
+ALTER TABLE incidents ALTER COLUMN details_text DROP NOT NULL;
+ALTER TABLE incidents ALTER COLUMN tags DROP NOT NULL;
+ALTER TABLE incidents ALTER COLUMN href DROP NOT NULL;
+ALTER TABLE incidents ALTER COLUMN "references" DROP NOT NULL;
+
+-- A generated column's expression can't be altered, so it is recreated tolerating a NULL details_text
+ALTER TABLE incidents DROP COLUMN IF EXISTS search_vector;
+ALTER TABLE incidents ADD COLUMN search_vector TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector('german', incident_text || ' ' || coalesce(details_text, ''))) STORED;
+CREATE INDEX IF NOT EXISTS incidents_search_vector_idx ON incidents USING GIN (search_vector);
@@ -0,0 +1,3 @@
+-- Plain text versions of the HTML texts, stored with `--strip-html`
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS incident_text_plain TEXT;
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS details_text_plain TEXT;
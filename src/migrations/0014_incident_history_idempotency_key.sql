@@ -0,0 +1,3 @@
+-- `<run id>:<fetch sequence>` of the fetch a snapshot was stored by, so a retried run doesn't store it twice
+ALTER TABLE incident_history ADD COLUMN IF NOT EXISTS idempotency_key TEXT;
+CREATE UNIQUE INDEX IF NOT EXISTS incident_history_idempotency_key_idx ON incident_history (idempotency_key);
@@ -0,0 +1,3 @@
+-- Validators of raw detail responses for conditional requests with `--revalidate-details`
+ALTER TABLE detail_history ADD COLUMN IF NOT EXISTS etag TEXT;
+ALTER TABLE detail_history ADD COLUMN IF NOT EXISTS last_modified TEXT;
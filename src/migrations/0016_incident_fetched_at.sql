@@ -0,0 +1,3 @@
+-- Time an incident was last stored from the portal, for re-syncing with `--max-age`. `NULL` for
+-- incidents stored before this column existed
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS fetched_at TIMESTAMP WITH TIME ZONE;
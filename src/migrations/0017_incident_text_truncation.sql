@@ -0,0 +1,3 @@
+-- Original length in bytes of texts truncated by `--max-incident-text-bytes`, `NULL` if not truncated
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS incident_text_original_bytes INTEGER;
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS details_text_original_bytes INTEGER;
@@ -0,0 +1,2 @@
+-- HMAC of the row stored with `--row-hmac-key`, checked by `verify-hash`
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS row_hmac TEXT;
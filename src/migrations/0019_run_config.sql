@@ -0,0 +1,6 @@
+-- Effective configuration of runs with `--record-config`, secrets redacted
+CREATE TABLE IF NOT EXISTS run_config (
+    run_id TEXT PRIMARY KEY,
+    recorded_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    config JSONB NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Raw responses stored gzip-compressed with `--compress-history` instead of as jsonb in `content`
+ALTER TABLE incident_history ADD COLUMN IF NOT EXISTS content_gzip BYTEA;
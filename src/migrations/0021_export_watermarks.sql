@@ -0,0 +1,6 @@
+-- Newest `fetched_at` exported by `export --incremental`, so the next export continues after it
+CREATE TABLE IF NOT EXISTS export_watermarks (
+    name TEXT PRIMARY KEY,
+    watermark TIMESTAMP WITH TIME ZONE NOT NULL,
+    exported_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,2 @@
+-- Time an incident's published flag went from 1 to something else, kept with `--mark-unpublished`
+ALTER TABLE incidents ADD COLUMN IF NOT EXISTS unpublished_at TIMESTAMP WITH TIME ZONE;
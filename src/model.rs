@@ -0,0 +1,151 @@
+//! Incidents and their details as returned by the portal
+
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashSet;
+
+// Debug is implemented in main.rs so the free texts honour `--redact-logs`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Incident {
+    #[serde(rename = "incidentID")]
+    pub incident_id: i32,
+    #[serde(rename = "orgPublishDate")]
+    pub org_publish_date: NaiveDate,
+    #[serde(deserialize_with = "parse_naive_datetime", serialize_with = "serialize_naive_datetime")]
+    #[serde(rename = "modifiedDate")]
+    pub modified_date: NaiveDateTime,
+    pub published: i32,
+    pub country: String,
+    #[serde(rename = "incidentText")]
+    pub incident_text: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IncidentDetail {
+    #[serde(rename = "publishDate")]
+    pub publish_date: NaiveDate,
+    #[serde(rename = "affectedObj")]
+    pub affected_obj: String,
+    #[serde(rename = "affectedType")]
+    pub affected_type: String,
+    #[serde(rename = "description_de")]
+    pub details_text: String,
+    pub tags: String,
+    pub href: String,
+    /// JSON text of the references, see [`parse_references`]
+    #[serde(deserialize_with = "deserialize_reference")]
+    pub reference: String,
+}
+
+/// The portal sends `reference` as a JSON-encoded string, but a JSON value is accepted too and kept as its JSON text
+fn deserialize_reference<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(reference) => Ok(reference),
+        serde_json::Value::Null => Ok(String::new()),
+        value => Ok(value.to_string()),
+    }
+}
+
+/// References of a detail as JSON. Besides the JSON text itself, a JSON string that contains JSON
+/// again (encoded once more) is unwrapped, and an empty reference is an empty list
+pub fn parse_references(reference: &str) -> serde_json::Result<serde_json::Value> {
+    if reference.trim().is_empty() {
+        return Ok(serde_json::Value::Array(Vec::new()));
+    }
+    match serde_json::from_str(reference)? {
+        serde_json::Value::String(inner) if !inner.trim().is_empty() => Ok(serde_json::from_str(&inner).unwrap_or(serde_json::Value::String(inner))),
+        serde_json::Value::String(_) => Ok(serde_json::Value::Array(Vec::new())),
+        value => Ok(value),
+    }
+}
+
+pub fn parse_naive_datetime<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    NaiveDateTime::parse_from_str(&s, "%Y-%m-%d %H:%M:%S")
+        .map_err(|e| serde::de::Error::custom(format!("Failed to parse datetime '{}': {}", s, e)))
+}
+
+pub fn serialize_naive_datetime<S>(datetime: &NaiveDateTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&datetime.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+impl IncidentDetail {
+    pub fn references(&self) -> serde_json::Result<serde_json::Value> {
+        parse_references(&self.reference)
+    }
+}
+
+impl Incident {
+    /// Whether the incident is live on the portal, unpublished ones may be drafts or retracted
+    pub fn is_published(&self) -> bool {
+        self.published == 1
+    }
+}
+
+/// Incidents of the list that aren't stored yet, keeping their order
+pub fn select_new_incidents(incidents: Vec<Incident>, existing_ids: &HashSet<i32>) -> Vec<Incident> {
+    incidents
+        .into_iter()
+        .filter(|incident| !existing_ids.contains(&incident.incident_id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn detail(reference: serde_json::Value) -> IncidentDetail {
+        serde_json::from_value(json!({
+            "publishDate": "2024-03-01",
+            "affectedObj": "",
+            "affectedType": "",
+            "description_de": "",
+            "tags": "",
+            "href": "",
+            "reference": reference,
+        }))
+            .unwrap()
+    }
+
+    #[test]
+    fn raw_array_reference() {
+        let detail = detail(json!(["https://example.org/a.pdf", {"title": "Bescheid"}]));
+        assert_eq!(detail.reference, r#"["https://example.org/a.pdf",{"title":"Bescheid"}]"#);
+        assert_eq!(detail.references().unwrap(), json!(["https://example.org/a.pdf", {"title": "Bescheid"}]));
+    }
+
+    #[test]
+    fn json_encoded_string_reference() {
+        let detail = detail(json!(r#"["https://example.org/a.pdf"]"#));
+        assert_eq!(detail.references().unwrap(), json!(["https://example.org/a.pdf"]));
+    }
+
+    #[test]
+    fn double_encoded_string_reference() {
+        assert_eq!(parse_references(r#""[\"https://example.org/a.pdf\"]""#).unwrap(), json!(["https://example.org/a.pdf"]));
+        assert_eq!(parse_references(r#""https://example.org/a.pdf""#).unwrap(), json!("https://example.org/a.pdf"));
+    }
+
+    #[test]
+    fn empty_reference() {
+        for reference in [json!(""), json!(null), json!("  ")] {
+            assert_eq!(detail(reference).references().unwrap(), json!([]));
+        }
+        assert_eq!(parse_references(r#""""#).unwrap(), json!([]));
+    }
+
+    #[test]
+    fn invalid_reference() {
+        assert!(detail(json!("[not json")).references().is_err());
+    }
+}
@@ -0,0 +1,328 @@
+//! Pacing of requests to the portal, honouring rate limits announced via response headers
+
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// Rate-limit budget as announced by the server
+#[derive(Debug, Clone, Copy)]
+struct RateLimit {
+    remaining: u64,
+    reset: Instant,
+}
+
+/// Why a delay was chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelayReason {
+    /// The static `--delay`
+    Base,
+    /// Spreading the announced remaining budget until the reset
+    RateLimit,
+    /// Waiting for the reset of an exhausted budget
+    RateLimitExhausted,
+}
+
+/// Minimum, average and maximum of the delays used
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DelayStats {
+    pub count: u32,
+    pub min: Duration,
+    pub max: Duration,
+    total: Duration,
+}
+
+impl DelayStats {
+    fn add(&mut self, delay: Duration) {
+        self.min = if self.count == 0 { delay } else { self.min.min(delay) };
+        self.max = self.max.max(delay);
+        self.total += delay;
+        self.count += 1;
+    }
+
+    pub fn avg(&self) -> Duration {
+        self.total.checked_div(self.count).unwrap_or_default()
+    }
+}
+
+/// Window of the rolling request rate
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Achieved request rate, as a rolling requests-per-minute and an average over the run
+struct RequestRate {
+    started: Instant,
+    total: u64,
+    /// Times of the requests within the last `RATE_WINDOW`
+    recent: VecDeque<Instant>,
+    /// Log the rolling rate this often, never if `None`
+    log_interval: Option<Duration>,
+    last_log: Instant,
+}
+
+impl RequestRate {
+    fn new(log_interval: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Self { started: now, total: 0, recent: VecDeque::new(), log_interval, last_log: now }
+    }
+
+    fn record(&mut self) {
+        let now = Instant::now();
+        self.total += 1;
+        self.recent.push_back(now);
+        while self.recent.front().is_some_and(|time| now.duration_since(*time) > RATE_WINDOW) {
+            self.recent.pop_front();
+        }
+
+        if self.log_interval.is_some_and(|interval| now.duration_since(self.last_log) >= interval) {
+            info!(requests = self.total, "Request rate: {} requests in the last minute", self.recent.len());
+            self.last_log = now;
+        }
+    }
+
+    /// Average requests per minute since the start
+    fn average(&self) -> f64 {
+        let minutes = self.started.elapsed().as_secs_f64() / 60.0;
+        if minutes > 0.0 { self.total as f64 / minutes } else { 0.0 }
+    }
+}
+
+/// Thresholds for warning that the portal is likely throttling this client
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottleThresholds {
+    /// Number of 429 and 503 responses, also if a retry succeeded, `0` disables the check
+    pub responses: u32,
+    /// Factor by which recent response times exceed those at the start of the run
+    pub latency_factor: f64,
+}
+
+/// Number of responses the response times at the start and the recent ones are averaged over
+const LATENCY_SAMPLES: usize = 5;
+
+/// Recent response times below this never count as a spike, so jitter of fast responses doesn't warn
+const MIN_SPIKE_LATENCY: Duration = Duration::from_secs(1);
+
+fn average(latencies: impl ExactSizeIterator<Item = Duration>) -> Duration {
+    let count = u32::try_from(latencies.len()).unwrap_or(u32::MAX);
+    latencies.sum::<Duration>().checked_div(count).unwrap_or_default()
+}
+
+/// Signs of throttling: throttling status codes and response times growing over the run
+struct ThrottleDetector {
+    thresholds: ThrottleThresholds,
+    throttled: u32,
+    /// Response times of the first responses
+    baseline: Vec<Duration>,
+    recent: VecDeque<Duration>,
+    /// Why throttling is suspected, checked until it is
+    suspected: Option<String>,
+}
+
+impl ThrottleDetector {
+    fn new(thresholds: ThrottleThresholds) -> Self {
+        Self { thresholds, throttled: 0, baseline: Vec::new(), recent: VecDeque::new(), suspected: None }
+    }
+
+    /// Record a response, `None` for a failed request. Returns the reason once throttling is first suspected
+    fn record(&mut self, status: Option<StatusCode>, latency: Duration) -> Option<String> {
+        if matches!(status, Some(StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE)) {
+            self.throttled += 1;
+        }
+        if status.is_some() {
+            if self.baseline.len() < LATENCY_SAMPLES {
+                self.baseline.push(latency);
+            }
+            self.recent.push_back(latency);
+            if self.recent.len() > LATENCY_SAMPLES {
+                self.recent.pop_front();
+            }
+        }
+        if self.suspected.is_some() {
+            return None;
+        }
+
+        let reason = if self.thresholds.responses > 0 && self.throttled >= self.thresholds.responses {
+            format!("{} responses with status 429 or 503", self.throttled)
+        } else {
+            let baseline = average(self.baseline.iter().copied());
+            let recent = average(self.recent.iter().copied());
+            let spiked = self.baseline.len() == LATENCY_SAMPLES
+                && self.recent.len() == LATENCY_SAMPLES
+                && recent >= MIN_SPIKE_LATENCY
+                && recent.as_secs_f64() > baseline.as_secs_f64() * self.thresholds.latency_factor;
+            if !spiked {
+                return None;
+            }
+            format!("response times rose from {:?} to {:?} on average", baseline, recent)
+        };
+        self.suspected = Some(reason.clone());
+        Some(reason)
+    }
+}
+
+/// Paces requests to the portal. Uses the static delay unless the portal announces a rate limit
+/// via `X-RateLimit-Remaining` / `X-RateLimit-Reset`, in which case the remaining budget is spread
+/// until the reset. The static delay is never undercut
+pub struct Pacer {
+    base_delay: Duration,
+    rate_limit: Mutex<Option<RateLimit>>,
+    stats: Mutex<DelayStats>,
+    rate: Mutex<RequestRate>,
+    throttle: Mutex<ThrottleDetector>,
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+impl Pacer {
+    /// Pacer using `base_delay`, logging the request rate every `rate_log_interval` if given
+    pub fn new(base_delay: Duration, rate_log_interval: Option<Duration>, throttle: ThrottleThresholds) -> Self {
+        Self {
+            base_delay,
+            rate_limit: Mutex::new(None),
+            stats: Default::default(),
+            rate: Mutex::new(RequestRate::new(rate_log_interval)),
+            throttle: Mutex::new(ThrottleDetector::new(throttle)),
+        }
+    }
+
+    /// Record the status of a response and how long it took, `None` for a failed request. Warns
+    /// once if the responses suggest that the portal is throttling this client
+    pub fn record_response(&self, url: &str, status: Option<StatusCode>, latency: Duration) {
+        let suspected = self.throttle.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).record(status, latency);
+        if let Some(reason) = suspected {
+            warn!(
+                "The portal is likely throttling this client ({}, last request to {}), increase --delay (currently {:?}) before access is blocked",
+                reason, url, self.base_delay,
+            );
+        }
+    }
+
+    /// Why the portal is suspected to throttle this client, if it is
+    pub fn throttling(&self) -> Option<String> {
+        self.throttle.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).suspected.clone()
+    }
+
+    /// Count a request for the request rate
+    pub fn record_request(&self) {
+        self.rate.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).record();
+    }
+
+    /// Number of requests and their average rate per minute so far
+    pub fn request_rate(&self) -> (u64, f64) {
+        let rate = self.rate.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        (rate.total, rate.average())
+    }
+
+    /// Record the rate-limit state from the headers of a response
+    pub fn observe(&self, headers: &HeaderMap) {
+        let (Some(remaining), Some(reset)) = (header_u64(headers, "x-ratelimit-remaining"), header_u64(headers, "x-ratelimit-reset")) else {
+            return;
+        };
+
+        // Reset is either a unix timestamp or the number of seconds until the reset
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let seconds_until_reset = if reset > now { reset - now } else if reset > 1_000_000_000 { 0 } else { reset };
+        debug!(remaining, seconds_until_reset, "Observed rate limit");
+
+        let rate_limit = RateLimit { remaining, reset: Instant::now() + Duration::from_secs(seconds_until_reset) };
+        *self.rate_limit.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(rate_limit);
+    }
+
+    /// Delay before the next request and why it was chosen
+    pub fn next_delay(&self) -> (Duration, DelayReason) {
+        let rate_limit = *self.rate_limit.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(rate_limit) = rate_limit else {
+            return (self.base_delay, DelayReason::Base);
+        };
+
+        let until_reset = rate_limit.reset.saturating_duration_since(Instant::now());
+        if until_reset.is_zero() {
+            return (self.base_delay, DelayReason::Base);
+        }
+        let (delay, reason) = if rate_limit.remaining <= 1 {
+            (until_reset, DelayReason::RateLimitExhausted)
+        } else {
+            (until_reset / u32::try_from(rate_limit.remaining).unwrap_or(u32::MAX), DelayReason::RateLimit)
+        };
+        if delay > self.base_delay {
+            (delay, reason)
+        } else {
+            (self.base_delay, DelayReason::Base)
+        }
+    }
+
+    pub async fn wait(&self) {
+        let (delay, reason) = self.next_delay();
+        debug!(delay_ms = delay.as_millis() as u64, reason = ?reason, "Effective delay before next request");
+        self.stats.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).add(delay);
+        tokio::time::sleep(delay).await;
+    }
+
+    /// Delays used so far
+    pub fn stats(&self) -> DelayStats {
+        *self.stats.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Pacers of the hosts requests are sent to. All hosts share one pacer unless pacing per host is
+/// enabled, then every host gets its own delay, rate limit and stats, e.g. so the rate limit of a
+/// document host doesn't slow down the portal
+pub struct HostPacers {
+    base_delay: Duration,
+    rate_log_interval: Option<Duration>,
+    throttle: ThrottleThresholds,
+    per_host: bool,
+    global: Arc<Pacer>,
+    hosts: Mutex<HashMap<String, Arc<Pacer>>>,
+}
+
+impl HostPacers {
+    pub fn new(base_delay: Duration, rate_log_interval: Option<Duration>, throttle: ThrottleThresholds, per_host: bool) -> Self {
+        Self {
+            base_delay,
+            rate_log_interval,
+            throttle,
+            per_host,
+            global: Arc::new(Pacer::new(base_delay, rate_log_interval, throttle)),
+            hosts: Default::default(),
+        }
+    }
+
+    /// Pacer for requests to `url`, the shared one for global pacing or URLs without host
+    pub fn for_url(&self, url: &str) -> Arc<Pacer> {
+        let host = reqwest::Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_owned));
+        let (true, Some(host)) = (self.per_host, host) else {
+            return self.global.clone();
+        };
+        let mut hosts = self.hosts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        hosts
+            .entry(host)
+            .or_insert_with_key(|host| {
+                debug!(host, "Pacing requests to new host");
+                Arc::new(Pacer::new(self.base_delay, self.rate_log_interval, self.throttle))
+            })
+            .clone()
+    }
+
+    /// Number of requests to all hosts so far
+    pub fn total_requests(&self) -> u64 {
+        self.all().iter().map(|(_, pacer)| pacer.request_rate().0).sum()
+    }
+
+    /// Why throttling is suspected, per pacer with its host as in [`HostPacers::all`]
+    pub fn throttling(&self) -> Vec<(Option<String>, String)> {
+        self.all().into_iter().filter_map(|(host, pacer)| pacer.throttling().map(|reason| (host, reason))).collect()
+    }
+
+    /// All pacers used so far with their host, `None` for the shared pacer
+    pub fn all(&self) -> Vec<(Option<String>, Arc<Pacer>)> {
+        let hosts = self.hosts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut pacers: Vec<_> = hosts.iter().map(|(host, pacer)| (Some(host.clone()), pacer.clone())).collect();
+        pacers.sort_by(|(a, _), (b, _)| a.cmp(b));
+        pacers.insert(0, (None, self.global.clone()));
+        pacers
+    }
+}
@@ -0,0 +1,153 @@
+//! Parquet output of `export`, mapping the columns of `incidents` to an Arrow schema so the export
+//! can be queried directly by DuckDB, Spark and similar tools
+
+use anyhow::{Context, Result};
+use arrow_array::builder::{BooleanBuilder, Date32Builder, Int32Builder, StringBuilder, TimestampMicrosecondBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{DateTime, NaiveDate};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde_json::Value;
+use std::io::Write;
+use std::sync::Arc;
+
+#[derive(Clone, Copy)]
+enum ColumnType {
+    Int32,
+    Boolean,
+    Date,
+    Timestamp,
+    Text,
+    /// JSON values, exported as their serialized text
+    Json,
+}
+
+/// Exported columns of `incidents` with their type and nullability, `search_vector` is left out
+const COLUMNS: &[(&str, ColumnType, bool)] = &[
+    ("incident_id", ColumnType::Int32, false),
+    ("org_publish_date", ColumnType::Date, false),
+    ("modified_date", ColumnType::Timestamp, false),
+    ("published", ColumnType::Int32, false),
+    ("is_published", ColumnType::Boolean, true),
+    ("publish_date", ColumnType::Timestamp, true),
+    ("affected_obj", ColumnType::Text, true),
+    ("affected_type", ColumnType::Text, true),
+    ("country", ColumnType::Text, false),
+    ("details_text", ColumnType::Text, true),
+    ("tags", ColumnType::Text, true),
+    ("href", ColumnType::Text, true),
+    ("references", ColumnType::Json, true),
+    ("incident_text", ColumnType::Text, false),
+    ("incident_text_plain", ColumnType::Text, true),
+    ("details_text_plain", ColumnType::Text, true),
+    ("fetched_at", ColumnType::Timestamp, true),
+    ("incident_text_original_bytes", ColumnType::Int32, true),
+    ("details_text_original_bytes", ColumnType::Int32, true),
+    ("row_hmac", ColumnType::Text, true),
+    ("unpublished_at", ColumnType::Timestamp, true),
+];
+
+fn data_type(column_type: ColumnType) -> DataType {
+    match column_type {
+        ColumnType::Int32 => DataType::Int32,
+        ColumnType::Boolean => DataType::Boolean,
+        ColumnType::Date => DataType::Date32,
+        ColumnType::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+        ColumnType::Text | ColumnType::Json => DataType::Utf8,
+    }
+}
+
+fn schema() -> Schema {
+    Schema::new(
+        COLUMNS
+            .iter()
+            .map(|(name, column_type, nullable)| Field::new(*name, data_type(*column_type), *nullable))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Arrow array of one column of the incidents as read via `to_jsonb`
+fn build_column(name: &str, column_type: ColumnType, incidents: &[Value]) -> Result<ArrayRef> {
+    let values = incidents.iter().map(|incident| incident.get(name).filter(|value| !value.is_null()));
+    let invalid = || format!("Column {} can't be exported as {:?}, e.g. because it was redacted", name, data_type(column_type));
+    let array: ArrayRef = match column_type {
+        ColumnType::Int32 => {
+            let mut builder = Int32Builder::with_capacity(incidents.len());
+            for value in values {
+                let value = value.map(|value| value.as_i64().and_then(|value| i32::try_from(value).ok()).with_context(invalid)).transpose()?;
+                builder.append_option(value);
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Boolean => {
+            let mut builder = BooleanBuilder::with_capacity(incidents.len());
+            for value in values {
+                builder.append_option(value.map(|value| value.as_bool().with_context(invalid)).transpose()?);
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Date => {
+            let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).context("Invalid epoch")?;
+            let mut builder = Date32Builder::with_capacity(incidents.len());
+            for value in values {
+                let days = value
+                    .map(|value| {
+                        let date: NaiveDate = value.as_str().and_then(|date| date.parse().ok()).with_context(invalid)?;
+                        i32::try_from((date - epoch).num_days()).ok().with_context(invalid)
+                    })
+                    .transpose()?;
+                builder.append_option(days);
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Timestamp => {
+            let mut builder = TimestampMicrosecondBuilder::with_capacity(incidents.len()).with_timezone("UTC");
+            for value in values {
+                let micros = value
+                    .map(|value| {
+                        value
+                            .as_str()
+                            .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+                            .map(|timestamp| timestamp.timestamp_micros())
+                            .with_context(invalid)
+                    })
+                    .transpose()?;
+                builder.append_option(micros);
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Text => {
+            let mut builder = StringBuilder::new();
+            for value in values {
+                builder.append_option(value.map(|value| value.as_str().with_context(invalid)).transpose()?);
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Json => {
+            let mut builder = StringBuilder::new();
+            for value in values {
+                builder.append_option(value.map(Value::to_string));
+            }
+            Arc::new(builder.finish())
+        }
+    };
+    Ok(array)
+}
+
+/// Write the incidents as one Snappy-compressed Parquet row group
+pub fn write<W: Write + Send>(writer: W, incidents: &[Value]) -> Result<()> {
+    let schema = Arc::new(schema());
+    let columns = COLUMNS
+        .iter()
+        .map(|(name, column_type, _)| build_column(name, *column_type, incidents))
+        .collect::<Result<Vec<_>>>()?;
+    let batch = RecordBatch::try_new(schema.clone(), columns).context("Failed to build Parquet record batch")?;
+
+    let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+    let mut writer = ArrowWriter::try_new(writer, schema, Some(properties)).context("Failed to create Parquet writer")?;
+    writer.write(&batch).context("Failed to write Parquet record batch")?;
+    writer.close().context("Failed to finish Parquet file")?;
+    Ok(())
+}
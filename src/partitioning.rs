@@ -0,0 +1,123 @@
+//! Optional declarative partitioning of the `incidents` table by year of `org_publish_date`
+
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use tracing::{debug, info, trace};
+
+/// Columns of `incidents` that are written, `is_published` and `search_vector` are generated
+const COLUMNS: &str = r#"incident_id, org_publish_date, modified_date, published, publish_date,
+    affected_obj, affected_type, country, details_text, tags, href,
+    "references", incident_text, incident_text_plain, details_text_plain, fetched_at,
+    incident_text_original_bytes, details_text_original_bytes, row_hmac, unpublished_at"#;
+
+/// Whether `incidents` is a partitioned table
+pub async fn is_partitioned(pool: &sqlx::PgPool) -> Result<bool> {
+    let partitioned: Option<bool> = sqlx::query_scalar("SELECT relkind = 'p' FROM pg_class WHERE oid = to_regclass('incidents')")
+        .fetch_optional(pool)
+        .await
+        .context("Failed to check whether incidents is partitioned")?;
+    Ok(partitioned.unwrap_or(false))
+}
+
+fn create_partition_sql(parent: &str, year: i32) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS incidents_y{year} PARTITION OF {parent} FOR VALUES FROM ('{year}-01-01') TO ('{next}-01-01')",
+        year = year,
+        next = year + 1,
+        parent = parent,
+    )
+}
+
+/// Convert `incidents` into a table partitioned by year, moving all stored incidents.
+/// The foreign key of `incident_revisions` is dropped as a partitioned table can't have a unique
+/// constraint on `incident_id` alone
+pub async fn partition_by_year(pool: &sqlx::PgPool) -> Result<()> {
+    if is_partitioned(pool).await? {
+        info!("incidents is already partitioned by year");
+        return Ok(());
+    }
+
+    let mut tx = pool.begin().await.context("Failed to start transaction")?;
+
+    let years: Vec<i32> = sqlx::query_scalar("SELECT DISTINCT extract(year FROM org_publish_date)::integer FROM incidents ORDER BY 1")
+        .fetch_all(&mut *tx)
+        .await
+        .context("Failed to fetch years of stored incidents")?;
+    info!("Partitioning incidents by year, creating partitions for {:?}", years);
+
+    sqlx::raw_sql(
+        r#"ALTER TABLE incident_revisions DROP CONSTRAINT IF EXISTS incident_revisions_incident_id_fkey;
+        CREATE TABLE incidents_partitioned (
+            incident_id INTEGER NOT NULL,
+            org_publish_date DATE NOT NULL,
+            modified_date TIMESTAMP WITH TIME ZONE NOT NULL,
+            published INTEGER NOT NULL,
+            is_published BOOLEAN GENERATED ALWAYS AS (published = 1) STORED,
+            publish_date TIMESTAMP WITH TIME ZONE,
+            affected_obj TEXT,
+            affected_type TEXT,
+            country TEXT NOT NULL,
+            details_text TEXT,
+            tags TEXT,
+            href TEXT,
+            "references" JSONB,
+            incident_text TEXT NOT NULL,
+            incident_text_plain TEXT,
+            details_text_plain TEXT,
+            fetched_at TIMESTAMP WITH TIME ZONE,
+            incident_text_original_bytes INTEGER,
+            details_text_original_bytes INTEGER,
+            row_hmac TEXT,
+            unpublished_at TIMESTAMP WITH TIME ZONE,
+            search_vector TSVECTOR GENERATED ALWAYS AS (to_tsvector('german', incident_text || ' ' || coalesce(details_text, ''))) STORED,
+            PRIMARY KEY (incident_id, org_publish_date)
+        ) PARTITION BY RANGE (org_publish_date)"#,
+    )
+        .execute(&mut *tx)
+        .await
+        .context("Failed to create partitioned table")?;
+
+    for year in &years {
+        sqlx::raw_sql(&create_partition_sql("incidents_partitioned", *year))
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to create partition for {}", year))?;
+    }
+
+    let moved = sqlx::raw_sql(&format!("INSERT INTO incidents_partitioned ({columns}) SELECT {columns} FROM incidents", columns = COLUMNS))
+        .execute(&mut *tx)
+        .await
+        .context("Failed to move incidents into the partitioned table")?;
+    debug!("Moved {} incidents", moved.rows_affected());
+
+    sqlx::raw_sql(
+        r#"DROP TABLE incidents;
+        ALTER TABLE incidents_partitioned RENAME TO incidents;
+        ALTER TABLE incidents RENAME CONSTRAINT incidents_partitioned_pkey TO incidents_pkey;
+        CREATE INDEX incidents_search_vector_idx ON incidents USING GIN (search_vector);"#,
+    )
+        .execute(&mut *tx)
+        .await
+        .context("Failed to replace incidents with the partitioned table")?;
+
+    tx.commit().await.context("Failed to commit partitioning")?;
+    info!("Partitioned incidents into {} yearly partitions", years.len());
+    Ok(())
+}
+
+/// Create the yearly partitions needed to store incidents published in `years`, a no-op if
+/// `incidents` isn't partitioned
+pub async fn ensure_year_partitions(pool: &sqlx::PgPool, years: &BTreeSet<i32>) -> Result<()> {
+    if years.is_empty() || !is_partitioned(pool).await? {
+        return Ok(());
+    }
+
+    for year in years {
+        trace!("Ensuring partition for {}", year);
+        sqlx::raw_sql(&create_partition_sql("incidents", *year))
+            .execute(pool)
+            .await
+            .with_context(|| format!("Failed to create partition for {}", year))?;
+    }
+    Ok(())
+}
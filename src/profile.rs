@@ -0,0 +1,81 @@
+//! Named portal profiles bundling the base URL, endpoint paths, referers and response-shape quirks,
+//! so a restructured or sister portal is supported by adding a profile instead of editing URLs
+
+use crate::field_mapping::FieldMapping;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::debug;
+
+/// Profile used unless `--portal-profile` is given
+pub const DEFAULT_PROFILE: &str = "dsgvo-portal";
+
+/// Endpoint layout of a portal. In a profiles file, fields that aren't given use the values of
+/// the default profile
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortalProfile {
+    pub base_url: String,
+    /// Path of the incident list
+    pub incidents_path: String,
+    pub incidents_referer_path: String,
+    /// Path of the details of an incident, `{id}` is replaced by the incident id
+    pub incident_detail_path: String,
+    pub incident_detail_referer_path: String,
+    /// Keys the portal uses for the fields, see `--field-mapping`
+    pub field_mapping: FieldMapping,
+}
+
+impl Default for PortalProfile {
+    fn default() -> Self {
+        Self {
+            base_url: "https://www.dsgvo-portal.de".to_owned(),
+            incidents_path: "/sicherheitsvorfall-datenbank/?cmd=getIncidents".to_owned(),
+            incidents_referer_path: "/sicherheitsvorfall-datenbank/".to_owned(),
+            incident_detail_path: "/sicherheitsvorfall-datenbank/incidentDetails.php?incident={id}".to_owned(),
+            incident_detail_referer_path: "/sicherheitsvorfaelle/".to_owned(),
+            field_mapping: FieldMapping::default(),
+        }
+    }
+}
+
+/// Parse a `--detail-url-template`, which has to contain the `{id}` placeholder
+pub fn parse_detail_url_template(value: &str) -> Result<String, String> {
+    if !value.contains("{id}") {
+        return Err(format!("'{}' doesn't contain the {{id}} placeholder", value));
+    }
+    if !value.starts_with('/') {
+        return Err(format!("'{}' isn't a path relative to the base URL starting with /", value));
+    }
+    Ok(value.to_owned())
+}
+
+/// Profiles compiled into the binary
+fn builtin(name: &str) -> Option<PortalProfile> {
+    match name {
+        DEFAULT_PROFILE => Some(PortalProfile::default()),
+        _ => None,
+    }
+}
+
+/// Profile `name` from the profiles file if given, which may also override built-in profiles
+pub fn resolve(name: &str, profiles_file: Option<&Path>) -> Result<PortalProfile> {
+    let mut profiles: HashMap<String, PortalProfile> = match profiles_file {
+        Some(path) => {
+            let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read portal profiles {}", path.display()))?;
+            serde_json::from_str(&content).with_context(|| format!("Failed to parse portal profiles {}", path.display()))?
+        }
+        None => HashMap::new(),
+    };
+    let profile = match profiles.remove(name).or_else(|| builtin(name)) {
+        Some(profile) => profile,
+        None => anyhow::bail!("Unknown portal profile '{}'", name),
+    };
+    profile.field_mapping.validate()?;
+    if !profile.incident_detail_path.contains("{id}") {
+        anyhow::bail!("incident_detail_path of portal profile '{}' doesn't contain {{id}}", name);
+    }
+    debug!("Using portal profile {}: {:?}", name, profile);
+    Ok(profile)
+}
@@ -0,0 +1,73 @@
+//! Refetching incidents whose ids are read from stdin, one per line, so other tools like a diff job
+//! or a monitoring alert can feed exactly the incidents that need refreshing
+
+use crate::http::HttpClient;
+use crate::model::Incident;
+use crate::{PortalClient, RunOptions};
+use anyhow::{Context, Result};
+use chrono::Datelike;
+use std::collections::HashMap;
+use std::io::BufRead;
+use tracing::{debug, info, warn};
+
+/// Fetch the details of every incident id read from stdin and upsert it, until EOF. The list fields
+/// are taken from the current incident list. Malformed lines and ids that aren't listed are skipped
+/// with a warning, failed incidents are recorded for `retry-failed`
+pub async fn refetch_from_stdin<H: HttpClient>(client: &PortalClient<H>, pool: &sqlx::PgPool, options: &RunOptions) -> Result<()> {
+    let incidents = match crate::fetch_incidents(client, pool, options).await? {
+        Some(incidents) => incidents,
+        None => crate::get_last_snapshot_incidents(pool, options).await?,
+    };
+    let incidents: HashMap<i32, Incident> = incidents.into_iter().map(|incident| (incident.incident_id, incident)).collect();
+    info!("Reading incident ids to refetch from stdin");
+
+    let mut lines = std::io::stdin().lock().lines();
+    let (mut succeeded, mut failed, mut skipped) = (0, 0, 0);
+    let mut line_number = 0;
+    // Reading stdin blocks, e.g. while the producing tool is still working
+    while let Some(line) = tokio::task::block_in_place(|| lines.next()) {
+        line_number += 1;
+        let line = line.context("Failed to read incident ids from stdin")?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Ok(id) = line.parse::<i32>() else {
+            warn!("Skipping malformed line {} of stdin: {:?}", line_number, line);
+            skipped += 1;
+            continue;
+        };
+        let Some(incident) = incidents.get(&id) else {
+            warn!(incident_id = id, "Skipping incident that isn't in the incident list");
+            skipped += 1;
+            continue;
+        };
+        if !options.id_filter.allows(id) {
+            debug!(incident_id = id, "Skipping incident filtered by --include-id/--exclude-id");
+            skipped += 1;
+            continue;
+        }
+
+        crate::partitioning::ensure_year_partitions(pool, &[incident.org_publish_date.year()].into()).await?;
+        match crate::telemetry::in_span("process_incident", vec![("incident.id", id.to_string())], crate::process_incident(client, pool, incident, options)).await {
+            Ok(crate::Processed::Stored | crate::Processed::Unchanged) => {
+                options.sinks.flush().await?;
+                succeeded += 1;
+            }
+            Ok(crate::Processed::Skipped) => skipped += 1,
+            Err(err) => {
+                warn!(incident_id = id, "Refetch of incident failed: {:#}", err);
+                crate::record_failed_incident(pool, incident, &err).await?;
+                failed += 1;
+            }
+        }
+        client.pacer(&options.endpoints.incident_detail(id)).wait().await;
+    }
+
+    info!("Refetched incidents from stdin: {} succeeded, {} failed, {} skipped", succeeded, failed, skipped);
+    crate::log_delay_stats(&client.pacers);
+    if failed > 0 {
+        anyhow::bail!("Failed to refetch {} incidents", failed);
+    }
+    Ok(())
+}
@@ -0,0 +1,118 @@
+//! Human-readable Markdown report of a run written with `--report-file`, for skimming what
+//! happened or attaching to a mail or ticket after a nightly run
+
+use crate::manifest::ChangedIncident;
+use crate::ProcessReport;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::fmt::Write;
+use std::path::Path;
+use tracing::info;
+
+/// Length of the incident titles in the report
+const TITLE_CHARS: usize = 80;
+
+#[derive(Default)]
+pub struct RunReport {
+    pub run_id: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// Number of listed incidents, `None` if the list wasn't fetched or didn't change since the last run
+    pub listed: Option<usize>,
+    /// Whether the list didn't change since the last run
+    pub unchanged: bool,
+    /// Ids and titles of the new incidents
+    pub new: Vec<(i32, String)>,
+    pub changed: Vec<ChangedIncident>,
+    pub process: ProcessReport,
+    pub requests: u64,
+    /// Why the portal was suspected to throttle requests, per host or `None` for all hosts
+    pub throttling: Vec<(Option<String>, String)>,
+    /// Error that aborted the run
+    pub error: Option<String>,
+}
+
+/// Title of an incident for the report, the start of its text without markup
+pub fn incident_title(incident_text: &str) -> String {
+    let text = crate::html_text::to_plain_text(incident_text).split_whitespace().collect::<Vec<_>>().join(" ");
+    let title: String = text.chars().take(TITLE_CHARS).collect();
+    if title.len() < text.len() { format!("{}…", title) } else { title }
+}
+
+impl RunReport {
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        let duration = (self.finished_at - self.started_at).to_std().unwrap_or_default();
+        let outcome = if self.error.is_some() || !self.process.failed.is_empty() { "failed" } else { "succeeded" };
+        let _ = writeln!(out, "# dsgvo-downloader run report\n");
+        let _ = writeln!(
+            out,
+            "Run `{}` started {} and {} after {}s.\n",
+            self.run_id,
+            self.started_at.format("%Y-%m-%d %H:%M:%S UTC"),
+            outcome,
+            duration.as_secs(),
+        );
+
+        let _ = writeln!(out, "## Summary\n");
+        if let Some(error) = &self.error {
+            let _ = writeln!(out, "- **Aborted:** {}", error);
+        }
+        match self.listed {
+            Some(listed) => {
+                let _ = writeln!(out, "- Listed incidents: {}", listed);
+            }
+            None if self.unchanged => {
+                let _ = writeln!(out, "- Incident list unchanged since the last run, diffed from the last snapshot");
+            }
+            None => {}
+        }
+        let _ = writeln!(
+            out,
+            "- New incidents: {} ({} stored, {} failed, {} skipped)",
+            self.new.len(),
+            self.process.succeeded.len(),
+            self.process.failed.len(),
+            self.process.skipped.len(),
+        );
+        let _ = writeln!(out, "- Changed incidents: {}", self.changed.len());
+        let _ = writeln!(out, "- Requests to the portal: {}", self.requests);
+        if self.process.budget_exhausted {
+            let _ = writeln!(out, "- Stopped early because the timeout budget elapsed");
+        }
+        for (host, reason) in &self.throttling {
+            let host = host.as_deref().unwrap_or("the portal");
+            let _ = writeln!(out, "- **Likely throttled by {}** ({}), consider increasing `--delay`", host, reason);
+        }
+
+        if !self.new.is_empty() {
+            let _ = writeln!(out, "\n## New incidents\n");
+            for (incident_id, title) in &self.new {
+                let _ = writeln!(out, "- {}: {}", incident_id, title);
+            }
+        }
+        if !self.changed.is_empty() {
+            let _ = writeln!(out, "\n## Changed incidents\n");
+            for changed in &self.changed {
+                let _ = writeln!(out, "- {}: modified {} → {}", changed.incident_id, changed.old_modified_date, changed.new_modified_date);
+            }
+        }
+        if !self.process.failed.is_empty() {
+            let _ = writeln!(out, "\n## Failures\n");
+            for (incident_id, err) in &self.process.failed {
+                let _ = writeln!(out, "- {}: {}", incident_id, err);
+            }
+        }
+        if !self.process.skipped.is_empty() {
+            let skipped: Vec<String> = self.process.skipped.iter().map(i32::to_string).collect();
+            let _ = writeln!(out, "\n## Skipped incidents\n\n{}", skipped.join(", "));
+        }
+        out
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_markdown()).with_context(|| format!("Failed to write run report {}", path.display()))?;
+        info!("Wrote run report {}", path.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! Automatic retries of transient failures, strictly limited to operations that are safe to repeat.
+//!
+//! Retried, because repeating them ends in the same state:
+//! - GET requests to the portal and document hosts, they don't change anything on the server
+//! - Read queries like the stored ids, modified dates and snapshot validators
+//! - `store_raw_response`, deduplicated by its idempotency key
+//! - `store_incident`, a single transaction that updates or inserts the incident and replaces its
+//!   details, so repeating it after a rollback or a lost commit acknowledgement changes nothing
+//!
+//! Not retried, because a repeat could write twice:
+//! - `store_raw_detail`, a plain insert into `detail_history`
+//! - `record_failed_incident`, which counts the attempts
+//! - `--on-stored` hooks, whose side effects are unknown
+//!
+//! A store may only be passed to [`RetryPolicy::idempotent`] once it is idempotent itself, e.g.
+//! an upsert or deduplicated by a key
+//!
+//! Database errors whose SQLSTATE is in `--db-retry-codes`, by default serialization failures and
+//! deadlocks under concurrent load, have their own budget of `--db-retries` with a short backoff,
+//! so they are retried even without `--retries`. Lost connections count as transient failures,
+//! other database errors like constraint violations fail again anyway and are never retried
+
+use anyhow::Result;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Delay before the first repeat after a retriable database error, doubled for every further one.
+/// Conflicts usually resolve as soon as the other transaction finished
+const DB_BACKOFF: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Repeats after the first attempt, 0 disables retries
+    pub retries: u32,
+    /// Delay before the first repeat, doubled for every further one
+    pub backoff: Duration,
+    /// Repeats after a database error with one of `db_codes`
+    pub db_retries: u32,
+    /// SQLSTATE codes, or two character classes of them, of retriable database errors
+    pub db_codes: Arc<[String]>,
+}
+
+impl RetryPolicy {
+    /// Delay before repeat number `retry`, counted from 0
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.backoff.saturating_mul(2u32.saturating_pow(retry))
+    }
+
+    /// Run an idempotent operation, repeating it on transient errors. Only pass operations that
+    /// are safe to repeat, see the module docs
+    pub async fn idempotent<T, F, Fut>(&self, name: &str, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 0;
+        let mut db_retry = 0;
+        loop {
+            let err = match operation().await {
+                Err(err) => err,
+                result => return result,
+            };
+            let delay = match self.classify(&err) {
+                Failure::Database(code) if db_retry < self.db_retries => {
+                    let delay = DB_BACKOFF.saturating_mul(2u32.saturating_pow(db_retry));
+                    db_retry += 1;
+                    warn!(attempt = db_retry, sqlstate = code, "{} failed, retrying in {:?}: {:#}", name, delay, err);
+                    delay
+                }
+                Failure::Transient if retry < self.retries => {
+                    let delay = self.backoff(retry);
+                    retry += 1;
+                    warn!(attempt = retry, "{} failed, retrying in {:?}: {:#}", name, delay, err);
+                    delay
+                }
+                _ => return Err(err),
+            };
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Whether an error may go away on its own. Database errors are only transient if the
+    /// connection failed or their SQLSTATE is one of `db_codes`, a constraint violation fails again anyway
+    fn classify(&self, err: &anyhow::Error) -> Failure {
+        let Some(err) = err.chain().find_map(|err| err.downcast_ref::<sqlx::Error>()) else {
+            return Failure::Transient;
+        };
+        match err {
+            sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::Tls(_) => Failure::Transient,
+            sqlx::Error::Database(err) => match err.code() {
+                Some(code) if self.db_codes.iter().any(|retriable| code.starts_with(retriable.as_str())) => Failure::Database(code.into_owned()),
+                Some(code) if code.starts_with("08") || code == "57P01" => Failure::Transient,
+                _ => Failure::Permanent,
+            },
+            _ => Failure::Permanent,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Failure {
+    /// Retried according to `retries`, e.g. a lost connection
+    Transient,
+    /// Retried according to `db_retries`, with the SQLSTATE
+    Database(String),
+    Permanent,
+}
+
+/// Parse a SQLSTATE code like `40001` or a class of them like `08`
+pub fn parse_sqlstate(value: &str) -> Result<String, String> {
+    let value = value.trim().to_ascii_uppercase();
+    if !matches!(value.len(), 2 | 5) || !value.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("'{}' is neither a SQLSTATE code like 40001 nor a class like 08", value));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Database error with a SQLSTATE, as raised by Postgres
+    #[derive(Debug)]
+    struct SqlState(&'static str);
+
+    impl std::fmt::Display for SqlState {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "simulated error with SQLSTATE {}", self.0)
+        }
+    }
+
+    impl std::error::Error for SqlState {}
+
+    impl sqlx::error::DatabaseError for SqlState {
+        fn message(&self) -> &str {
+            "simulated error"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
+    }
+
+    fn database_error(code: &'static str) -> anyhow::Error {
+        anyhow::Error::new(sqlx::Error::Database(Box::new(SqlState(code)))).context("Failed to store incident 1")
+    }
+
+    fn policy(db_codes: &[&str]) -> RetryPolicy {
+        RetryPolicy {
+            retries: 0,
+            backoff: Duration::ZERO,
+            db_retries: 3,
+            db_codes: db_codes.iter().map(|code| code.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn classifies_sqlstates() {
+        let policy = policy(&["40001", "40P01"]);
+        assert_eq!(policy.classify(&database_error("40001")), Failure::Database("40001".to_owned()));
+        assert_eq!(policy.classify(&database_error("40P01")), Failure::Database("40P01".to_owned()));
+        assert_eq!(policy.classify(&database_error("23505")), Failure::Permanent);
+        assert_eq!(policy.classify(&database_error("08006")), Failure::Transient);
+        assert_eq!(policy.classify(&database_error("57P01")), Failure::Transient);
+        assert_eq!(policy.classify(&anyhow::anyhow!("Failed to send request")), Failure::Transient);
+    }
+
+    #[test]
+    fn classifies_sqlstate_classes() {
+        assert_eq!(policy(&["40"]).classify(&database_error("40P01")), Failure::Database("40P01".to_owned()));
+        assert_eq!(policy(&[]).classify(&database_error("40001")), Failure::Permanent);
+    }
+
+    #[test]
+    fn parses_sqlstates() {
+        assert_eq!(parse_sqlstate(" 40p01 "), Ok("40P01".to_owned()));
+        assert_eq!(parse_sqlstate("08"), Ok("08".to_owned()));
+        assert!(parse_sqlstate("4000").is_err());
+        assert!(parse_sqlstate("40-01").is_err());
+    }
+
+    /// Run `idempotent` on an operation failing with `code` for `failures` attempts, returns the
+    /// result and the number of attempts
+    async fn run_failing(code: &'static str, failures: u32) -> (Result<()>, u32) {
+        let attempts = AtomicU32::new(0);
+        let result = policy(&["40001", "40P01"])
+            .idempotent("Storing incident", || async {
+                if attempts.fetch_add(1, Ordering::SeqCst) < failures {
+                    return Err(database_error(code));
+                }
+                Ok(())
+            })
+            .await;
+        (result, attempts.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn recovers_from_serialization_failure() {
+        let (result, attempts) = run_failing("40001", 2).await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_db_retries() {
+        let (result, attempts) = run_failing("40P01", u32::MAX).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 4);
+    }
+
+    /// A serialization failure raised by Postgres itself, on the database of `TEST_DATABASE_URL`
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn recovers_from_postgres_serialization_failure() {
+        let database_url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set for database tests");
+        let pool = sqlx::PgPool::connect(&database_url).await.unwrap();
+        let attempts = AtomicU32::new(0);
+        let result = policy(&["40001"])
+            .idempotent("Storing incident", || async {
+                let sql = match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => "DO $$ BEGIN RAISE EXCEPTION 'simulated' USING ERRCODE = 'serialization_failure'; END $$",
+                    _ => "SELECT 1",
+                };
+                sqlx::query(sql).execute(&pool).await?;
+                Ok(())
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_constraint_violations() {
+        let (result, attempts) = run_failing("23505", u32::MAX).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
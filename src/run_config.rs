@@ -0,0 +1,69 @@
+//! Snapshot of the effective configuration of a run recorded with `--record-config`, so the
+//! settings behind a run that behaved unexpectedly can be looked up by its run id
+
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use serde_json::{json, Map, Value};
+use tracing::debug;
+
+/// Arguments whose values are never recorded
+const SECRET_ARGS: &[&str] = &["row-hmac-key", "auth-header", "bearer-token"];
+
+/// A value with the password of a URL masked, like that of `--database-url`
+fn redact(value: &str) -> String {
+    match reqwest::Url::parse(value) {
+        Ok(url) if url.password().is_some() => crate::mask_database_url(value),
+        _ => value.to_owned(),
+    }
+}
+
+/// Resolved values of all arguments of `matches` and its subcommand, with where they came from
+fn arguments(matches: &clap::ArgMatches) -> Map<String, Value> {
+    let mut arguments = Map::new();
+    for id in matches.ids() {
+        let id = id.as_str();
+        let Some(raw) = matches.get_raw(id) else {
+            continue;
+        };
+        let values: Vec<Value> = raw
+            .map(|value| {
+                let value = value.to_string_lossy();
+                if SECRET_ARGS.contains(&id) { "***".into() } else { redact(&value).into() }
+            })
+            .collect();
+        let source = match matches.value_source(id) {
+            Some(ValueSource::DefaultValue) => "default",
+            Some(ValueSource::EnvVariable) => "env",
+            Some(ValueSource::CommandLine) => "command line",
+            _ => "unknown",
+        };
+        let value = match <[Value; 1]>::try_from(values) {
+            Ok([value]) => value,
+            Err(values) => Value::Array(values),
+        };
+        arguments.insert(id.to_owned(), json!({ "value": value, "source": source }));
+    }
+    arguments
+}
+
+/// Effective configuration of this invocation
+pub fn snapshot(matches: &clap::ArgMatches) -> Value {
+    let subcommand = matches.subcommand().map(|(name, matches)| json!({ "name": name, "arguments": arguments(matches) }));
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "arguments": arguments(matches),
+        "subcommand": subcommand,
+    })
+}
+
+/// Store the configuration of the run, once per run id as watch cycles share it
+pub async fn record(pool: &sqlx::PgPool, run_id: &str, config: &Value) -> Result<()> {
+    sqlx::query("INSERT INTO run_config (run_id, config) VALUES ($1, $2) ON CONFLICT (run_id) DO NOTHING")
+        .bind(run_id)
+        .bind(config)
+        .execute(pool)
+        .await
+        .context("Failed to record the run configuration, is the schema migrated?")?;
+    debug!("Recorded configuration of run {}", run_id);
+    Ok(())
+}
@@ -3,19 +3,129 @@ CREATE TABLE IF NOT EXISTS incidents (
      org_publish_date DATE NOT NULL,
      modified_date TIMESTAMP WITH TIME ZONE NOT NULL,
      published INTEGER NOT NULL,
-     publish_date TIMESTAMP WITH TIME ZONE NOT NULL,
-     affected_obj TEXT NOT NULL,
-     affected_type TEXT NOT NULL,
+     is_published BOOLEAN GENERATED ALWAYS AS (published = 1) STORED,
+     publish_date TIMESTAMP WITH TIME ZONE,
+     affected_obj TEXT,
+     affected_type TEXT,
      country TEXT NOT NULL,
-     details_text TEXT NOT NULL,
-     tags TEXT NOT NULL,
-     href TEXT NOT NULL,
-     "references" JSONB NOT NULL,
-     incident_text TEXT NOT NULL
+     details_text TEXT,
+     tags TEXT,
+     href TEXT,
+     "references" JSONB,
+     incident_text TEXT NOT NULL,
+     incident_text_plain TEXT,
+     details_text_plain TEXT,
+     fetched_at TIMESTAMP WITH TIME ZONE,
+     incident_text_original_bytes INTEGER,
+     details_text_original_bytes INTEGER,
+     row_hmac TEXT,
+     unpublished_at TIMESTAMP WITH TIME ZONE,
+     search_vector TSVECTOR GENERATED ALWAYS AS (to_tsvector('german', incident_text || ' ' || coalesce(details_text, ''))) STORED
 );
 
+CREATE INDEX IF NOT EXISTS incidents_search_vector_idx ON incidents USING GIN (search_vector);
+
 CREATE TABLE IF NOT EXISTS incident_history (
     id SERIAL PRIMARY KEY,
-    content JSONB NOT NULL,
+    content JSONB,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    etag TEXT,
+    last_modified TEXT,
+    raw_text TEXT,
+    is_json BOOLEAN NOT NULL DEFAULT TRUE,
+    idempotency_key TEXT,
+    content_gzip BYTEA
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS incident_history_idempotency_key_idx ON incident_history (idempotency_key);
+
+CREATE TABLE IF NOT EXISTS failed_incidents (
+    incident_id INTEGER PRIMARY KEY,
+    incident JSONB NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 1,
+    permanently_failed BOOLEAN NOT NULL DEFAULT FALSE,
+    last_attempt_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS incident_revisions (
+    id SERIAL PRIMARY KEY,
+    incident_id INTEGER NOT NULL REFERENCES incidents (incident_id),
+    snapshot JSONB NOT NULL,
+    valid_from TIMESTAMP WITH TIME ZONE NOT NULL,
+    valid_to TIMESTAMP WITH TIME ZONE NOT NULL,
     created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
-);
\ No newline at end of file
+);
+
+CREATE INDEX IF NOT EXISTS incident_revisions_incident_id_idx ON incident_revisions (incident_id);
+
+-- All details of an incident, the portal may return multiple affected objects per incident.
+-- `incidents` keeps the first detail for compatibility
+CREATE TABLE IF NOT EXISTS incident_details (
+    incident_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    publish_date TIMESTAMP WITH TIME ZONE NOT NULL,
+    affected_obj TEXT NOT NULL,
+    affected_type TEXT NOT NULL,
+    details_text TEXT NOT NULL,
+    tags TEXT NOT NULL,
+    href TEXT NOT NULL,
+    "references" JSONB NOT NULL,
+    PRIMARY KEY (incident_id, position)
+);
+
+-- Latest state of every incident across all raw snapshots, rebuilt by `flatten-history`
+CREATE TABLE IF NOT EXISTS incident_history_latest (
+    incident_id INTEGER PRIMARY KEY,
+    modified_date TIMESTAMP WITH TIME ZONE NOT NULL,
+    incident JSONB NOT NULL,
+    snapshot_id INTEGER NOT NULL,
+    snapshot_created_at TIMESTAMP WITH TIME ZONE
+);
+
+-- Documents linked in the references of incidents, downloaded with `--fetch-attachments`
+CREATE TABLE IF NOT EXISTS incident_attachments (
+    incident_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    sha256 TEXT NOT NULL,
+    content BYTEA,
+    path TEXT,
+    fetched_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (incident_id, url)
+);
+
+-- Raw incident detail responses, stored with `--store-raw-details`
+CREATE TABLE IF NOT EXISTS detail_history (
+    id SERIAL PRIMARY KEY,
+    incident_id INTEGER NOT NULL,
+    content JSONB,
+    raw_text TEXT,
+    is_json BOOLEAN NOT NULL DEFAULT TRUE,
+    fetched_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    etag TEXT,
+    last_modified TEXT
+);
+
+CREATE INDEX IF NOT EXISTS detail_history_incident_id_idx ON detail_history (incident_id, fetched_at);
+
+CREATE TABLE IF NOT EXISTS run_config (
+    run_id TEXT PRIMARY KEY,
+    recorded_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    config JSONB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS export_watermarks (
+    name TEXT PRIMARY KEY,
+    watermark TIMESTAMP WITH TIME ZONE NOT NULL,
+    exported_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Keep in sync with the migrations in `src/migrations`, a fresh database starts at the latest version
+CREATE TABLE IF NOT EXISTS schema_version (
+    version INTEGER PRIMARY KEY,
+    applied_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO schema_version (version) VALUES (22) ON CONFLICT DO NOTHING;
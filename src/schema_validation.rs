@@ -0,0 +1,154 @@
+//! Minimal JSON Schema validation of portal responses, supporting the subset of keywords used by the embedded
+//! schemas: `type`, `required`, `properties`, `additionalProperties` (boolean only) and `items`
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+pub const INCIDENTS_SCHEMA: &str = include_str!("schemas/incidents.schema.json");
+pub const INCIDENT_DETAIL_SCHEMA: &str = include_str!("schemas/incident_detail.schema.json");
+
+/// Validate `instance` against `schema`, returning a description of every violation
+pub fn validate(schema: &str, instance: &Value) -> Result<Vec<String>> {
+    let schema: Value = serde_json::from_str(schema).context("Failed to parse embedded JSON schema")?;
+    let mut violations = Vec::new();
+    validate_value(&schema, instance, "$", &mut violations);
+    Ok(violations)
+}
+
+fn validate_value(schema: &Value, instance: &Value, path: &str, violations: &mut Vec<String>) {
+    if let Some(expected) = schema.get("type") {
+        let matches = match expected {
+            Value::String(expected) => matches_type(expected, instance),
+            Value::Array(expected) => expected.iter().filter_map(Value::as_str).any(|expected| matches_type(expected, instance)),
+            _ => true,
+        };
+        if !matches {
+            violations.push(format!("{}: expected type {}, got {}", path, expected, type_name(instance)));
+            return;
+        }
+    }
+
+    if let Value::Object(object) = instance {
+        for field in schema.get("required").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str) {
+            if !object.contains_key(field) {
+                violations.push(format!("{}: missing required field '{}'", path, field));
+            }
+        }
+
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (field, value) in object {
+            match properties.and_then(|properties| properties.get(field)) {
+                Some(property) => validate_value(property, value, &format!("{}.{}", path, field), violations),
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    violations.push(format!("{}: unexpected field '{}'", path, field));
+                }
+                None => {}
+            }
+        }
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (instance, schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            validate_value(item_schema, item, &format!("{}[{}]", path, index), violations);
+        }
+    }
+}
+
+fn matches_type(expected: &str, instance: &Value) -> bool {
+    match expected {
+        "null" => instance.is_null(),
+        "boolean" => instance.is_boolean(),
+        "object" => instance.is_object(),
+        "array" => instance.is_array(),
+        "number" => instance.is_number(),
+        "integer" => instance.is_i64() || instance.is_u64(),
+        "string" => instance.is_string(),
+        _ => true,
+    }
+}
+
+fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUPPORTED_KEYWORDS: &[&str] = &["$schema", "title", "description", "type", "required", "properties", "additionalProperties", "items"];
+
+    const DETAILS: &[(&str, &str)] = &[
+        ("101", include_str!("../fixtures/portal/details/101.json")),
+        ("102", include_str!("../fixtures/portal/details/102.json")),
+        ("103", include_str!("../fixtures/portal/details/103.json")),
+        ("104", include_str!("../fixtures/portal/details/104.json")),
+        ("105", include_str!("../fixtures/portal/details/105.json")),
+    ];
+
+    /// Collect every keyword of `schema` the validator would silently ignore
+    fn unsupported_keywords(schema: &Value, path: &str, unsupported: &mut Vec<String>) {
+        let Some(object) = schema.as_object() else {
+            unsupported.push(format!("{}: schema is not an object", path));
+            return;
+        };
+        for (keyword, value) in object {
+            if !SUPPORTED_KEYWORDS.contains(&keyword.as_str()) {
+                unsupported.push(format!("{}.{}", path, keyword));
+            }
+            match keyword.as_str() {
+                "properties" => {
+                    for (field, property) in value.as_object().into_iter().flatten() {
+                        unsupported_keywords(property, &format!("{}.properties.{}", path, field), unsupported);
+                    }
+                }
+                "items" => unsupported_keywords(value, &format!("{}.items", path), unsupported),
+                "additionalProperties" if !value.is_boolean() => unsupported.push(format!("{}.additionalProperties", path)),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn embedded_schemas_only_use_supported_keywords() {
+        for schema in [INCIDENTS_SCHEMA, INCIDENT_DETAIL_SCHEMA] {
+            let schema: Value = serde_json::from_str(schema).unwrap();
+            let mut unsupported = Vec::new();
+            unsupported_keywords(&schema, "$", &mut unsupported);
+            assert!(unsupported.is_empty(), "unsupported schema keywords: {:?}", unsupported);
+        }
+    }
+
+    #[test]
+    fn fixtures_match_the_embedded_schemas() {
+        let incidents: Value = serde_json::from_str(include_str!("../fixtures/portal/incidents.json")).unwrap();
+        assert_eq!(validate(INCIDENTS_SCHEMA, &incidents).unwrap(), Vec::<String>::new());
+
+        for (id, detail) in DETAILS {
+            let detail: Value = serde_json::from_str(detail).unwrap();
+            assert_eq!(validate(INCIDENT_DETAIL_SCHEMA, &detail).unwrap(), Vec::<String>::new(), "details of incident {}", id);
+        }
+    }
+
+    #[test]
+    fn reports_violations() {
+        let mut incidents: Value = serde_json::from_str(include_str!("../fixtures/portal/incidents.json")).unwrap();
+        let incident = incidents[0].as_object_mut().unwrap();
+        incident.remove("modifiedDate");
+        incident.insert("incidentID".to_string(), Value::from("101"));
+
+        let violations = validate(INCIDENTS_SCHEMA, &incidents).unwrap();
+        assert!(violations.contains(&"$[0]: missing required field 'modifiedDate'".to_string()), "{:?}", violations);
+        assert!(violations.iter().any(|violation| violation.starts_with("$[0].incidentID: expected type")), "{:?}", violations);
+
+        let violations = validate(INCIDENT_DETAIL_SCHEMA, &Value::from("not an object")).unwrap();
+        assert_eq!(violations.len(), 1, "{:?}", violations);
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{Context, Result};
+use tracing::{debug, info};
+
+/// Text search configuration the stored `search_vector` column is built with
+pub const DEFAULT_LANGUAGE: &str = "german";
+
+/// Full-text search over the incident texts, printing matching incident ids with a snippet
+pub async fn search_incidents(pool: &sqlx::PgPool, query: &str, language: &str, limit: i64) -> Result<()> {
+    debug!("Searching incidents for '{}' using text search configuration {}", query, language);
+
+    // The stored column only matches the default language, other languages are computed on the fly without index
+    let document = if language == DEFAULT_LANGUAGE {
+        "search_vector"
+    } else {
+        "to_tsvector($1::regconfig, incident_text || ' ' || coalesce(details_text, ''))"
+    };
+    let sql = format!(
+        r#"SELECT incident_id,
+                  ts_headline($1::regconfig, incident_text || ' ' || coalesce(details_text, ''), query, 'MaxWords=25, MinWords=10, StartSel=*, StopSel=*')
+           FROM incidents, plainto_tsquery($1::regconfig, $2) query
+           WHERE {document} @@ query
+           ORDER BY ts_rank({document}, query) DESC, incident_id
+           LIMIT $3"#
+    );
+
+    let results: Vec<(i32, String)> = sqlx::query_as(&sql)
+        .bind(language)
+        .bind(query)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+        .context("Failed to search incidents")?;
+
+    for (incident_id, snippet) in &results {
+        println!("{}: {}", incident_id, snippet.split_whitespace().collect::<Vec<_>>().join(" "));
+    }
+    info!("Found {} matching incidents", results.len());
+    Ok(())
+}
+
+/// Length of the text snippet printed by `list`
+const LIST_SNIPPET_CHARS: usize = 80;
+
+/// Bounds of `list`, all optional. Dates are inclusive and compared to the publish date, or the
+/// original publish date for incidents stored without details
+pub struct ListFilter {
+    pub from: Option<chrono::NaiveDate>,
+    pub to: Option<chrono::NaiveDate>,
+    pub country: Option<String>,
+    pub tag: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+struct ListedIncident {
+    incident_id: i32,
+    country: String,
+    publish_date: Option<chrono::DateTime<chrono::Utc>>,
+    tags: Option<String>,
+    text: String,
+}
+
+/// Print the stored incidents matching `filter` ordered by publish date, as a table or one JSON object per line
+pub async fn list_incidents(pool: &sqlx::PgPool, filter: &ListFilter, json: bool) -> Result<()> {
+    debug!(from = ?filter.from, to = ?filter.to, country = ?filter.country, tag = ?filter.tag, "Listing incidents");
+
+    let incidents: Vec<ListedIncident> = sqlx::query_as(
+        r#"SELECT incident_id, country, publish_date, tags, coalesce(incident_text_plain, incident_text) AS text
+           FROM incidents
+           WHERE ($1::date IS NULL OR coalesce(publish_date::date, org_publish_date) >= $1)
+             AND ($2::date IS NULL OR coalesce(publish_date::date, org_publish_date) <= $2)
+             AND ($3::text IS NULL OR country = $3)
+             AND ($4::text IS NULL OR EXISTS (SELECT 1 FROM unnest(string_to_array(tags, ',')) tag WHERE lower(trim(tag)) = lower($4)))
+           ORDER BY coalesce(publish_date::date, org_publish_date), incident_id
+           LIMIT $5"#,
+    )
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.country.as_deref())
+        .bind(filter.tag.as_deref())
+        .bind(filter.limit)
+        .fetch_all(pool)
+        .await
+        .context("Failed to list incidents")?;
+
+    for incident in &incidents {
+        if json {
+            println!("{}", serde_json::to_string(incident).context("Failed to serialize incident")?);
+        } else {
+            let text = incident.text.split_whitespace().collect::<Vec<_>>().join(" ");
+            let snippet: String = text.chars().take(LIST_SNIPPET_CHARS).collect();
+            let ellipsis = if snippet.len() < text.len() { "…" } else { "" };
+            let publish_date = incident.publish_date.map(|date| date.format("%Y-%m-%d").to_string()).unwrap_or_else(|| "-".to_owned());
+            println!("{}\t{}\t{}\t{}{}", incident.incident_id, incident.country, publish_date, snippet, ellipsis);
+        }
+    }
+    info!("Listed {} incidents", incidents.len());
+    Ok(())
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+struct CountryCount {
+    country: String,
+    incidents: i64,
+}
+
+/// Print the distinct countries of the stored incidents with their number of incidents, most
+/// frequent first, as the values `list --country` accepts
+pub async fn list_countries(pool: &sqlx::PgPool, json: bool) -> Result<()> {
+    let countries: Vec<CountryCount> = sqlx::query_as(
+        "SELECT country, count(*) AS incidents FROM incidents GROUP BY country ORDER BY incidents DESC, country",
+    )
+        .fetch_all(pool)
+        .await
+        .context("Failed to list countries")?;
+
+    for country in &countries {
+        if json {
+            println!("{}", serde_json::to_string(country).context("Failed to serialize country")?);
+        } else {
+            println!("{}\t{}", country.country, country.incidents);
+        }
+    }
+    info!("Listed {} countries", countries.len());
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! `self-test` subcommand storing a synthetic incident through the regular store path and reading
+//! it back, to check the schema, the serialization and the database connection without the portal
+
+use crate::model::{Incident, IncidentDetail};
+use anyhow::{Context, Result};
+use chrono::{NaiveDate, NaiveDateTime};
+use serde_json::{json, Value};
+use tracing::{info, trace};
+
+/// Reserved id of the synthetic incident, the portal only uses positive ids
+const SELF_TEST_INCIDENT_ID: i32 = -1;
+
+/// Synthetic incident with values that tend to break serialization: a leap day, seconds in the
+/// modified date, HTML, quotes, umlauts and emoji in the texts and nested references
+fn synthetic_incident() -> Result<(Incident, IncidentDetail)> {
+    let incident = Incident {
+        incident_id: SELF_TEST_INCIDENT_ID,
+        org_publish_date: NaiveDate::from_ymd_opt(2024, 2, 29).context("Invalid self-test date")?,
+        modified_date: NaiveDateTime::parse_from_str("2024-03-01 23:59:58", "%Y-%m-%d %H:%M:%S").context("Invalid self-test date")?,
+        published: 1,
+        country: "DE".to_owned(),
+        incident_text: "<p>Selbsttest: Datenpanne bei \"Müller & Söhne\" – Größe 🙂</p>".to_owned(),
+    };
+    let detail = IncidentDetail {
+        publish_date: NaiveDate::from_ymd_opt(2024, 3, 1).context("Invalid self-test date")?,
+        affected_obj: "Kund*innen, Beschäftigte".to_owned(),
+        affected_type: "Unternehmen".to_owned(),
+        details_text: "<p>Zeile 1<br>Zeile 2 mit 'Apostroph' und \\Backslash</p>".to_owned(),
+        tags: "Selbsttest,Ümlaut".to_owned(),
+        href: "https://example.org/selbsttest?a=1&b=2".to_owned(),
+        reference: json!(["https://example.org/bescheid.pdf", {"title": "Bescheid", "pages": [1, 2]}]).to_string(),
+    };
+    Ok((incident, detail))
+}
+
+/// Compare a stored value with the expected one, printing the result
+fn check(results: &mut Vec<bool>, field: &str, expected: Value, actual: Value) {
+    let passed = expected == actual;
+    if passed {
+        println!("PASS {}", field);
+    } else {
+        println!("FAIL {}: expected {}, got {}", field, expected, actual);
+    }
+    results.push(passed);
+}
+
+/// Store the synthetic incident, verify every field and remove it again. Everything happens in a
+/// transaction that is rolled back, so no trace is left even if the test is interrupted
+pub async fn self_test(pool: &sqlx::PgPool) -> Result<()> {
+    let (incident, detail) = synthetic_incident()?;
+    let expected_references = detail.references().context("Failed to parse self-test references")?;
+    let mut tx = pool.begin().await.context("Failed to start transaction")?;
+
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM incidents WHERE incident_id = $1)")
+        .bind(SELF_TEST_INCIDENT_ID)
+        .fetch_one(&mut *tx)
+        .await
+        .context("Failed to check for the self-test incident")?;
+    if exists {
+        anyhow::bail!("Incident {} is reserved for the self-test but already stored", SELF_TEST_INCIDENT_ID);
+    }
+
+    trace!("Storing self-test incident");
+    crate::store_incident(&mut *tx, &incident, std::slice::from_ref(&detail), &crate::sink::StoreOptions::default()).await.context("Failed to store the self-test incident")?;
+
+    let stored: Option<Value> = sqlx::query_scalar(
+        r#"SELECT jsonb_build_object(
+            'org_publish_date', to_char(org_publish_date, 'YYYY-MM-DD'),
+            'modified_date', to_char(modified_date, 'YYYY-MM-DD HH24:MI:SS'),
+            'published', published,
+            'country', country,
+            'incident_text', incident_text,
+            'publish_date', to_char(publish_date, 'YYYY-MM-DD'),
+            'affected_obj', affected_obj,
+            'affected_type', affected_type,
+            'details_text', details_text,
+            'tags', tags,
+            'href', href,
+            'references', "references",
+            'search_vector', search_vector @@ plainto_tsquery('german', 'Selbsttest')
+        ) FROM incidents WHERE incident_id = $1"#,
+    )
+        .bind(SELF_TEST_INCIDENT_ID)
+        .fetch_optional(&mut *tx)
+        .await
+        .context("Failed to read back the self-test incident")?;
+    let stored = stored.context("The self-test incident wasn't stored")?;
+    let stored_detail: Option<Value> = sqlx::query_scalar(
+        r#"SELECT jsonb_build_object(
+            'publish_date', to_char(publish_date, 'YYYY-MM-DD'),
+            'affected_obj', affected_obj,
+            'affected_type', affected_type,
+            'details_text', details_text,
+            'tags', tags,
+            'href', href,
+            'references', "references"
+        ) FROM incident_details WHERE incident_id = $1 AND position = 0"#,
+    )
+        .bind(SELF_TEST_INCIDENT_ID)
+        .fetch_optional(&mut *tx)
+        .await
+        .context("Failed to read back the self-test details")?;
+    let stored_detail = stored_detail.context("The details of the self-test incident weren't stored")?;
+
+    let mut results = Vec::new();
+    let field = |row: &Value, name: &str| row.get(name).cloned().unwrap_or(Value::Null);
+    let expected = [
+        ("org_publish_date", json!(incident.org_publish_date.to_string())),
+        ("modified_date", json!(incident.modified_date.format("%Y-%m-%d %H:%M:%S").to_string())),
+        ("published", json!(incident.published)),
+        ("country", json!(incident.country)),
+        ("incident_text", json!(incident.incident_text)),
+        ("publish_date", json!(detail.publish_date.to_string())),
+        ("affected_obj", json!(detail.affected_obj)),
+        ("affected_type", json!(detail.affected_type)),
+        ("details_text", json!(detail.details_text)),
+        ("tags", json!(detail.tags)),
+        ("href", json!(detail.href)),
+        ("references", expected_references),
+    ];
+    for (name, value) in &expected {
+        check(&mut results, &format!("incidents.{}", name), value.clone(), field(&stored, name));
+    }
+    check(&mut results, "incidents.search_vector", json!(true), field(&stored, "search_vector"));
+    for (name, value) in expected.iter().filter(|(name, _)| stored_detail.get(*name).is_some()) {
+        check(&mut results, &format!("incident_details.{}", name), value.clone(), field(&stored_detail, name));
+    }
+
+    tx.rollback().await.context("Failed to remove the self-test incident")?;
+    trace!("Removed self-test incident");
+
+    let failed = results.iter().filter(|passed| !**passed).count();
+    println!("Self-test: {} of {} checks passed", results.len() - failed, results.len());
+    info!(passed = results.len() - failed, failed, "Self-test finished");
+    if failed > 0 {
+        anyhow::bail!("Self-test failed, {} fields didn't round-trip", failed);
+    }
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! Synthetic failures for resilience testing, only compiled with the `simulate-errors` feature so
+//! they can never trigger in a normal build
+
+use crate::db::Database;
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// Fails a share of the operations it is asked about, reproducible with a seed since incidents
+/// are processed in order
+pub struct ErrorSimulation {
+    rate: f64,
+    rng: Mutex<StdRng>,
+}
+
+impl ErrorSimulation {
+    /// `operations` names what fails for the log, e.g. "detail requests"
+    pub fn new(rate: f64, seed: Option<u64>, operations: &str) -> Self {
+        let seed = seed.unwrap_or_else(rand::random);
+        warn!("Simulating errors in {:.0}% of {} using seed {}", rate * 100.0, operations, seed);
+        Self { rate, rng: Mutex::new(StdRng::seed_from_u64(seed)) }
+    }
+
+    fn should_fail(&self) -> bool {
+        self.rng.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).random_bool(self.rate)
+    }
+
+    /// Fail with the configured probability
+    pub fn maybe_fail(&self, what: &str) -> Result<()> {
+        if self.should_fail() {
+            info!("Simulating failure of {}", what);
+            anyhow::bail!("Simulated failure of {}", what);
+        }
+        Ok(())
+    }
+
+    /// Fail with the configured probability with a serialization failure raised by the database, so
+    /// the error is classified like one caused by a concurrent transaction
+    pub async fn maybe_fail_serialization<D: Database>(&self, db: &D, what: &str) -> Result<()> {
+        if self.should_fail() {
+            info!("Simulating serialization failure of {}", what);
+            db.fail_serialization(what).await?;
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    let rate: f64 = value.parse().map_err(|_| format!("invalid rate '{}'", value))?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(format!("rate {} is not between 0 and 1", rate));
+    }
+    Ok(rate)
+}
@@ -0,0 +1,289 @@
+//! Destinations the pipeline writes incidents to. The database is always attached, further sinks
+//! receive the same incidents, e.g. to feed a stream or a search index, without touching the pipeline
+
+use crate::db::Database;
+use crate::integrity::RowHmac;
+use crate::model::{Incident, IncidentDetail};
+use crate::retry::RetryPolicy;
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use std::collections::HashSet;
+use std::io::Write;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// Raw incident list as received from the portal
+pub struct RawResponse<'a> {
+    pub content: &'a str,
+    pub etag: Option<&'a str>,
+    pub last_modified: Option<&'a str>,
+    /// Unique per fetch, so a retried store doesn't store the response twice
+    pub idempotency_key: &'a str,
+}
+
+pub trait Sink: Send + Sync {
+    /// Name used in logs and errors
+    fn name(&self) -> &'static str;
+
+    /// Ids of the incidents the sink already has, `None` if it doesn't keep track of them
+    fn existing_ids(&self) -> BoxFuture<'_, Result<Option<HashSet<i32>>>>;
+
+    fn store_raw_response<'a>(&'a self, response: &'a RawResponse<'a>) -> BoxFuture<'a, Result<()>>;
+
+    fn store_incident<'a>(&'a self, incident: &'a Incident, details: &'a [IncidentDetail]) -> BoxFuture<'a, Result<()>>;
+
+    /// Undo storing `incident`, the last stored one, as a later step failed for it, e.g. another sink or a
+    /// `--strict-hook`. Only possible until the next flush, sinks writing through can't undo anything
+    fn discard<'a>(&'a self, _incident: &'a Incident) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Make the incidents stored since the last flush durable, called every `--commit-every`
+    /// incidents and when processing ends
+    fn flush(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Make everything still buffered durable before the process exits, called once after the
+    /// last flush
+    fn close(&self) -> BoxFuture<'_, Result<()>> {
+        self.flush()
+    }
+}
+
+/// The sinks of a run, written in the order they were added
+#[derive(Default)]
+pub struct Sinks {
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl Sinks {
+    pub fn add(&mut self, sink: Box<dyn Sink>) {
+        debug!("Writing incidents to the {} sink", sink.name());
+        self.sinks.push(sink);
+    }
+
+    /// Incidents that all sinks keeping track of their incidents have, so a newly attached sink
+    /// gets the incidents it misses
+    pub async fn existing_ids(&self) -> Result<HashSet<i32>> {
+        let mut existing: Option<HashSet<i32>> = None;
+        for sink in &self.sinks {
+            let Some(ids) = sink.existing_ids().await.with_context(|| format!("Failed to get existing incidents of the {} sink", sink.name()))? else {
+                continue;
+            };
+            existing = Some(match existing {
+                Some(existing) => existing.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+        Ok(existing.unwrap_or_default())
+    }
+
+    pub async fn store_raw_response(&self, response: &RawResponse<'_>) -> Result<()> {
+        for sink in &self.sinks {
+            sink.store_raw_response(response).await.with_context(|| format!("Failed to store raw response in the {} sink", sink.name()))?;
+        }
+        Ok(())
+    }
+
+    /// Store an incident in every sink, stopping at the first failure. The sinks that stored it before
+    /// discard it then
+    pub async fn store_incident(&self, incident: &Incident, details: &[IncidentDetail]) -> Result<()> {
+        for (position, sink) in self.sinks.iter().enumerate() {
+            let result = sink.store_incident(incident, details)
+                .await
+                .with_context(|| format!("Failed to store incident {} in the {} sink", incident.incident_id, sink.name()));
+            if let Err(err) = result {
+                Self::discard_from(&self.sinks[..position], incident).await?;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo storing an incident in every sink, see [`Sink::discard`]
+    pub async fn discard(&self, incident: &Incident) -> Result<()> {
+        Self::discard_from(&self.sinks, incident).await
+    }
+
+    async fn discard_from(sinks: &[Box<dyn Sink>], incident: &Incident) -> Result<()> {
+        for sink in sinks {
+            sink.discard(incident)
+                .await
+                .with_context(|| format!("Failed to discard incident {} in the {} sink", incident.incident_id, sink.name()))?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        for sink in &self.sinks {
+            sink.flush().await.with_context(|| format!("Failed to flush the {} sink", sink.name()))?;
+        }
+        Ok(())
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        for sink in &self.sinks {
+            sink.close().await.with_context(|| format!("Failed to close the {} sink", sink.name()))?;
+        }
+        Ok(())
+    }
+}
+
+/// How incidents are written to the `incidents` table
+#[derive(Default)]
+pub struct StoreOptions {
+    /// Store plain text versions of the HTML texts
+    pub strip_html: bool,
+    /// Truncate the texts stored in `incidents` to this many bytes
+    pub max_text_bytes: Option<usize>,
+    /// Sign stored incident rows
+    pub row_hmac: Option<RowHmac>,
+    /// Record when the published flag of an incident goes from 1 to something else in `unpublished_at`
+    pub mark_unpublished: bool,
+}
+
+/// The incident tables of the database, Postgres unless a test passes a fake
+pub struct DatabaseSink<D: Database = sqlx::PgPool> {
+    pub db: D,
+    pub retry: RetryPolicy,
+    /// Store incidents in a shared transaction committed by [`Sink::flush`] instead of each one separately
+    pub batch: bool,
+    pub store: StoreOptions,
+    /// Store raw responses gzip-compressed instead of as jsonb
+    pub compress_history: bool,
+    /// Fail a share of the incident stores, see `--simulate-db-errors`
+    #[cfg(feature = "simulate-errors")]
+    simulate_errors: Option<crate::simulate::ErrorSimulation>,
+    transaction: Mutex<Option<Batch<D::Batch>>>,
+}
+
+/// Incidents stored by a [`DatabaseSink`] in batch mode since the last flush
+struct Batch<B> {
+    batch: B,
+    /// Ids of the incidents in the batch, their failures are cleared once it is committed
+    incident_ids: Vec<i32>,
+    /// Whether the last incident can still be discarded
+    discardable: bool,
+}
+
+impl<D: Database> DatabaseSink<D> {
+    pub fn new(db: D, retry: RetryPolicy, batch: bool, store: StoreOptions, compress_history: bool) -> Self {
+        Self {
+            db,
+            retry,
+            batch,
+            store,
+            compress_history,
+            #[cfg(feature = "simulate-errors")]
+            simulate_errors: None,
+            transaction: Mutex::new(None),
+        }
+    }
+
+    /// Fail a share of the incident stores with a serialization failure, see `--simulate-db-errors`
+    #[cfg(feature = "simulate-errors")]
+    pub fn simulate_errors(self, simulate_errors: Option<crate::simulate::ErrorSimulation>) -> Self {
+        Self { simulate_errors, ..self }
+    }
+}
+
+impl<D: Database> Sink for DatabaseSink<D> {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    fn existing_ids(&self) -> BoxFuture<'_, Result<Option<HashSet<i32>>>> {
+        Box::pin(async move {
+            let ids = self.retry.idempotent("Getting existing incidents", || self.db.existing_incident_ids()).await?;
+            Ok(Some(ids))
+        })
+    }
+
+    fn store_raw_response<'a>(&'a self, response: &'a RawResponse<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.retry.idempotent("Storing raw response", move || self.db.store_raw_response(response, self.compress_history)))
+    }
+
+    fn store_incident<'a>(&'a self, incident: &'a Incident, details: &'a [IncidentDetail]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            if !self.batch {
+                let store = || async {
+                    #[cfg(feature = "simulate-errors")]
+                    if let Some(simulation) = &self.simulate_errors {
+                        simulation.maybe_fail_serialization(&self.db, &format!("storing incident {}", incident.incident_id)).await?;
+                    }
+                    self.db.store_incident(incident, details, &self.store).await
+                };
+                self.retry.idempotent("Storing incident", store).await?;
+                return self.db.clear_failed_incidents(&[incident.incident_id]).await;
+            }
+            // Not retried, but a failure doesn't abort the incidents stored before. Failures are cleared
+            // after the commit, clearing them in the transaction would lock their rows against recording
+            // a failure until the next flush
+            let mut batch = self.transaction.lock().await;
+            let batch = match batch.as_mut() {
+                Some(batch) => batch,
+                None => batch.insert(Batch { batch: self.db.begin_batch().await?, incident_ids: Vec::new(), discardable: false }),
+            };
+            batch.discardable = false;
+            self.db.store_incident_in_batch(&mut batch.batch, incident, details, &self.store).await?;
+            batch.discardable = true;
+            batch.incident_ids.push(incident.incident_id);
+            Ok(())
+        })
+    }
+
+    fn discard<'a>(&'a self, incident: &'a Incident) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut batch = self.transaction.lock().await;
+            let Some(batch) = batch.as_mut().filter(|batch| batch.discardable && batch.incident_ids.last() == Some(&incident.incident_id)) else {
+                return Ok(());
+            };
+            self.db.discard_last(&mut batch.batch).await?;
+            batch.discardable = false;
+            batch.incident_ids.pop();
+            debug!(incident_id = incident.incident_id, "Discarded incident from the uncommitted batch");
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            if let Some(batch) = self.transaction.lock().await.take() {
+                self.db.commit_batch(batch.batch).await?;
+                debug!("Committed stored incidents");
+                self.db.clear_failed_incidents(&batch.incident_ids).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Every stored incident as a line of `{"incident": ..., "details": [...]}` on stdout, e.g. to
+/// pipe into a message queue. The raw incident list isn't written
+pub struct JsonLinesSink;
+
+impl Sink for JsonLinesSink {
+    fn name(&self) -> &'static str {
+        "jsonl"
+    }
+
+    fn existing_ids(&self) -> BoxFuture<'_, Result<Option<HashSet<i32>>>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn store_raw_response<'a>(&'a self, _response: &'a RawResponse<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn store_incident<'a>(&'a self, incident: &'a Incident, details: &'a [IncidentDetail]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let line = serde_json::to_string(&serde_json::json!({ "incident": incident, "details": details })).context("Failed to serialize incident")?;
+            writeln!(std::io::stdout().lock(), "{}", line).context("Failed to write incident to stdout")
+        })
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { std::io::stdout().flush().context("Failed to flush stdout") })
+    }
+}
@@ -0,0 +1,54 @@
+//! Optional push of run metrics to a statsd or dogstatsd endpoint over UDP, for short-lived cron
+//! jobs that can't be scraped. Without `--statsd-addr` every metric is a no-op
+
+use anyhow::{Context, Result};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use tracing::{debug, trace};
+
+pub struct Statsd {
+    socket: Option<UdpSocket>,
+    /// Prepended to every metric name, separated by a dot
+    prefix: String,
+}
+
+impl Statsd {
+    pub fn disabled() -> Self {
+        Self { socket: None, prefix: String::new() }
+    }
+
+    /// Send metrics to `addr` like `localhost:8125`
+    pub fn connect(addr: &str, prefix: &str) -> Result<Self> {
+        let target = addr
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve statsd address {}", addr))?
+            .next()
+            .with_context(|| format!("Statsd address {} doesn't resolve to any address", addr))?;
+        let local: std::net::SocketAddr = if target.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(local).context("Failed to bind statsd socket")?;
+        socket.connect(target).with_context(|| format!("Failed to connect statsd socket to {}", target))?;
+        // Metrics are best-effort and must never stall a run
+        socket.set_nonblocking(true).context("Failed to make statsd socket non-blocking")?;
+        debug!("Sending metrics to statsd at {}", target);
+        Ok(Self { socket: Some(socket), prefix: prefix.to_owned() })
+    }
+
+    pub fn count(&self, name: &str, value: u64) {
+        self.send(name, &format!("{}|c", value));
+    }
+
+    pub fn timing(&self, name: &str, duration: Duration) {
+        self.send(name, &format!("{}|ms", duration.as_millis()));
+    }
+
+    fn send(&self, name: &str, value: &str) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        let metric = if self.prefix.is_empty() { format!("{}:{}", name, value) } else { format!("{}.{}:{}", self.prefix, name, value) };
+        trace!("Sending metric {}", metric);
+        if let Err(err) = socket.send(metric.as_bytes()) {
+            debug!("Failed to send metric {}: {}", metric, err);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Optional OpenTelemetry tracing, exporting spans via OTLP/HTTP with JSON encoding.
+//! Without the `otlp` feature or a configured endpoint every span is a no-op.
+
+use anyhow::Result;
+use std::future::Future;
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use anyhow::{Context, Result};
+    use tracing::{debug, trace};
+    use std::sync::{Mutex, OnceLock};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    pub(super) static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+    tokio::task_local! {
+        /// Trace and span id of the span the current task is in
+        pub(super) static CURRENT_SPAN: (String, String);
+    }
+
+    pub(super) struct FinishedSpan {
+        pub trace_id: String,
+        pub span_id: String,
+        pub parent_span_id: Option<String>,
+        pub name: &'static str,
+        pub start: SystemTime,
+        pub end: SystemTime,
+        pub attributes: Vec<(&'static str, String)>,
+        pub error: Option<String>,
+    }
+
+    pub(super) struct Tracer {
+        pub endpoint: String,
+        pub client: reqwest::Client,
+        pub spans: Mutex<Vec<FinishedSpan>>,
+    }
+
+    pub(super) fn random_id(bytes: usize) -> String {
+        (0..bytes).map(|_| format!("{:02x}", rand::random::<u8>())).collect()
+    }
+
+    fn unix_nanos(time: SystemTime) -> String {
+        time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+    }
+
+    fn span_json(span: &FinishedSpan) -> serde_json::Value {
+        let attributes: Vec<_> = span.attributes
+            .iter()
+            .map(|(key, value)| serde_json::json!({ "key": key, "value": { "stringValue": value } }))
+            .collect();
+        let status = match &span.error {
+            Some(message) => serde_json::json!({ "code": 2, "message": message }),
+            None => serde_json::json!({ "code": 1 }),
+        };
+        serde_json::json!({
+            "traceId": span.trace_id,
+            "spanId": span.span_id,
+            "parentSpanId": span.parent_span_id.clone().unwrap_or_default(),
+            "name": span.name,
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(span.start),
+            "endTimeUnixNano": unix_nanos(span.end),
+            "attributes": attributes,
+            "status": status,
+        })
+    }
+
+    impl Tracer {
+        pub async fn flush(&self) -> Result<()> {
+            let spans = std::mem::take(&mut *self.spans.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+            if spans.is_empty() {
+                return Ok(());
+            }
+
+            debug!("Exporting {} spans to {}", spans.len(), self.endpoint);
+            let body = serde_json::json!({
+                "resourceSpans": [{
+                    "resource": {
+                        "attributes": [{ "key": "service.name", "value": { "stringValue": env!("CARGO_PKG_NAME") } }]
+                    },
+                    "scopeSpans": [{
+                        "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                        "spans": spans.iter().map(span_json).collect::<Vec<_>>(),
+                    }]
+                }]
+            });
+
+            let response = self.client
+                .post(format!("{}/v1/traces", self.endpoint))
+                .json(&body)
+                .send()
+                .await
+                .context("Failed to export spans")?;
+            trace!("OTLP response status: {}", response.status());
+            if !response.status().is_success() {
+                anyhow::bail!("Unexpected status code exporting spans: {}", response.status());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Enable exporting spans to the given OTLP/HTTP endpoint, e.g. `http://localhost:4318`
+#[cfg(feature = "otlp")]
+pub fn init(endpoint: &str) {
+    let tracer = otlp::Tracer {
+        endpoint: endpoint.trim_end_matches('/').to_owned(),
+        client: reqwest::Client::new(),
+        spans: Default::default(),
+    };
+    if otlp::TRACER.set(tracer).is_err() {
+        tracing::warn!("OTLP tracing was already initialized");
+    }
+}
+
+/// Run `future` inside a span, nested in the span of the calling task if there is one
+pub async fn in_span<T>(name: &'static str, attributes: Vec<(&'static str, String)>, future: impl Future<Output = Result<T>>) -> Result<T> {
+    #[cfg(feature = "otlp")]
+    if let Some(tracer) = otlp::TRACER.get() {
+        let parent = otlp::CURRENT_SPAN.try_with(Clone::clone).ok();
+        let trace_id = parent.as_ref().map(|(trace_id, _)| trace_id.clone()).unwrap_or_else(|| otlp::random_id(16));
+        let span_id = otlp::random_id(8);
+        let start = std::time::SystemTime::now();
+
+        let result = otlp::CURRENT_SPAN.scope((trace_id.clone(), span_id.clone()), future).await;
+
+        let span = otlp::FinishedSpan {
+            trace_id,
+            span_id,
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            name,
+            start,
+            end: std::time::SystemTime::now(),
+            attributes,
+            error: result.as_ref().err().map(|err| format!("{:#}", err)),
+        };
+        tracer.spans.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(span);
+        return result;
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    let _ = (name, attributes);
+    future.await
+}
+
+/// Export all finished spans, failures are logged since tracing must not break a run
+pub async fn flush() {
+    #[cfg(feature = "otlp")]
+    if let Some(tracer) = otlp::TRACER.get() {
+        if let Err(err) = tracer.flush().await {
+            tracing::warn!("Failed to export traces: {:#}", err);
+        }
+    }
+}